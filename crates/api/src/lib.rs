@@ -5,30 +5,59 @@ use anyhow::{Ok, Result};
 use log::info;
 use lookup_endpoint::AppState;
 use lookup_endpoint::{lookup_multiple, lookup_single};
+pub use lookup_service::NameLocalization;
 use ntex::web;
-use rocksdb::DB;
-use std::sync::Arc;
-use util::rocksdb_options;
+use std::{collections::HashMap, sync::Arc};
+use util::{
+    CellSystemKind, LayerCells, open_layered_db_read_only, read_layer_build, read_layer_cells,
+};
 
+/// Serves lookups on the layers of an index. Layers that don't record their cells are taken to
+/// hold geohashes down to `max_geohash_level`.
 pub async fn run_api(
     db_name: &str,
     max_geohash_level: usize,
     port: u16,
     workers: usize,
+    names: NameLocalization,
 ) -> Result<()> {
     info!(
         "Starting webserver on port {} with {} workers",
         port, workers
     );
 
-    let rockdb_options = rocksdb_options();
-    let db = Arc::new(DB::open_for_read_only(&rockdb_options, db_name, false)?);
+    let (db, layers) = open_layered_db_read_only(db_name)?;
+    let layer_cells = layers
+        .iter()
+        .map(|layer| {
+            // Lookups encode locations at the deepest level border cells were split to
+            let cells = match read_layer_build(&db, layer)? {
+                Some(build) => LayerCells {
+                    max_level: build.lookup_level(),
+                    ..build.cells
+                },
+                None => read_layer_cells(&db, layer)?.unwrap_or(LayerCells {
+                    system: CellSystemKind::Geohash,
+                    max_level: max_geohash_level,
+                }),
+            };
+            info!(
+                "Layer {} holds {} cells down to level {}",
+                layer, cells.system, cells.max_level
+            );
+            Ok((layer.clone(), cells))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    let db = Arc::new(db);
+    info!("Serving layers {}", layers.join(", "));
 
     web::HttpServer::new(move || {
         web::App::new()
             .state(AppState {
                 db: db.clone(),
-                max_geohash_level,
+                layers: layers.clone(),
+                layer_cells: layer_cells.clone(),
+                names: names.clone(),
             })
             .service(lookup_single)
             .service(lookup_multiple)
@@ -1,11 +1,18 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use geohash::Coord;
+use anyhow::{Result, bail};
+use geo::Coord;
+use log::error;
+use ntex::http::header::ACCEPT_LANGUAGE;
 use ntex::web;
 use rocksdb::{DBWithThreadMode, MultiThreaded};
 use serde::{Deserialize, Serialize};
+use util::LayerCells;
 
-use crate::lookup_service::lookup_coordinates;
+use crate::lookup_service::{
+    LayerValues, NameLocalization, ResolvedFeature, accept_languages, lookup_coordinates,
+    resolve_fallback,
+};
 
 #[derive(Deserialize)]
 pub struct Location {
@@ -13,39 +20,69 @@ pub struct Location {
     lng: f64,
 }
 
+#[derive(Deserialize)]
+struct LocationQuery {
+    lat: f64,
+    lng: f64,
+    layers: Option<String>,
+    fallback: Option<String>,
+    lang: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct LocationsRequest {
     locations: Vec<Location>,
+    layers: Option<Vec<String>>,
+    fallback: Option<Vec<String>>,
+    lang: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum LocationResult {
+    Layers(LayerValues),
+    Resolved(Option<ResolvedFeature>),
 }
 
 #[derive(Serialize)]
 struct LocationsResponse {
-    locations: Vec<String>,
+    locations: Vec<LocationResult>,
 }
 
 pub struct AppState {
     pub db: Arc<DBWithThreadMode<MultiThreaded>>,
-    pub max_geohash_level: usize,
+    pub layers: Vec<String>,
+    pub layer_cells: HashMap<String, LayerCells>,
+    pub names: NameLocalization,
 }
 
 #[web::get("/lookup")]
 async fn lookup_single(
-    location: web::types::Query<Location>,
+    req: web::HttpRequest,
+    location: web::types::Query<LocationQuery>,
     state: web::types::State<AppState>,
 ) -> impl web::Responder {
     let coord = Coord {
         x: location.lng,
         y: location.lat,
     };
-    let res = lookup_coordinates(&state.db, vec![coord], state.max_geohash_level).unwrap();
-    web::HttpResponse::Ok().body(res.into_iter().next().unwrap())
+    let layers = location.layers.as_deref().map(split_layers);
+    let fallback = location.fallback.as_deref().map(split_layers);
+    let languages = request_languages(&req, location.lang.as_deref().map(split_layers));
+
+    match resolve_locations(&state, vec![coord], layers, fallback, &languages) {
+        Ok(res) => web::HttpResponse::Ok().json(&res.into_iter().next().unwrap()),
+        Err(err) => error_response(err),
+    }
 }
 
 #[web::post("/lookup")]
 async fn lookup_multiple(
+    req: web::HttpRequest,
     location_request: web::types::Json<LocationsRequest>,
     state: web::types::State<AppState>,
 ) -> impl web::Responder {
+    let location_request = location_request.into_inner();
     let coordinates: Vec<_> = location_request
         .locations
         .iter()
@@ -54,13 +91,107 @@ async fn lookup_multiple(
             y: location.lat,
         })
         .collect();
+    let languages = request_languages(&req, location_request.lang);
+
+    match resolve_locations(
+        &state,
+        coordinates,
+        location_request.layers,
+        location_request.fallback,
+        &languages,
+    ) {
+        Ok(resolved_locations) => {
+            let location_response = LocationsResponse {
+                locations: resolved_locations,
+            };
+            web::HttpResponse::Ok().json(&location_response)
+        }
+        Err(err) => error_response(err),
+    }
+}
+
+/// Failures reading the index are answered as server errors, anything else is a problem of the
+/// request.
+fn error_response(err: anyhow::Error) -> web::HttpResponse {
+    let index_error = err.chain().any(|cause| {
+        cause.is::<rocksdb::Error>()
+            || cause.is::<bitcode::Error>()
+            || cause.is::<serde_json::Error>()
+    });
+    if index_error {
+        error!("Lookup failed: {:#}", err);
+        web::HttpResponse::InternalServerError().body(format!("{:#}", err))
+    } else {
+        web::HttpResponse::BadRequest().body(err.to_string())
+    }
+}
+
+/// Looks up the requested layers, or all layers of the index if none are requested.
+/// With a fallback order every location resolves to the first layer in that order holding a value,
+/// so all layers of the fallback order have to be looked up.
+fn resolve_locations(
+    state: &AppState,
+    coordinates: Vec<Coord>,
+    layers: Option<Vec<String>>,
+    fallback: Option<Vec<String>>,
+    languages: &[String],
+) -> Result<Vec<LocationResult>> {
+    if let (Some(layers), Some(fallback)) = (&layers, &fallback) {
+        let missing: Vec<&str> = fallback
+            .iter()
+            .filter(|layer| !layers.contains(layer))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Fallback layers {} are not among the requested layers",
+                missing.join(", ")
+            );
+        }
+    }
+
+    let layers = layers
+        .or_else(|| fallback.clone())
+        .unwrap_or_else(|| state.layers.clone());
 
     let resolved_locations =
-        lookup_coordinates(&state.db, coordinates, state.max_geohash_level).unwrap();
+        lookup_coordinates(&state.db, coordinates, &layers, &state.layer_cells)?;
 
-    let location_response = LocationsResponse {
-        locations: resolved_locations,
-    };
+    Ok(resolved_locations
+        .into_iter()
+        .map(|mut layer_values| {
+            for feature in layer_values.values_mut().flatten() {
+                feature.name = state.names.localized_name(&feature.properties, languages);
+                for overlapping in feature.overlapping.iter_mut() {
+                    overlapping.name = state
+                        .names
+                        .localized_name(&overlapping.properties, languages);
+                }
+            }
+            layer_values
+        })
+        .map(|layer_values| match &fallback {
+            Some(fallback) => LocationResult::Resolved(resolve_fallback(&layer_values, fallback)),
+            None => LocationResult::Layers(layer_values),
+        })
+        .collect())
+}
+
+/// Languages from the `lang` parameter, or from the `Accept-Language` header if it is missing.
+fn request_languages(req: &web::HttpRequest, lang: Option<Vec<String>>) -> Vec<String> {
+    lang.unwrap_or_else(|| {
+        req.headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|header| header.to_str().ok())
+            .map(accept_languages)
+            .unwrap_or_default()
+    })
+}
 
-    web::HttpResponse::Ok().json(&location_response)
+fn split_layers(layers: &str) -> Vec<String> {
+    layers
+        .split(',')
+        .map(|layer| layer.trim().to_owned())
+        .filter(|layer| !layer.is_empty())
+        .collect()
 }
@@ -1,60 +1,404 @@
-use geo::Contains;
-use geohash::{Coord, GeohashError, encode};
+use std::{cmp::Ordering, collections::HashMap};
+
+use anyhow::{Context, Result};
+use geo::{Contains, Coord};
+use geojson::JsonObject;
 use rocksdb::{DBWithThreadMode, MultiThreaded};
-use util::GeohashValue;
+use serde::Serialize;
+use util::{CellSystem, FeatureProperties, GeohashValue, LayerCells, properties_layer};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ResolvedFeature {
+    pub value: String,
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub properties: JsonObject,
+    /// Other features covering the location, in order of their priority
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overlapping: Vec<ResolvedFeature>,
+}
+
+pub type LayerValues = HashMap<String, Option<ResolvedFeature>>;
 
 pub fn lookup_coordinates(
     db: &DBWithThreadMode<MultiThreaded>,
     coords: Vec<Coord>,
-    max_geohash_level: usize,
-) -> Result<Vec<String>, GeohashError> {
-    let hash_strings: Vec<String> = coords
+    layers: &[String],
+    layer_cells: &HashMap<String, LayerCells>,
+) -> Result<Vec<LayerValues>> {
+    if coords.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let layer_cfs = layers
         .iter()
-        .map(|coord| encode(coord.clone(), max_geohash_level).unwrap())
-        .flat_map(|hash| (1..=hash.len()).map(move |i| hash[0..i].to_string()))
+        .map(|layer| {
+            let layer_cf = db
+                .cf_handle(layer)
+                .with_context(|| format!("Unknown layer {}", layer))?;
+            let cells = layer_cells
+                .get(layer)
+                .with_context(|| format!("Unknown layer {}", layer))?;
+            Ok((layer_cf, cells.cells(), cells.max_level))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Per layer and location the cell of the location and all its ancestors
+    let layer_hashes = layer_cfs
+        .iter()
+        .map(|(_, system, max_level)| {
+            coords
+                .iter()
+                .map(|coord| Ok(system.ancestors(&system.encode(*coord, *max_level)?)))
+                .collect::<Result<Vec<Vec<String>>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let lookup_keys = layer_cfs
+        .iter()
+        .zip(&layer_hashes)
+        .flat_map(|((layer_cf, _, _), hashes)| {
+            hashes
+                .iter()
+                .flatten()
+                .map(move |hash| (layer_cf, hash.as_str()))
+        });
+
+    let mut lookup_res = db.multi_get_cf(lookup_keys).into_iter();
+
+    let resolved_values: Vec<Vec<Vec<String>>> = layer_cfs
+        .iter()
+        .zip(layers)
+        .zip(&layer_hashes)
+        .map(|(((_, system, _), layer), hashes)| {
+            hashes
+                .iter()
+                .zip(&coords)
+                .map(|(hashes, coord)| {
+                    let chunk: Vec<_> = lookup_res.by_ref().take(hashes.len()).collect();
+                    resolve_values(chunk, coord, hashes, *system)
+                        .with_context(|| format!("Failed to read layer {}", layer))
+                })
+                .collect()
+        })
+        .collect::<Result<_>>()?;
+
+    let properties = lookup_properties(db, layers, &resolved_values)?;
+
+    let mut resolved_locations = vec![LayerValues::new(); coords.len()];
+
+    for (layer_index, (layer, layer_values)) in layers.iter().zip(resolved_values).enumerate() {
+        for (i, mut values) in layer_values.into_iter().enumerate() {
+            values.sort_by(|a, b| priority_order(layer_index, a, b, &properties));
+            // Cells hold the ids of their features, except in indexes built without properties
+            let mut features = values.into_iter().map(|key| {
+                let feature_properties = properties.get(&(layer_index, key.clone()));
+                ResolvedFeature {
+                    id: feature_properties.and_then(|feature| feature.id.clone()),
+                    name: None,
+                    properties: feature_properties
+                        .map(|feature| feature.properties.clone())
+                        .unwrap_or_default(),
+                    value: feature_properties
+                        .and_then(|feature| feature.value.clone())
+                        .unwrap_or(key),
+                    overlapping: Vec::new(),
+                }
+            });
+            let resolved_feature = features.next().map(|feature| ResolvedFeature {
+                overlapping: features.collect(),
+                ..feature
+            });
+            resolved_locations[i].insert(layer.clone(), resolved_feature);
+        }
+    }
+
+    Ok(resolved_locations)
+}
+
+/// Fetches the properties of all resolved features with one multi_get_cf, keyed by layer index
+/// and feature id. Layers built without a properties table resolve to their values only.
+fn lookup_properties(
+    db: &DBWithThreadMode<MultiThreaded>,
+    layers: &[String],
+    resolved_values: &[Vec<Vec<String>>],
+) -> Result<HashMap<(usize, String), FeatureProperties>> {
+    let properties_cfs: Vec<_> = layers
+        .iter()
+        .map(|layer| db.cf_handle(&properties_layer(layer)))
         .collect();
 
-    let hash_string_slices: Vec<&str> = hash_strings
+    let mut property_keys: Vec<(usize, &str)> = resolved_values
         .iter()
-        .map(|hash_string| hash_string.as_str())
+        .enumerate()
+        .filter(|(layer_index, _)| properties_cfs[*layer_index].is_some())
+        .flat_map(|(layer_index, values)| {
+            values
+                .iter()
+                .flatten()
+                .map(move |value| (layer_index, value.as_str()))
+        })
         .collect();
+    property_keys.sort_unstable();
+    property_keys.dedup();
 
-    let lookup_res: Vec<_> = db.multi_get(hash_string_slices);
-    let lookup_chunks: Vec<_> = lookup_res.chunks(max_geohash_level).collect();
-
-    let mut resolved_locations = Vec::<String>::new();
-
-    for (i, chunk) in lookup_chunks.into_iter().enumerate() {
-        let mut found_loc = false;
-        for lookup_val in chunk {
-            if let Result::Ok(Some(out)) = lookup_val {
-                let res = bitcode::deserialize::<GeohashValue>(&out).unwrap();
-
-                let contains_res = match res {
-                    GeohashValue::DirectValue { value } => Some(value),
-                    GeohashValue::Undecided { options } => options
-                        .iter()
-                        .find(|option| {
-                            let coord = coords.get(i).unwrap();
-                            option.shape.contains(&geo::Coord {
-                                x: coord.x,
-                                y: coord.y,
-                            })
-                        })
-                        .and_then(|option| Some(option.value.clone())),
-                };
+    let lookup_keys = property_keys.iter().filter_map(|(layer_index, value)| {
+        properties_cfs[*layer_index]
+            .as_ref()
+            .map(|properties_cf| (properties_cf, *value))
+    });
 
-                if let Some(val) = contains_res {
-                    resolved_locations.push(val);
-                    found_loc = true;
-                    break;
-                }
+    let lookup_res = db.multi_get_cf(lookup_keys);
+
+    let mut properties = HashMap::new();
+    for ((layer_index, value), lookup_val) in property_keys.into_iter().zip(lookup_res) {
+        if let Some(out) = lookup_val? {
+            let feature_properties = serde_json::from_slice::<FeatureProperties>(&out)?;
+            properties.insert((layer_index, value.to_owned()), feature_properties);
+        }
+    }
+
+    Ok(properties)
+}
+
+/// How the localized name of a resolved feature is picked from its properties.
+#[derive(Clone, Debug)]
+pub struct NameLocalization {
+    /// Property holding the default name, localized names are stored as `{key}:{lang}`.
+    pub key: String,
+    /// Languages tried after the requested ones, before the default name.
+    pub fallback: Vec<String>,
+}
+
+impl NameLocalization {
+    /// Tries the requested languages, each also by its primary subtag (`de` for `de-CH`), then
+    /// the fallback languages and finally the default name.
+    pub fn localized_name(&self, properties: &JsonObject, languages: &[String]) -> Option<String> {
+        languages
+            .iter()
+            .chain(self.fallback.iter())
+            .flat_map(|lang| {
+                [
+                    Some(lang.as_str()),
+                    lang.split_once('-').map(|(primary, _)| primary),
+                ]
+            })
+            .flatten()
+            .map(|lang| format!("{}:{}", self.key, lang))
+            .chain(std::iter::once(self.key.clone()))
+            .find_map(|key| properties.get(&key)?.as_str().map(|name| name.to_owned()))
+    }
+}
+
+/// Languages of an `Accept-Language` header ordered by their quality, `*` and `q=0` are dropped.
+pub fn accept_languages(header: &str) -> Vec<String> {
+    let mut languages: Vec<(f32, String)> = header
+        .split(',')
+        .filter_map(|language| {
+            let mut parts = language.split(';');
+            let lang = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.trim().parse::<f32>().ok())?;
+
+            (!lang.is_empty() && lang != "*" && quality > 0.0).then(|| (quality, lang.to_owned()))
+        })
+        .collect();
+    languages.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    languages.into_iter().map(|(_, lang)| lang).collect()
+}
+
+/// Orders the features of a layer by their rank, features without a rank last and ties by id.
+fn priority_order(
+    layer_index: usize,
+    a: &str,
+    b: &str,
+    properties: &HashMap<(usize, String), FeatureProperties>,
+) -> Ordering {
+    let rank = |value: &str| {
+        properties
+            .get(&(layer_index, value.to_owned()))
+            .and_then(|feature| feature.priority)
+    };
+    match (rank(a), rank(b)) {
+        (Some(rank_a), Some(rank_b)) => rank_a.total_cmp(&rank_b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+    .then_with(|| a.cmp(b))
+}
+
+/// Picks the feature of the first layer in `fallback` that resolved the location.
+pub fn resolve_fallback(values: &LayerValues, fallback: &[String]) -> Option<ResolvedFeature> {
+    fallback
+        .iter()
+        .find_map(|layer| values.get(layer).cloned().flatten())
+}
+
+/// Values of all cells covering `coord`, `chunk` holds the lookups of `hashes`, the cell of
+/// `coord` and its ancestors.
+fn resolve_values(
+    chunk: Vec<Result<Option<Vec<u8>>, rocksdb::Error>>,
+    coord: &Coord,
+    hashes: &[String],
+    system: &dyn CellSystem,
+) -> Result<Vec<String>> {
+    let point = *coord;
+    let mut values = Vec::new();
+    for (hash, lookup_val) in hashes.iter().zip(chunk) {
+        if let Some(out) = lookup_val? {
+            let res = bitcode::deserialize::<GeohashValue>(&out)
+                .with_context(|| format!("Cell {} holds an unreadable value", hash))?;
+            let Ok(origin) = system.origin(hash) else {
+                continue;
+            };
+
+            match res {
+                GeohashValue::DirectValue { value } => values.push(value),
+                GeohashValue::Undecided { options } => values.extend(
+                    options
+                        .into_iter()
+                        .filter(|option| option.shape.contains(&point))
+                        .map(|option| option.value),
+                ),
+                GeohashValue::UndecidedCompact { options } => values.extend(
+                    options
+                        .into_iter()
+                        .filter(|option| option.shape.contains(origin, point))
+                        .map(|option| option.value),
+                ),
+                GeohashValue::Overlapping { options } => values.extend(
+                    options
+                        .into_iter()
+                        .filter(|option| {
+                            option
+                                .shape
+                                .as_ref()
+                                .is_none_or(|shape| shape.contains(origin, point))
+                        })
+                        .map(|option| option.value),
+                ),
             }
         }
-        if !found_loc {
-            resolved_locations.push(String::from(""));
+    }
+
+    values.sort_unstable();
+    values.dedup();
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use util::GeohashCells;
+
+    use super::*;
+
+    fn languages(languages: &[&str]) -> Vec<String> {
+        languages.iter().map(|lang| (*lang).to_owned()).collect()
+    }
+
+    fn names() -> JsonObject {
+        json!({
+            "name": "Schweiz/Suisse/Svizzera",
+            "name:de": "Schweiz",
+            "name:de-CH": "Schwiiz",
+            "name:fr": "Suisse",
+        })
+        .as_object()
+        .unwrap()
+        .clone()
+    }
+
+    fn localization(fallback: &[&str]) -> NameLocalization {
+        NameLocalization {
+            key: "name".to_owned(),
+            fallback: languages(fallback),
         }
     }
 
-    Ok(resolved_locations)
+    #[test]
+    fn orders_accepted_languages_by_quality() {
+        assert_eq!(
+            accept_languages("fr;q=0.5, de-CH, en;q=0.8, de;q=0.9"),
+            languages(&["de-CH", "de", "en", "fr"])
+        );
+        assert_eq!(
+            accept_languages("it, rm"),
+            languages(&["it", "rm"]),
+            "Languages of equal quality keep their order"
+        );
+    }
+
+    #[test]
+    fn drops_wildcards_refused_and_malformed_languages() {
+        assert_eq!(
+            accept_languages("*, en;q=0, fr;q=abc, ;q=0.3, de;q=0.1"),
+            languages(&["de"])
+        );
+        assert!(accept_languages("").is_empty());
+    }
+
+    #[test]
+    fn localizes_names_by_requested_language_and_primary_subtag() {
+        let names = names();
+        let localization = localization(&[]);
+
+        let name = |requested: &[&str]| localization.localized_name(&names, &languages(requested));
+
+        assert_eq!(name(&["de-CH"]).as_deref(), Some("Schwiiz"));
+        assert_eq!(name(&["de-AT"]).as_deref(), Some("Schweiz"));
+        assert_eq!(name(&["it", "fr"]).as_deref(), Some("Suisse"));
+        assert_eq!(name(&["it"]).as_deref(), Some("Schweiz/Suisse/Svizzera"));
+    }
+
+    #[test]
+    fn localizes_names_by_fallback_languages_after_requested_ones() {
+        let names = names();
+        let localization = localization(&["fr"]);
+
+        let name = |requested: &[&str]| localization.localized_name(&names, &languages(requested));
+
+        assert_eq!(name(&["it"]).as_deref(), Some("Suisse"));
+        assert_eq!(name(&["de"]).as_deref(), Some("Schweiz"));
+        assert_eq!(
+            localization.localized_name(&JsonObject::new(), &languages(&["de"])),
+            None
+        );
+    }
+
+    fn cell(value: &GeohashValue) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        Ok(Some(bitcode::serialize(value).unwrap()))
+    }
+
+    #[test]
+    fn resolves_the_values_of_the_cells_of_a_location() {
+        let coord = Coord { x: 13.4, y: 52.5 };
+        let hashes = GeohashCells.ancestors(&GeohashCells.encode(coord, 3).unwrap());
+        let chunk = vec![
+            cell(&GeohashValue::DirectValue {
+                value: "de".to_owned(),
+            }),
+            Ok(None),
+            cell(&GeohashValue::DirectValue {
+                value: "eu".to_owned(),
+            }),
+        ];
+
+        let values = resolve_values(chunk, &coord, &hashes, &GeohashCells).unwrap();
+
+        assert_eq!(values, vec!["de", "eu"]);
+    }
+
+    #[test]
+    fn unreadable_cells_are_errors() {
+        let coord = Coord { x: 13.4, y: 52.5 };
+        let hashes = GeohashCells.ancestors(&GeohashCells.encode(coord, 1).unwrap());
+        let chunk = vec![Ok(Some(vec![0xff; 3]))];
+
+        assert!(resolve_values(chunk, &coord, &hashes, &GeohashCells).is_err());
+    }
 }
@@ -1,17 +1,24 @@
-use anyhow::{Context, Ok, Result};
-use api::run_api;
+use anyhow::{bail, Context, Ok, Result};
+use api::{run_api, NameLocalization};
 use clap::{Parser, Subcommand};
-use env_logger;
-use extract::extract;
+use extract::{
+    extract, extract_changes, write_features, ClipRegion, ElementStore, MemoryStore, OsmChange,
+    OutputFormat, RocksDbStore,
+};
 use geo::Polygon;
 use geojson::Feature;
-use log::info;
-use ntex;
-use process::{extract_topologies, save_geohash_index};
+use log::{info, warn};
+use process::{
+    extract_topologies, feature_id, feature_properties, feature_value, index_features,
+    read_features, repair_features, save_geohash_index, update_geohash_index, InputFormat,
+};
 use rayon::ThreadPoolBuilder;
 use std::thread;
-use std::{fs::read_to_string, str::FromStr};
-use util::{GeohashIndex, TopodexConfig};
+use std::{collections::HashSet, fs::read_to_string, path::Path};
+use util::{
+    CellSystem, CellSystemKind, GeohashIndex, LayerBuild, LayerCells, RelationDiagnostic,
+    TopodexConfig,
+};
 
 fn default_thread_count() -> String {
     thread::available_parallelism()
@@ -35,11 +42,20 @@ enum Commands {
         #[arg(short, long)]
         geohash_db: String,
 
+        /// Deepest geohash level of layers built before their cells were recorded in the DB
         #[arg(short, long, default_value_t = 5)]
         max_geohash_level: usize,
 
         #[arg(short, long, default_value_t = 8090)]
         port: u16,
+
+        /// Property holding the default name, localized names are read from `<key>:<lang>`
+        #[arg(long, default_value = "name")]
+        name_key: String,
+
+        /// Comma separated languages tried after the requested ones, e.g. `en,fr`
+        #[arg(long, default_value = "")]
+        lang_fallback: String,
     },
     Extract {
         #[arg(short, long)]
@@ -48,16 +64,87 @@ enum Commands {
         #[arg(short, long)]
         features_output_path: String,
 
+        /// Format of the features file: geojsonl, geojson, flatgeobuf or geoparquet.
+        /// Picked by file extension if not given
+        #[arg(long)]
+        output_format: Option<OutputFormat>,
+
         #[arg(short, long)]
         config_path: String,
+
+        #[arg(short, long)]
+        diagnostics_output_path: Option<String>,
+
+        /// Keep nodes and ways in a scratch RocksDB at this path instead of memory
+        #[arg(short, long)]
+        element_store_path: Option<String>,
+
+        /// Memory budget of the scratch RocksDB in megabytes
+        #[arg(long, default_value_t = 1024)]
+        memory_budget_mb: usize,
+
+        /// Only keep features inside `min_lon,min_lat,max_lon,max_lat` or the polygons of a
+        /// GeoJSON file, cut to that region
+        #[arg(long)]
+        clip: Option<ClipRegion>,
+    },
+    /// Applies an OsmChange file to the features of an earlier extract and to its geohash DB,
+    /// rebuilding only the relations affected by the change. The cells of the changed features
+    /// are filled the way the layer of the config was processed, as recorded in the DB
+    Update {
+        #[arg(short, long)]
+        osm_pbf_file: String,
+
+        /// OsmChange file (.osc or .osc.gz) with the changes since the PBF file
+        #[arg(long)]
+        osm_change_file: String,
+
+        #[arg(short, long)]
+        features_output_path: String,
+
+        #[arg(short, long)]
+        geohash_db_output_path: String,
+
+        #[arg(short, long)]
+        config_path: String,
+
+        #[arg(short, long)]
+        diagnostics_output_path: Option<String>,
+
+        #[arg(short, long)]
+        element_store_path: Option<String>,
+
+        #[arg(long, default_value_t = 1024)]
+        memory_budget_mb: usize,
+
+        /// Clip region of the earlier extract
+        #[arg(long)]
+        clip: Option<ClipRegion>,
     },
     Process {
         #[arg(short, long)]
         features_output_path: String,
 
+        /// Cells covering the features: geohash, quadkey, s2 or h3. Recorded in the DB
+        #[arg(long, default_value_t = CellSystemKind::Geohash)]
+        cell_system: CellSystemKind,
+
+        /// Deepest level of the cells, the length of geohashes and quadkeys, the level of S2
+        /// cells or the resolution of H3 cells
         #[arg(short, long, default_value_t = 5)]
         max_geohash_level: usize,
 
+        /// Stop splitting a border cell once the part of a feature inside it has at most this
+        /// many vertices. Simple borders then stop above `max_geohash_level`, intricate ones are
+        /// split beyond it down to the deepest level of the cell system
+        #[arg(long)]
+        max_cell_vertices: Option<usize>,
+
+        /// Simplify the shapes stored for border cells, with this tolerance in meters. Lookups
+        /// near a border may then be off by up to that distance
+        #[arg(long)]
+        simplify_tolerance: Option<f64>,
+
         #[arg(short, long)]
         processed_features_output_path: Option<String>,
 
@@ -66,6 +153,16 @@ enum Commands {
 
         #[arg(short, long)]
         config_path: String,
+
+        /// Format of the features file: geojson, shapefile, geopackage or flatgeobuf.
+        /// Picked by file extension if not given
+        #[arg(short, long)]
+        input_format: Option<InputFormat>,
+
+        /// Memory budget in megabytes of the scratch RocksDB the cells are staged in, next to
+        /// the geohash DB
+        #[arg(long, default_value_t = 1024)]
+        memory_budget_mb: usize,
     },
 }
 
@@ -84,79 +181,256 @@ async fn main() -> Result<()> {
         Commands::Extract {
             osm_pbf_file,
             features_output_path,
+            output_format,
             config_path,
+            diagnostics_output_path,
+            element_store_path,
+            memory_budget_mb,
+            clip,
         } => {
             let config = topodex_config(&config_path)?;
 
+            let store = element_store(element_store_path, memory_budget_mb)?;
+
             info!("Read file {}", osm_pbf_file);
-            let geometries = extract(&osm_pbf_file, &config)?;
-            info!("Received {} geometries", geometries.len());
+            let (geometries, diagnostics) =
+                extract(&osm_pbf_file, &config, store.as_ref(), clip.as_ref())?;
+            info!(
+                "Received {} geometries, {} relations with issues",
+                geometries.len(),
+                diagnostics.len()
+            );
 
-            let geojson_str = geometries
+            if let Some(output_path) = diagnostics_output_path {
+                write_diagnostics(&output_path, &diagnostics)?;
+            }
+
+            write_features(Path::new(&features_output_path), &geometries, output_format)?;
+        }
+        Commands::Update {
+            osm_pbf_file,
+            osm_change_file,
+            features_output_path,
+            geohash_db_output_path,
+            config_path,
+            diagnostics_output_path,
+            element_store_path,
+            memory_budget_mb,
+            clip,
+        } => {
+            let output_format = OutputFormat::from_path(Path::new(&features_output_path));
+            if !matches!(
+                output_format,
+                OutputFormat::GeoJsonLines | OutputFormat::FeatureCollection
+            ) {
+                bail!(
+                    "Update needs the features of the earlier extract as GeoJSON, got {}",
+                    output_format
+                );
+            }
+
+            let config = topodex_config(&config_path)?;
+            let change = OsmChange::from_path(&osm_change_file)?;
+            info!(
+                "Read change {} with {} nodes, {} ways, {} relations",
+                osm_change_file,
+                change.nodes.len(),
+                change.ways.len(),
+                change.relations.len()
+            );
+
+            let store = element_store(element_store_path, memory_budget_mb)?;
+            let update = extract_changes(
+                &osm_pbf_file,
+                &change,
+                &config,
+                store.as_ref(),
+                clip.as_ref(),
+            )?;
+            info!(
+                "Rebuilt {} geometries, replacing {} features",
+                update.features.len(),
+                update.replaced_features.len()
+            );
+
+            if let Some(output_path) = diagnostics_output_path {
+                write_diagnostics(&output_path, &update.diagnostics)?;
+            }
+
+            let (replaced, mut geometries): (Vec<Feature>, Vec<Feature>) =
+                read_features(Path::new(&features_output_path), Some(InputFormat::GeoJson))?
+                    .collect::<Result<Vec<Feature>>>()?
+                    .into_iter()
+                    .partition(|feature| {
+                        feature_id(feature).is_some_and(|id| update.replaced_features.contains(&id))
+                    });
+            geometries.extend(update.features.iter().cloned());
+
+            // Compaction merged the cells of features sharing a process value, the features
+            // sharing one with a changed feature are filled again with it
+            let changed_values: HashSet<String> = replaced
+                .iter()
+                .chain(update.features.iter())
+                .filter_map(|feature| feature_value(&feature.properties, &config))
+                .collect();
+            let refilled: Vec<Feature> = geometries
+                .iter()
+                .filter(|feature| {
+                    feature_value(&feature.properties, &config)
+                        .is_some_and(|value| changed_values.contains(&value))
+                })
+                .cloned()
+                .collect();
+            let removed: HashSet<String> = update
+                .replaced_features
                 .iter()
-                .map(|feature| feature.to_string())
-                .collect::<Vec<String>>()
-                .join("\n");
+                .cloned()
+                .chain(refilled.iter().filter_map(feature_id))
+                .collect();
+
+            update_geohash_index(
+                &removed,
+                repair_features(refilled),
+                feature_properties(&geometries, &config),
+                &geohash_db_output_path,
+                &config,
+            )?;
 
-            std::fs::write(features_output_path, geojson_str)?;
+            write_features(
+                Path::new(&features_output_path),
+                &geometries,
+                Some(output_format),
+            )?;
         }
         Commands::Process {
             features_output_path,
+            cell_system,
             max_geohash_level,
+            max_cell_vertices,
+            simplify_tolerance,
             processed_features_output_path,
             geohash_db_output_path,
             config_path,
+            input_format,
+            memory_budget_mb,
         } => {
             let config = topodex_config(&config_path)?;
 
-            let features_str = read_to_string(features_output_path)?;
-            let geometries: Vec<Feature> = features_str
-                .split("\n")
-                .into_iter()
-                .map(|feature_str| Feature::from_str(feature_str).unwrap())
-                .collect();
-            let geohash_indexes = extract_topologies(geometries, max_geohash_level, &config)?;
-            info!("Geohash indexes count: {}", geohash_indexes.len());
+            let features = stream_features(&features_output_path, input_format)?;
+            let cells = LayerCells {
+                system: cell_system,
+                max_level: max_geohash_level,
+            };
+            let build = LayerBuild {
+                cells,
+                max_cell_vertices,
+                simplify_tolerance,
+            };
+            let memory_budget = memory_budget_mb * 1024 * 1024;
 
-            if let Some(output_path) = processed_features_output_path {
-                let geojson_str = geohash_to_geojson(&geohash_indexes);
-                std::fs::write(output_path, geojson_str)?;
-            }
+            // The features are streamed into the index unless their cells are also written
+            // out as features, which needs all of them at once
+            match processed_features_output_path {
+                Some(output_path) => {
+                    let geometries = repair_features(features.collect());
+                    let properties = feature_properties(&geometries, &config);
+                    let geohash_indexes =
+                        extract_topologies(geometries, cells, max_cell_vertices, &config)?;
+                    info!("Geohash indexes count: {}", geohash_indexes.len());
+                    let geojson_str = geohash_to_geojson(&geohash_indexes, cells.cells())?;
+                    std::fs::write(output_path, geojson_str)?;
 
-            save_geohash_index(geohash_indexes, &geohash_db_output_path)?;
+                    save_geohash_index(
+                        vec![geohash_indexes],
+                        properties,
+                        &geohash_db_output_path,
+                        &config.layer,
+                        build,
+                        memory_budget,
+                    )?;
+                }
+                None => index_features(
+                    features,
+                    &geohash_db_output_path,
+                    build,
+                    memory_budget,
+                    &config,
+                )?,
+            }
         }
         Commands::Serve {
             geohash_db,
             max_geohash_level,
             port,
+            name_key,
+            lang_fallback,
         } => {
-            run_api(&geohash_db, max_geohash_level, port, thread_count).await?;
+            let names = NameLocalization {
+                key: name_key,
+                fallback: lang_fallback
+                    .split(',')
+                    .map(|lang| lang.trim().to_owned())
+                    .filter(|lang| !lang.is_empty())
+                    .collect(),
+            };
+            run_api(&geohash_db, max_geohash_level, port, thread_count, names).await?;
         }
     }
     Ok(())
 }
 
 fn topodex_config(config_path: &str) -> Result<TopodexConfig> {
-    let config_str = read_to_string(&config_path)
+    let config_str = read_to_string(config_path)
         .with_context(|| format!("Failed to read configuration from {}", config_path))?;
     let config: TopodexConfig = serde_json::from_str(&config_str)
         .with_context(|| format!("Failed to parse provided topodex config at {}", config_path))?;
     Ok(config)
 }
 
-fn geohash_to_geojson(geohash_indexes: &Vec<GeohashIndex>) -> String {
+fn element_store(path: Option<String>, memory_budget_mb: usize) -> Result<Box<dyn ElementStore>> {
+    Ok(match path {
+        Some(path) => Box::new(RocksDbStore::open(path, memory_budget_mb * 1024 * 1024)?),
+        None => Box::<MemoryStore>::default(),
+    })
+}
+
+/// The features of `path` as they are read, records that can't be read are logged and skipped.
+fn stream_features(
+    path: &str,
+    format: Option<InputFormat>,
+) -> Result<impl Iterator<Item = Feature> + Send + '_> {
+    Ok(
+        read_features(Path::new(path), format)?.filter_map(move |feature| {
+            feature
+                .inspect_err(|err| warn!("Skipping feature in {}: {:#}", path, err))
+                .ok()
+        }),
+    )
+}
+
+fn write_diagnostics(path: &str, diagnostics: &[RelationDiagnostic]) -> Result<()> {
+    let diagnostics_str = diagnostics
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<String>, _>>()?
+        .join("\n");
+    std::fs::write(path, diagnostics_str)?;
+    Ok(())
+}
+
+fn geohash_to_geojson(geohash_indexes: &[GeohashIndex], cells: &dyn CellSystem) -> Result<String> {
     let bboxes = geohash_indexes
         .iter()
         .map(|geohash_index| match geohash_index {
-            GeohashIndex::DirectValue { hash, value: _ } => {
-                vec![geohash::decode_bbox(&hash).unwrap().to_polygon()]
-            }
+            GeohashIndex::DirectValue { hash, value: _ } => Ok(cells.shape(hash)?.0),
             GeohashIndex::PartialValue {
                 hash: _,
                 value: _,
                 shape,
-            } => shape.into_iter().map(|t| t.clone()).collect(),
+            } => Ok(shape.0.clone()),
         })
+        .collect::<Result<Vec<Vec<Polygon>>>>()?
+        .into_iter()
         .flatten()
         .collect::<Vec<Polygon>>();
 
@@ -176,5 +450,5 @@ fn geohash_to_geojson(geohash_indexes: &Vec<GeohashIndex>) -> String {
         bbox: None,
     };
 
-    feature.to_string()
+    Ok(feature.to_string())
 }
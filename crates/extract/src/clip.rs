@@ -0,0 +1,124 @@
+use std::{fs::read_to_string, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use geo::{BooleanOps, BoundingRect, Contains, Intersects, MultiPolygon, Polygon, Rect};
+use geojson::GeoJson;
+
+/// Region the extracted features are clipped to, a bounding box or any polygon.
+#[derive(Clone)]
+pub struct ClipRegion {
+    shape: MultiPolygon,
+    bounds: Rect,
+    /// Bounds of each polygon of `shape`, to clip only with the polygons near a feature
+    polygon_bounds: Vec<Rect>,
+    /// Set when the region is its own bounding box, containment is then a bounds check
+    rectangular: bool,
+}
+
+impl ClipRegion {
+    pub fn from_bbox(min_x: f64, min_y: f64, max_x: f64, max_y: f64) -> Result<Self> {
+        if min_x >= max_x || min_y >= max_y {
+            bail!("Empty clip bbox {},{},{},{}", min_x, min_y, max_x, max_y);
+        }
+        let bounds = Rect::new((min_x, min_y), (max_x, max_y));
+        Ok(ClipRegion {
+            shape: MultiPolygon(vec![bounds.to_polygon()]),
+            bounds,
+            polygon_bounds: vec![bounds],
+            rectangular: true,
+        })
+    }
+
+    pub fn from_shape(shape: MultiPolygon) -> Result<Self> {
+        let Some(bounds) = shape.bounding_rect() else {
+            bail!("Empty clip polygon");
+        };
+        let polygon_bounds = shape
+            .0
+            .iter()
+            .map(|polygon| polygon.bounding_rect().unwrap_or(bounds))
+            .collect();
+        Ok(ClipRegion {
+            shape,
+            bounds,
+            polygon_bounds,
+            rectangular: false,
+        })
+    }
+
+    /// Reads the polygons of a GeoJSON geometry, feature or feature collection, overlapping
+    /// polygons are merged.
+    pub fn from_geojson(geojson: &str) -> Result<Self> {
+        let polygons = match GeoJson::from_str(geojson)? {
+            GeoJson::Geometry(geometry) => vec![geometry],
+            GeoJson::Feature(feature) => feature.geometry.into_iter().collect(),
+            GeoJson::FeatureCollection(collection) => collection
+                .features
+                .into_iter()
+                .filter_map(|feature| feature.geometry)
+                .collect(),
+        }
+        .into_iter()
+        .map(|geometry| match geometry.value {
+            geojson::Value::Polygon(_) => Ok(MultiPolygon(vec![Polygon::try_from(geometry)?])),
+            geojson::Value::MultiPolygon(_) => Ok(MultiPolygon::try_from(geometry)?),
+            value => bail!("Clip region must be polygons, found {}", value.type_name()),
+        })
+        .collect::<Result<Vec<MultiPolygon>>>()?;
+
+        let shape = polygons
+            .into_iter()
+            .reduce(|region, polygon| region.union(&polygon))
+            .unwrap_or_else(|| MultiPolygon(vec![]));
+        ClipRegion::from_shape(shape)
+    }
+
+    /// Clips `shape` to the region, `None` if nothing of it lies inside.
+    pub fn clip(&self, shape: MultiPolygon) -> Option<MultiPolygon> {
+        let bounds = shape.bounding_rect()?;
+        if !self.bounds.intersects(&bounds) {
+            return None;
+        }
+        if self.rectangular && self.bounds.contains(&bounds) {
+            return Some(shape);
+        }
+
+        let nearby: MultiPolygon = self
+            .shape
+            .0
+            .iter()
+            .zip(&self.polygon_bounds)
+            .filter(|(_, polygon_bounds)| polygon_bounds.intersects(&bounds))
+            .map(|(polygon, _)| polygon.clone())
+            .collect();
+        if nearby.0.is_empty() {
+            return None;
+        }
+
+        let clipped = shape.intersection(&nearby);
+        (!clipped.0.is_empty()).then_some(clipped)
+    }
+}
+
+/// Parses `min_lon,min_lat,max_lon,max_lat` or the path of a GeoJSON file.
+impl FromStr for ClipRegion {
+    type Err = anyhow::Error;
+
+    fn from_str(region: &str) -> Result<Self> {
+        let bbox = region
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>();
+        match bbox.as_deref() {
+            Ok([min_x, min_y, max_x, max_y]) => {
+                ClipRegion::from_bbox(*min_x, *min_y, *max_x, *max_y)
+            }
+            _ => {
+                let geojson = read_to_string(region)
+                    .with_context(|| format!("Failed to read clip region {}", region))?;
+                ClipRegion::from_geojson(&geojson)
+                    .with_context(|| format!("Invalid clip region {}", region))
+            }
+        }
+    }
+}
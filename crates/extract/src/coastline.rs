@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use geo::{
+    coord, BooleanOps, BoundingRect, Coord, LineString, MultiPolygon, Polygon, Rect, Winding,
+};
+use log::{info, warn};
+
+use crate::element_store::ElementStore;
+
+/// Assembles `natural=coastline` ways into land polygons. Coastline ways run with the land on
+/// their left, so closed counter-clockwise rings are islands and closed clockwise rings are
+/// seas enclosed by land. Coastlines cut at the border of the extract are closed by following
+/// the border of `extent`, grown to hold all coastlines, counter-clockwise to the start of the
+/// next coastline. Their ends are moved straight onto that border, extracts cut along a polygon
+/// are only approximated near it.
+pub fn land_polygons(
+    coastline_ways: &[i64],
+    extent: Rect,
+    store: &dyn ElementStore,
+) -> Result<MultiPolygon> {
+    let mut ways: Vec<Vec<i64>> = Vec::with_capacity(coastline_ways.len());
+    for way_id in coastline_ways {
+        if let Some(node_ids) = store.way(*way_id)? {
+            if node_ids.len() >= 2 {
+                ways.push(node_ids);
+            }
+        }
+    }
+    let (rings, open_chains) = chain_ways(ways);
+
+    let mut islands = Vec::new();
+    let mut seas = Vec::new();
+    for ring in rings {
+        let Some(ring) = locations(&ring, store)? else {
+            continue;
+        };
+        if ring.0.len() < 4 {
+            continue;
+        }
+        if ring.is_ccw() {
+            islands.push(Polygon::new(ring, vec![]));
+        } else {
+            seas.push(Polygon::new(ring, vec![]));
+        }
+    }
+
+    let mut located_chains = Vec::with_capacity(open_chains.len());
+    for chain in &open_chains {
+        located_chains.extend(locations(chain, store)?);
+    }
+    let open_chains = located_chains;
+    let extent = open_chains
+        .iter()
+        .chain(
+            islands
+                .iter()
+                .chain(&seas)
+                .map(|polygon| polygon.exterior()),
+        )
+        .filter_map(|ring| ring.bounding_rect())
+        .fold(extent, covering);
+    info!(
+        "Coastline: {} islands, {} enclosed seas, {} coastlines crossing the extract border",
+        islands.len(),
+        seas.len(),
+        open_chains.len()
+    );
+
+    let mut land = if !open_chains.is_empty() {
+        MultiPolygon(close_along_extent(open_chains, extent))
+    } else if !seas.is_empty() {
+        MultiPolygon(vec![extent.to_polygon()])
+    } else {
+        MultiPolygon(vec![])
+    };
+    if !seas.is_empty() {
+        land = land.difference(&MultiPolygon(seas));
+    }
+    if !islands.is_empty() {
+        land = land.union(&MultiPolygon(islands));
+    }
+    Ok(land)
+}
+
+/// Smallest rectangle covering both.
+pub fn covering(a: Rect, b: Rect) -> Rect {
+    Rect::new(
+        coord! {x: a.min().x.min(b.min().x), y: a.min().y.min(b.min().y)},
+        coord! {x: a.max().x.max(b.max().x), y: a.max().y.max(b.max().y)},
+    )
+}
+
+/// Joins ways whose last node is the first node of another way. Returns the closed rings and
+/// the chains left open, both as node ids.
+fn chain_ways(ways: Vec<Vec<i64>>) -> (Vec<Vec<i64>>, Vec<Vec<i64>>) {
+    let mut by_start: HashMap<i64, usize> = HashMap::new();
+    let mut has_predecessor = vec![false; ways.len()];
+    for (index, way) in ways.iter().enumerate() {
+        by_start.insert(way[0], index);
+    }
+    for way in &ways {
+        if let Some(&next) = by_start.get(way.last().unwrap()) {
+            has_predecessor[next] = true;
+        }
+    }
+
+    let mut used = vec![false; ways.len()];
+    let follow = |first: usize, used: &mut Vec<bool>| {
+        let mut chain = ways[first].clone();
+        used[first] = true;
+        while let Some(&next) = by_start.get(chain.last().unwrap()) {
+            if used[next] {
+                break;
+            }
+            used[next] = true;
+            chain.extend(ways[next].iter().skip(1));
+        }
+        chain
+    };
+
+    let mut rings = Vec::new();
+    let mut open_chains = Vec::new();
+    // Chains start at ways without predecessor, what is left afterwards are rings
+    for first in 0..ways.len() {
+        if !has_predecessor[first] && !used[first] {
+            open_chains.push(follow(first, &mut used));
+        }
+    }
+    for first in 0..ways.len() {
+        if !used[first] {
+            let chain = follow(first, &mut used);
+            if chain.first() == chain.last() {
+                rings.push(chain);
+            } else {
+                open_chains.push(chain);
+            }
+        }
+    }
+    (rings, open_chains)
+}
+
+fn locations(node_ids: &[i64], store: &dyn ElementStore) -> Result<Option<LineString>> {
+    let locations = store
+        .nodes(node_ids)?
+        .into_iter()
+        .collect::<Option<Vec<(f64, f64)>>>();
+    let Some(locations) = locations else {
+        warn!(
+            "Coastline from node {} has missing nodes, skipping",
+            node_ids[0]
+        );
+        return Ok(None);
+    };
+    Ok(Some(
+        locations
+            .into_iter()
+            .map(|(lon, lat)| coord! {x: lon, y: lat})
+            .collect(),
+    ))
+}
+
+/// Closes open coastlines into land rings along the border of `extent`.
+fn close_along_extent(chains: Vec<LineString>, extent: Rect) -> Vec<Polygon> {
+    let perimeter = 2.0 * (extent.width() + extent.height());
+    let starts: Vec<f64> = chains
+        .iter()
+        .map(|chain| border_position(chain.0[0], extent))
+        .collect();
+
+    let mut used = vec![false; chains.len()];
+    let mut polygons = Vec::new();
+    for first in 0..chains.len() {
+        if used[first] {
+            continue;
+        }
+
+        let mut ring: Vec<Coord> = Vec::new();
+        let mut current = first;
+        loop {
+            used[current] = true;
+            let chain = &chains[current].0;
+            ring.extend(chain);
+
+            // The next coastline is the first one starting counter-clockwise along the border
+            let end = border_position(*chain.last().unwrap(), extent);
+            let next = (0..chains.len())
+                .filter(|&index| index == first || !used[index])
+                .min_by(|&a, &b| {
+                    let distance = |index: usize| (starts[index] - end).rem_euclid(perimeter);
+                    distance(a).total_cmp(&distance(b))
+                })
+                .unwrap();
+
+            ring.push(border_point(end, extent));
+            ring.extend(corners_between(end, starts[next], extent));
+            ring.push(border_point(starts[next], extent));
+            if next == first {
+                break;
+            }
+            current = next;
+        }
+
+        // Coastlines ending on the border repeat their end point
+        ring.dedup();
+        polygons.push(Polygon::new(LineString::new(ring), vec![]));
+    }
+    polygons
+}
+
+/// Distance along the border of `extent`, counter-clockwise from its lower left corner, of the
+/// border point closest to `coord`.
+fn border_position(coord: Coord, extent: Rect) -> f64 {
+    let (min, max) = (extent.min(), extent.max());
+    let (width, height) = (extent.width(), extent.height());
+    let x = coord.x.clamp(min.x, max.x);
+    let y = coord.y.clamp(min.y, max.y);
+
+    [
+        (y - min.y, x - min.x),
+        (max.x - x, width + (y - min.y)),
+        (max.y - y, width + height + (max.x - x)),
+        (x - min.x, 2.0 * width + height + (max.y - y)),
+    ]
+    .into_iter()
+    .min_by(|(a, _), (b, _)| a.total_cmp(b))
+    .map(|(_, position)| position)
+    .unwrap()
+}
+
+fn border_point(position: f64, extent: Rect) -> Coord {
+    let (min, max) = (extent.min(), extent.max());
+    let (width, height) = (extent.width(), extent.height());
+    if position <= width {
+        coord! {x: min.x + position, y: min.y}
+    } else if position <= width + height {
+        coord! {x: max.x, y: min.y + position - width}
+    } else if position <= 2.0 * width + height {
+        coord! {x: max.x - (position - width - height), y: max.y}
+    } else {
+        coord! {x: min.x, y: max.y - (position - 2.0 * width - height)}
+    }
+}
+
+/// Corners of `extent` passed going counter-clockwise from `from` to `to`.
+fn corners_between(from: f64, to: f64, extent: Rect) -> Vec<Coord> {
+    let perimeter = 2.0 * (extent.width() + extent.height());
+    let corners = [
+        extent.width(),
+        extent.width() + extent.height(),
+        2.0 * extent.width() + extent.height(),
+        perimeter,
+    ];
+    let distance = (to - from).rem_euclid(perimeter);
+
+    let mut passed: Vec<(f64, Coord)> = corners
+        .iter()
+        .map(|&corner| ((corner - from).rem_euclid(perimeter), corner))
+        .filter(|(corner_distance, _)| *corner_distance > 0.0 && *corner_distance < distance)
+        .map(|(corner_distance, corner)| (corner_distance, border_point(corner, extent)))
+        .collect();
+    passed.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    passed.into_iter().map(|(_, corner)| corner).collect()
+}
@@ -1,39 +1,545 @@
-use std::{fs::File, io::{BufReader, Read}, path::Path};
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    panic::{self, resume_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, OnceLock,
+    },
+    thread, vec,
+};
 
-use osmpbf::{BlobDecode, BlobReader, Element};
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use osmpbf::{Blob, BlobDecode, BlobReader, ByteOffset, Element, PrimitiveBlock};
+use rayon::{
+    iter::{ParallelBridge, ParallelIterator},
+    ThreadPool, ThreadPoolBuilder,
+};
 
 pub struct ElementCollectReader<R: Read + Send> {
     blob_iter: BlobReader<R>,
+    path: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElementType {
+    Node,
+    Way,
+    Relation,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct BlockContent {
+    nodes: bool,
+    ways: bool,
+    relations: bool,
+}
+
+impl BlockContent {
+    fn from_block(block: &PrimitiveBlock) -> Self {
+        let mut content = BlockContent::default();
+        for group in block.groups() {
+            content.nodes |= group.nodes().len() > 0 || group.dense_nodes().len() > 0;
+            content.ways |= group.ways().len() > 0;
+            content.relations |= group.relations().len() > 0;
+        }
+        content
+    }
+
+    fn contains(&self, element_type: ElementType) -> bool {
+        match element_type {
+            ElementType::Node => self.nodes,
+            ElementType::Way => self.ways,
+            ElementType::Relation => self.relations,
+        }
+    }
+}
+
+/// Elements of a PBF file, blob by blob. The stream reads the blobs itself and hands them to
+/// the decoding threads shared by all streams, keeping a few of them in flight, and only reads
+/// more as the decoded ones are consumed. Blobs arrive in no particular order. A blob that
+/// can't be read or decoded is reported as an error, iteration may continue with the following
+/// blobs. Dropping the stream stops decoding.
+///
+/// Decoding never waits for a consumer, so streams may be consumed from any thread, inside
+/// `rayon::scope` or a parallel iterator as well, and several streams may be held at once.
+pub struct ElementStream<T> {
+    blobs: Box<dyn Iterator<Item = Result<Blob, osmpbf::Error>> + Send>,
+    decode: Arc<dyn Fn(Blob) -> Result<Vec<T>, osmpbf::Error> + Send + Sync>,
+    sender: Sender<thread::Result<Result<Vec<T>, osmpbf::Error>>>,
+    receiver: Receiver<thread::Result<Result<Vec<T>, osmpbf::Error>>>,
+    in_flight: usize,
+    elements: vec::IntoIter<T>,
+}
+
+impl<T: Send + 'static> ElementStream<T> {
+    fn new<B, FMO>(blobs: B, element_types: Vec<ElementType>, filter_map_op: FMO) -> Self
+    where
+        B: Iterator<Item = Result<Blob, osmpbf::Error>> + Send + 'static,
+        FMO: for<'a> Fn(Element<'a>) -> Option<T> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = channel();
+        ElementStream {
+            blobs: Box::new(blobs),
+            decode: Arc::new(move |blob: Blob| match blob.decode()? {
+                BlobDecode::OsmData(block) => {
+                    Ok(block_elements(&block, &element_types, &filter_map_op))
+                }
+                _ => Ok(Vec::new()),
+            }),
+            sender,
+            receiver,
+            in_flight: 0,
+            elements: Vec::new().into_iter(),
+        }
+    }
+
+    /// Hands blobs to the decoding threads until enough of them are in flight.
+    fn read_ahead(&mut self) {
+        let pool = decode_pool();
+        while self.in_flight < 2 * pool.current_num_threads() {
+            let Some(blob) = self.blobs.next() else {
+                return;
+            };
+            self.in_flight += 1;
+            let (decode, sender) = (self.decode.clone(), self.sender.clone());
+            match blob {
+                Ok(blob) => pool.spawn(move || {
+                    // Fails only once the stream is dropped
+                    let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(|| decode(blob))));
+                }),
+                Err(error) => {
+                    let _ = sender.send(Ok(Err(error)));
+                }
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Iterator for ElementStream<T> {
+    type Item = Result<T, osmpbf::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(element) = self.elements.next() {
+                return Some(Ok(element));
+            }
+            self.read_ahead();
+            if self.in_flight == 0 {
+                return None;
+            }
+            // The stream holds a sender itself, receiving fails only on a closed channel
+            let decoded = self.receiver.recv().expect("Element stream channel closed");
+            self.in_flight -= 1;
+            match decoded {
+                Ok(Ok(elements)) => self.elements = elements.into_iter(),
+                Ok(Err(error)) => return Some(Err(error)),
+                // Pass on a panic of the decoding instead of ending early
+                Err(panic) => resume_unwind(panic),
+            }
+        }
+    }
+}
+
+/// Threads decoding the blobs of all element streams, as many as the global rayon pool has.
+fn decode_pool() -> &'static ThreadPool {
+    static POOL: OnceLock<ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .num_threads(rayon::current_num_threads())
+            .thread_name(|index| format!("pbf-decode-{}", index))
+            .build()
+            .expect("Failed to start the PBF decoding threads")
+    })
+}
+
+/// Offsets of the data blobs of a PBF file together with the element types they contain.
+/// Built while reading the file once, later reads only decode the blobs they need.
+pub struct BlobIndex {
+    path: PathBuf,
+    blobs: Vec<(ByteOffset, BlockContent)>,
 }
 
 impl ElementCollectReader<BufReader<File>> {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, osmpbf::Error> {
         Ok(ElementCollectReader {
-            blob_iter: BlobReader::from_path(path)?,
+            blob_iter: BlobReader::seekable_from_path(&path)?,
+            path: path.as_ref().to_path_buf(),
         })
     }
 
-    pub fn elements<T, FMO>(self, filter_map_op: FMO) -> Result<Vec<T>, osmpbf::Error>
+    /// Reads all elements of the file and indexes its blobs on the way.
+    pub fn indexed_elements<T, FMO>(
+        self,
+        filter_map_op: FMO,
+    ) -> Result<(Vec<T>, BlobIndex), osmpbf::Error>
     where
         T: Send,
         FMO: for<'a> Fn(Element<'a>) -> Option<T> + Send + Sync,
     {
-        let result: Vec<T> = self
+        let blobs: Vec<(Option<ByteOffset>, BlockContent, Vec<T>)> = self
             .blob_iter
             .par_bridge()
-            .filter_map(Result::ok)
-            .flat_map(|blob| match blob.decode() {
-                Ok(BlobDecode::OsmData(block)) => Some(
-                    block
-                        .elements()
-                        .filter_map(&filter_map_op)
-                        .collect::<Vec<T>>(),
-                ),
-                _ => None,
+            .map(|blob| {
+                let blob = blob?;
+                match blob.decode()? {
+                    BlobDecode::OsmData(block) => Ok(Some((
+                        blob.offset(),
+                        BlockContent::from_block(&block),
+                        block
+                            .elements()
+                            .filter_map(&filter_map_op)
+                            .collect::<Vec<T>>(),
+                    ))),
+                    _ => Ok(None),
+                }
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<_, osmpbf::Error>>()?;
+
+        let mut index = BlobIndex {
+            path: self.path,
+            blobs: Vec::with_capacity(blobs.len()),
+        };
+        let mut elements = Vec::<T>::new();
+        for (offset, content, mut blob_elements) in blobs {
+            if let Some(offset) = offset {
+                index.blobs.push((offset, content));
+            }
+            elements.append(&mut blob_elements);
+        }
+        index.blobs.sort_unstable_by_key(|(offset, _)| offset.0);
+
+        Ok((elements, index))
+    }
+}
+
+impl<R: Read + Send + 'static> ElementCollectReader<R> {
+    /// Reads all elements of the file, collected from `stream`. Fails with the first blob that
+    /// can't be read or decoded.
+    pub fn elements<T, FMO>(self, filter_map_op: FMO) -> Result<Vec<T>, osmpbf::Error>
+    where
+        T: Send + 'static,
+        FMO: for<'a> Fn(Element<'a>) -> Option<T> + Send + Sync + 'static,
+    {
+        self.stream(
+            &[ElementType::Node, ElementType::Way, ElementType::Relation],
+            filter_map_op,
+        )
+        .collect()
+    }
+
+    /// Streams the elements of `element_types` in the file. Without an index every blob is
+    /// decoded, only the groups of other element types are skipped.
+    pub fn stream<T, FMO>(
+        self,
+        element_types: &[ElementType],
+        filter_map_op: FMO,
+    ) -> ElementStream<T>
+    where
+        T: Send + 'static,
+        FMO: for<'a> Fn(Element<'a>) -> Option<T> + Send + Sync + 'static,
+    {
+        ElementStream::new(self.blob_iter, element_types.to_vec(), filter_map_op)
+    }
+}
+
+impl BlobIndex {
+    pub fn blob_count(&self, element_type: ElementType) -> usize {
+        self.blobs
+            .iter()
+            .filter(|(_, content)| content.contains(element_type))
+            .count()
+    }
+
+    /// Decodes only the blobs holding elements of `element_type`.
+    pub fn elements<T, FMO>(
+        &self,
+        element_type: ElementType,
+        filter_map_op: FMO,
+    ) -> Result<Vec<T>, osmpbf::Error>
+    where
+        T: Send,
+        FMO: for<'a> Fn(Element<'a>) -> Option<T> + Send + Sync,
+    {
+        let mut blob_reader = BlobReader::seekable_from_path(&self.path)?;
+
+        self.blobs
+            .iter()
+            .filter(|(_, content)| content.contains(element_type))
+            .map(|(offset, _)| blob_reader.blob_from_offset(*offset))
+            .par_bridge()
+            .map(|blob| match blob?.decode()? {
+                BlobDecode::OsmData(block) => {
+                    Ok(block_elements(&block, &[element_type], &filter_map_op))
+                }
+                _ => Ok(Vec::new()),
+            })
+            .collect::<Result<Vec<Vec<T>>, osmpbf::Error>>()
+            .map(|blobs| blobs.into_iter().flatten().collect())
+    }
+
+    /// Decodes only the blobs holding elements of `element_type` and hands the elements of each
+    /// blob to `consume` instead of collecting them.
+    pub fn consume_elements<T, FMO, C, E>(
+        &self,
+        element_type: ElementType,
+        filter_map_op: FMO,
+        consume: C,
+    ) -> Result<(), E>
+    where
+        T: Send,
+        FMO: for<'a> Fn(Element<'a>) -> Option<T> + Send + Sync,
+        C: Fn(Vec<T>) -> Result<(), E> + Send + Sync,
+        E: From<osmpbf::Error> + Send,
+    {
+        let mut blob_reader = BlobReader::seekable_from_path(&self.path)?;
+
+        self.blobs
+            .iter()
+            .filter(|(_, content)| content.contains(element_type))
+            .map(|(offset, _)| blob_reader.blob_from_offset(*offset))
+            .par_bridge()
+            .try_for_each(|blob| match blob?.decode()? {
+                BlobDecode::OsmData(block) => {
+                    consume(block_elements(&block, &[element_type], &filter_map_op))
+                }
+                _ => Ok(()),
             })
-            .flatten()
+    }
+
+    /// Streams the elements of `element_type`, decoding only the blobs holding them.
+    pub fn stream<T, FMO>(
+        &self,
+        element_type: ElementType,
+        filter_map_op: FMO,
+    ) -> Result<ElementStream<T>, osmpbf::Error>
+    where
+        T: Send + 'static,
+        FMO: for<'a> Fn(Element<'a>) -> Option<T> + Send + Sync + 'static,
+    {
+        let mut blob_reader = BlobReader::seekable_from_path(&self.path)?;
+        let offsets: Vec<ByteOffset> = self
+            .blobs
+            .iter()
+            .filter(|(_, content)| content.contains(element_type))
+            .map(|(offset, _)| *offset)
             .collect();
-        Ok(result)
+
+        Ok(ElementStream::new(
+            offsets
+                .into_iter()
+                .map(move |offset| blob_reader.blob_from_offset(offset)),
+            vec![element_type],
+            filter_map_op,
+        ))
+    }
+}
+
+/// Applies `filter_map_op` to the elements of `element_types` in a block. The groups are read
+/// per element type, so elements of other types are never decoded.
+fn block_elements<T, FMO>(
+    block: &PrimitiveBlock,
+    element_types: &[ElementType],
+    filter_map_op: &FMO,
+) -> Vec<T>
+where
+    FMO: for<'a> Fn(Element<'a>) -> Option<T>,
+{
+    let wanted = |element_type: ElementType| element_types.contains(&element_type);
+    let mut elements = Vec::new();
+    for group in block.groups() {
+        if wanted(ElementType::Node) {
+            elements.extend(group.nodes().map(Element::Node).filter_map(filter_map_op));
+            elements.extend(
+                group
+                    .dense_nodes()
+                    .map(Element::DenseNode)
+                    .filter_map(filter_map_op),
+            );
+        }
+        if wanted(ElementType::Way) {
+            elements.extend(group.ways().map(Element::Way).filter_map(filter_map_op));
+        }
+        if wanted(ElementType::Relation) {
+            elements.extend(
+                group
+                    .relations()
+                    .map(Element::Relation)
+                    .filter_map(filter_map_op),
+            );
+        }
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{mpsc::sync_channel, Mutex},
+        time::Duration,
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn varint(bytes: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+    }
+
+    fn zigzag(value: i64) -> u64 {
+        ((value << 1) ^ (value >> 63)) as u64
+    }
+
+    fn number_field(bytes: &mut Vec<u8>, field: u64, value: u64) {
+        varint(bytes, field << 3);
+        varint(bytes, value);
     }
-}
\ No newline at end of file
+
+    fn bytes_field(bytes: &mut Vec<u8>, field: u64, value: &[u8]) {
+        varint(bytes, (field << 3) | 2);
+        varint(bytes, value.len() as u64);
+        bytes.extend_from_slice(value);
+    }
+
+    /// Writes a PBF file of uncompressed data blobs, each holding `nodes_per_blob` nodes with
+    /// ids counting up from 1.
+    fn write_pbf(path: &Path, blobs: usize, nodes_per_blob: usize) {
+        let mut file = Vec::new();
+        for blob in 0..blobs {
+            let mut group = Vec::new();
+            for node in 0..nodes_per_blob {
+                let mut encoded = Vec::new();
+                number_field(
+                    &mut encoded,
+                    1,
+                    zigzag((blob * nodes_per_blob + node + 1) as i64),
+                );
+                number_field(&mut encoded, 8, zigzag(525_000_000));
+                number_field(&mut encoded, 9, zigzag(134_000_000));
+                bytes_field(&mut group, 1, &encoded);
+            }
+            let mut string_table = Vec::new();
+            bytes_field(&mut string_table, 1, b"");
+            let mut block = Vec::new();
+            bytes_field(&mut block, 1, &string_table);
+            bytes_field(&mut block, 2, &group);
+
+            let mut data = Vec::new();
+            bytes_field(&mut data, 1, &block);
+            number_field(&mut data, 2, block.len() as u64);
+            let mut header = Vec::new();
+            bytes_field(&mut header, 1, b"OSMData");
+            number_field(&mut header, 3, data.len() as u64);
+
+            file.extend_from_slice(&(header.len() as u32).to_be_bytes());
+            file.extend(header);
+            file.extend(data);
+        }
+        fs::write(path, file).unwrap();
+    }
+
+    fn node_id(element: Element) -> Option<i64> {
+        match element {
+            Element::Node(node) => Some(node.id()),
+            _ => None,
+        }
+    }
+
+    fn sorted_ids(stream: ElementStream<i64>) -> Vec<i64> {
+        let mut ids = stream.collect::<Result<Vec<i64>, osmpbf::Error>>().unwrap();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn streams_all_elements() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nodes.osm.pbf");
+        write_pbf(&path, 8, 10);
+
+        let reader = ElementCollectReader::from_path(&path).unwrap();
+        let ids = sorted_ids(reader.stream(&[ElementType::Node], node_id));
+        assert_eq!(ids, (1..=80).collect::<Vec<i64>>());
+
+        let reader = ElementCollectReader::from_path(&path).unwrap();
+        let (_, index) = reader.indexed_elements(node_id).unwrap();
+        assert_eq!(index.blob_count(ElementType::Node), 8);
+        assert_eq!(index.blob_count(ElementType::Way), 0);
+        let ids = sorted_ids(index.stream(ElementType::Node, node_id).unwrap());
+        assert_eq!(ids.len(), 80);
+        assert!(sorted_ids(index.stream(ElementType::Way, node_id).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn collects_all_elements() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nodes.osm.pbf");
+        write_pbf(&path, 8, 10);
+
+        let reader = ElementCollectReader::from_path(&path).unwrap();
+        let mut ids = reader.elements(node_id).unwrap();
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=80).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn held_streams_leave_others_decoding() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nodes.osm.pbf");
+        let blobs = 4 * rayon::current_num_threads() + 8;
+        write_pbf(&path, blobs, 10);
+
+        let (sender, receiver) = sync_channel(1);
+        thread::spawn(move || {
+            let reader = ElementCollectReader::from_path(&path).unwrap();
+            let mut held = reader.stream(&[ElementType::Node], node_id);
+            // Starts decoding, then waits while the other stream is consumed
+            assert!(held.next().is_some());
+            let reader = ElementCollectReader::from_path(&path).unwrap();
+            let consumed = sorted_ids(reader.stream(&[ElementType::Node], node_id)).len();
+            let _ = sender.send((consumed, 1 + sorted_ids(held).len()));
+        });
+
+        let counts = receiver
+            .recv_timeout(Duration::from_secs(60))
+            .expect("A held stream kept the other from decoding");
+        assert_eq!(counts, (blobs * 10, blobs * 10));
+    }
+
+    #[test]
+    fn streams_can_be_consumed_on_all_threads_of_the_global_pool() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nodes.osm.pbf");
+        // More blobs than the stream buffers, so decoding has to wait for the consumers
+        write_pbf(&path, 4 * rayon::current_num_threads() + 8, 10);
+
+        let (sender, receiver) = sync_channel(1);
+        thread::spawn(move || {
+            let counts = Mutex::new(Vec::new());
+            // Every worker of the global pool blocks on a stream of its own
+            rayon::scope(|scope| {
+                for _ in 0..rayon::current_num_threads() {
+                    scope.spawn(|_| {
+                        let reader = ElementCollectReader::from_path(&path).unwrap();
+                        let ids = sorted_ids(reader.stream(&[ElementType::Node], node_id));
+                        counts.lock().unwrap().push(ids.len());
+                    });
+                }
+            });
+            let _ = sender.send(counts.into_inner().unwrap());
+        });
+
+        let counts = receiver
+            .recv_timeout(Duration::from_secs(60))
+            .expect("Streams consumed inside the rayon pool deadlocked");
+        let expected = (4 * rayon::current_num_threads() + 8) * 10;
+        assert_eq!(counts, vec![expected; rayon::current_num_threads()]);
+    }
+}
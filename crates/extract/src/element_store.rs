@@ -0,0 +1,252 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{bail, Result};
+use rocksdb::{ColumnFamilyDescriptor, WriteBatch, DB};
+use util::bounded_rocksdb_options;
+
+use crate::element_collection_reader::ElementType;
+
+const NODES_CF: &str = "nodes";
+const WAYS_CF: &str = "ways";
+const WANTED_NODES_CF: &str = "wanted_nodes";
+const WANTED_WAYS_CF: &str = "wanted_ways";
+const ELEMENT_CFS: [&str; 4] = [NODES_CF, WAYS_CF, WANTED_NODES_CF, WANTED_WAYS_CF];
+
+/// Storage for the node locations and way refs needed to assemble relations, and for the ids of
+/// the nodes and ways that are needed before they are read. Inserts happen from several threads
+/// while the PBF file is read.
+pub trait ElementStore: Send + Sync {
+    fn insert_nodes(&self, nodes: Vec<(i64, (f64, f64))>) -> Result<()>;
+
+    fn insert_ways(&self, ways: Vec<(i64, Vec<i64>)>) -> Result<()>;
+
+    fn node(&self, id: i64) -> Result<Option<(f64, f64)>>;
+
+    fn way(&self, id: i64) -> Result<Option<Vec<i64>>>;
+
+    fn nodes(&self, ids: &[i64]) -> Result<Vec<Option<(f64, f64)>>> {
+        ids.iter().map(|id| self.node(*id)).collect()
+    }
+
+    /// Marks nodes or ways as needed, so that they are stored once they are read.
+    fn insert_wanted(&self, element_type: ElementType, ids: Vec<i64>) -> Result<()>;
+
+    /// Whether each of `ids` is marked as needed. Asked for the elements of a whole blob at
+    /// once, as every element of the file is checked.
+    fn wanted(&self, element_type: ElementType, ids: &[i64]) -> Result<Vec<bool>>;
+}
+
+/// Keeps all elements in hash maps, fastest as long as they fit into memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    nodes: RwLock<HashMap<i64, (f64, f64)>>,
+    ways: RwLock<HashMap<i64, Vec<i64>>>,
+    wanted_nodes: RwLock<HashSet<i64>>,
+    wanted_ways: RwLock<HashSet<i64>>,
+}
+
+impl MemoryStore {
+    fn wanted_ids(&self, element_type: ElementType) -> &RwLock<HashSet<i64>> {
+        match element_type {
+            ElementType::Node => &self.wanted_nodes,
+            _ => &self.wanted_ways,
+        }
+    }
+}
+
+impl ElementStore for MemoryStore {
+    fn insert_nodes(&self, nodes: Vec<(i64, (f64, f64))>) -> Result<()> {
+        self.nodes.write().unwrap().extend(nodes);
+        Ok(())
+    }
+
+    fn insert_ways(&self, ways: Vec<(i64, Vec<i64>)>) -> Result<()> {
+        self.ways.write().unwrap().extend(ways);
+        Ok(())
+    }
+
+    fn node(&self, id: i64) -> Result<Option<(f64, f64)>> {
+        Ok(self.nodes.read().unwrap().get(&id).copied())
+    }
+
+    fn way(&self, id: i64) -> Result<Option<Vec<i64>>> {
+        Ok(self.ways.read().unwrap().get(&id).cloned())
+    }
+
+    fn insert_wanted(&self, element_type: ElementType, ids: Vec<i64>) -> Result<()> {
+        self.wanted_ids(element_type).write().unwrap().extend(ids);
+        Ok(())
+    }
+
+    fn wanted(&self, element_type: ElementType, ids: &[i64]) -> Result<Vec<bool>> {
+        let wanted = self.wanted_ids(element_type).read().unwrap();
+        Ok(ids.iter().map(|id| wanted.contains(id)).collect())
+    }
+}
+
+/// Keeps elements and the ids of wanted elements in a scratch RocksDB so extraction runs within
+/// a bounded memory budget. Whether the elements of a blob are wanted is looked up in one batch,
+/// mostly answered by the bloom filters. The database is removed again when the store is
+/// dropped.
+pub struct RocksDbStore {
+    db: Option<DB>,
+    path: PathBuf,
+}
+
+impl RocksDbStore {
+    pub fn open<P: AsRef<Path>>(path: P, memory_budget: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            bail!("Element store path {} already exists", path.display());
+        }
+
+        // The column families share the block cache and write buffer manager of the options
+        let options = bounded_rocksdb_options(memory_budget);
+        let column_families =
+            ELEMENT_CFS.map(|name| ColumnFamilyDescriptor::new(name, options.clone()));
+        let db = DB::open_cf_descriptors(&options, &path, column_families)?;
+
+        Ok(RocksDbStore { db: Some(db), path })
+    }
+
+    fn db(&self) -> &DB {
+        self.db.as_ref().unwrap()
+    }
+
+    fn wanted_cf(element_type: ElementType) -> &'static str {
+        match element_type {
+            ElementType::Node => WANTED_NODES_CF,
+            _ => WANTED_WAYS_CF,
+        }
+    }
+}
+
+impl ElementStore for RocksDbStore {
+    fn insert_nodes(&self, nodes: Vec<(i64, (f64, f64))>) -> Result<()> {
+        let nodes_cf = self.db().cf_handle(NODES_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        for (id, (lon, lat)) in nodes {
+            let mut location = [0u8; 16];
+            location[..8].copy_from_slice(&lon.to_le_bytes());
+            location[8..].copy_from_slice(&lat.to_le_bytes());
+            batch.put_cf(&nodes_cf, id.to_be_bytes(), location);
+        }
+        self.db().write_without_wal(batch)?;
+        Ok(())
+    }
+
+    fn insert_ways(&self, ways: Vec<(i64, Vec<i64>)>) -> Result<()> {
+        let ways_cf = self.db().cf_handle(WAYS_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        for (id, node_ids) in ways {
+            let refs: Vec<u8> = node_ids
+                .iter()
+                .flat_map(|node_id| node_id.to_le_bytes())
+                .collect();
+            batch.put_cf(&ways_cf, id.to_be_bytes(), refs);
+        }
+        self.db().write_without_wal(batch)?;
+        Ok(())
+    }
+
+    fn node(&self, id: i64) -> Result<Option<(f64, f64)>> {
+        let nodes_cf = self.db().cf_handle(NODES_CF).unwrap();
+        let location = self.db().get_cf(&nodes_cf, id.to_be_bytes())?;
+        Ok(location.and_then(|location| decode_location(&location)))
+    }
+
+    fn way(&self, id: i64) -> Result<Option<Vec<i64>>> {
+        let ways_cf = self.db().cf_handle(WAYS_CF).unwrap();
+        let refs = self.db().get_cf(&ways_cf, id.to_be_bytes())?;
+        Ok(refs.map(|refs| {
+            refs.chunks_exact(8)
+                .map(|node_id| i64::from_le_bytes(node_id.try_into().unwrap()))
+                .collect()
+        }))
+    }
+
+    fn nodes(&self, ids: &[i64]) -> Result<Vec<Option<(f64, f64)>>> {
+        let nodes_cf = self.db().cf_handle(NODES_CF).unwrap();
+        self.db()
+            .multi_get_cf(ids.iter().map(|id| (&nodes_cf, id.to_be_bytes())))
+            .into_iter()
+            .map(|location| Ok(location?.and_then(|location| decode_location(&location))))
+            .collect()
+    }
+
+    fn insert_wanted(&self, element_type: ElementType, ids: Vec<i64>) -> Result<()> {
+        let wanted_cf = self.db().cf_handle(Self::wanted_cf(element_type)).unwrap();
+        let mut batch = WriteBatch::default();
+        for id in ids {
+            batch.put_cf(&wanted_cf, id.to_be_bytes(), []);
+        }
+        self.db().write_without_wal(batch)?;
+        Ok(())
+    }
+
+    fn wanted(&self, element_type: ElementType, ids: &[i64]) -> Result<Vec<bool>> {
+        let wanted_cf = self.db().cf_handle(Self::wanted_cf(element_type)).unwrap();
+        self.db()
+            .multi_get_cf(ids.iter().map(|id| (&wanted_cf, id.to_be_bytes())))
+            .into_iter()
+            .map(|wanted| Ok(wanted?.is_some()))
+            .collect()
+    }
+}
+
+impl Drop for RocksDbStore {
+    fn drop(&mut self) {
+        self.db = None;
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn decode_location(location: &[u8]) -> Option<(f64, f64)> {
+    let lon = f64::from_le_bytes(location.get(..8)?.try_into().ok()?);
+    let lat = f64::from_le_bytes(location.get(8..16)?.try_into().ok()?);
+    Some((lon, lat))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn check_batches(store: &dyn ElementStore) {
+        store
+            .insert_nodes(vec![(1, (8.5, 47.3)), (3, (-0.1, 51.5))])
+            .unwrap();
+        store.insert_wanted(ElementType::Node, vec![1, 3]).unwrap();
+        store.insert_wanted(ElementType::Way, vec![2]).unwrap();
+
+        assert_eq!(
+            store.wanted(ElementType::Node, &[3, 2, 1]).unwrap(),
+            vec![true, false, true]
+        );
+        assert_eq!(
+            store.wanted(ElementType::Way, &[1, 2]).unwrap(),
+            vec![false, true]
+        );
+        assert_eq!(
+            store.nodes(&[3, 2, 1]).unwrap(),
+            vec![Some((-0.1, 51.5)), None, Some((8.5, 47.3))]
+        );
+        assert_eq!(store.node(2).unwrap(), None);
+    }
+
+    #[test]
+    fn memory_store_answers_batches_in_order() {
+        check_batches(&MemoryStore::default());
+    }
+
+    #[test]
+    fn rocksdb_store_answers_batches_in_order() {
+        let dir = TempDir::new().unwrap();
+        let store = RocksDbStore::open(dir.path().join("elements"), 64 << 20).unwrap();
+        check_batches(&store);
+    }
+}
@@ -1,106 +1,382 @@
+mod clip;
+mod coastline;
 mod element_collection_reader;
+mod element_store;
+mod osm_change;
+mod output;
 mod read_osm_data;
 
 use anyhow::Result;
-use geo::{coord, Coord, LineString, MultiPolygon, Polygon, Within};
+use coastline::{covering, land_polygons};
+use geo::{
+    coord, BoundingRect, Coord, Distance, Haversine, LineString, MultiPolygon, Point, Polygon,
+    Within,
+};
 use geojson::{feature::Id, Feature, Geometry, Value};
-use log::info;
-use read_osm_data::read_osm_elements;
-use std::{collections::HashMap, time::Instant};
-use util::{RelationMember, RelationWithLocations, RelationWithMembers, TopodexConfig, Way};
-
-pub fn extract(path: &str, extract_config: &TopodexConfig) -> Result<Vec<Feature>> {
-    let (relations, ways, nodes) = read_osm_elements(path, extract_config)?;
-    let start = Instant::now();
-    info!(
-        "Countries combination: {} seconds",
-        start.elapsed().as_secs()
-    );
-
-    let countries = build_relations(relations, ways, nodes)?;
-
-    Ok(countries
+use log::{info, warn};
+use read_osm_data::{read_osm_elements, OsmElements};
+
+pub use clip::ClipRegion;
+pub use element_collection_reader::{BlobIndex, ElementCollectReader, ElementStream, ElementType};
+pub use element_store::{ElementStore, MemoryStore, RocksDbStore};
+pub use osm_change::OsmChange;
+pub use output::{
+    write_features, FeatureCollectionWriter, FeatureWriter, FlatGeobufWriter, GeoJsonLinesWriter,
+    GeoParquetWriter, OutputFormat,
+};
+use std::collections::HashSet;
+use util::{
+    AreaWay, Coverage, RelationDiagnostic, RelationMember, RelationWithLocations,
+    RelationWithMembers, TopodexConfig, Way,
+};
+
+/// Extracts the features of `path`. With a clip region, features entirely outside of it are
+/// skipped and the others cut to it.
+pub fn extract(
+    path: &str,
+    extract_config: &TopodexConfig,
+    store: &dyn ElementStore,
+    clip: Option<&ClipRegion>,
+) -> Result<(Vec<Feature>, Vec<RelationDiagnostic>)> {
+    let elements = read_osm_elements(path, extract_config, None, store)?;
+    let area_ways = unclaimed_area_ways(elements.area_ways, &elements.relations);
+    let (mut features, diagnostics) = relation_features(
+        elements.relations,
+        &elements.coastline_ways,
+        extract_config,
+        store,
+        clip,
+    )?;
+    features.extend(area_way_features(area_ways, store, clip)?);
+
+    Ok((features, diagnostics))
+}
+
+/// Features rebuilt after applying an OsmChange file to a PBF file.
+pub struct FeatureUpdate {
+    pub features: Vec<Feature>,
+    /// Ids of the features replaced by `features`, including those of deleted elements and of
+    /// elements that no longer match the filters.
+    pub replaced_features: HashSet<String>,
+    pub diagnostics: Vec<RelationDiagnostic>,
+}
+
+/// Rebuilds only the features of relations and closed ways touched by `change`, directly or
+/// through one of their nested relations, ways or nodes.
+pub fn extract_changes(
+    path: &str,
+    change: &OsmChange,
+    extract_config: &TopodexConfig,
+    store: &dyn ElementStore,
+    clip: Option<&ClipRegion>,
+) -> Result<FeatureUpdate> {
+    let OsmElements {
+        relations,
+        area_ways,
+        coastline_ways,
+    } = read_osm_elements(path, extract_config, Some(change), store)?;
+    let replaced_features = relations
+        .iter()
+        .map(|relation| relation.id)
+        .chain(change.relations.keys().copied())
+        .map(|id| id.to_string())
+        .chain(
+            area_ways
+                .iter()
+                .map(|area_way| area_way.id)
+                .chain(change.ways.keys().copied())
+                .map(way_feature_id),
+        )
+        .collect();
+    let area_ways = unclaimed_area_ways(area_ways, &relations);
+    let (mut features, diagnostics) =
+        relation_features(relations, &coastline_ways, extract_config, store, clip)?;
+    features.extend(area_way_features(area_ways, store, clip)?);
+
+    Ok(FeatureUpdate {
+        features,
+        replaced_features,
+        diagnostics,
+    })
+}
+
+fn relation_features(
+    relations: Vec<RelationWithMembers>,
+    coastline_ways: &[i64],
+    extract_config: &TopodexConfig,
+    store: &dyn ElementStore,
+    clip: Option<&ClipRegion>,
+) -> Result<(Vec<Feature>, Vec<RelationDiagnostic>)> {
+    let (countries, diagnostics) =
+        build_relations(relations, store, extract_config.ring_gap_tolerance)?;
+
+    let land = match extract_config.coverage {
+        Coverage::Land => land_region(&countries, coastline_ways, store)?,
+        Coverage::Maritime => None,
+    };
+
+    let countries_count = countries.len();
+    let features = countries
         .into_iter()
-        .map(|country| {
-            let geometry = Geometry::new(Value::from(&country.shape));
+        .filter_map(|country| {
+            let shape = match &land {
+                Some(land) => land.clip(country.shape)?,
+                None => country.shape,
+            };
+            let shape = match clip {
+                Some(clip) => clip.clip(shape)?,
+                None => shape,
+            };
+            let geometry = Geometry::new(Value::from(&shape));
 
-            Feature {
+            Some(Feature {
                 bbox: None,
                 geometry: Some(geometry),
                 id: Some(Id::String(country.id.to_string())),
                 properties: Some(country.tags),
                 foreign_members: None,
-            }
+            })
         })
-        .collect::<Vec<Feature>>())
+        .collect::<Vec<Feature>>();
+    if clip.is_some() || land.is_some() {
+        info!(
+            "Skipped {} relations outside of the clip region or without land",
+            countries_count - features.len()
+        );
+    }
+
+    Ok((features, diagnostics))
 }
 
-fn extract_ways(
-    relation: &RelationWithMembers,
-    ways: &HashMap<i64, Vec<i64>>,
-) -> (Vec<Way>, Vec<Way>) {
-    relation
-        .members
+/// Land assembled from the coastline, covering at least the given relations. `None` if the
+/// extract has no coastline, relations then keep their waters.
+fn land_region(
+    relations: &[RelationWithLocations],
+    coastline_ways: &[i64],
+    store: &dyn ElementStore,
+) -> Result<Option<ClipRegion>> {
+    let Some(extent) = relations
         .iter()
-        .filter_map(|way| {
-            if let Some(node_ids) = ways.get(&way.to_i64()) {
-                Some(Way {
-                    id: way.to_i64(),
-                    node_ids: node_ids.to_vec(),
-                    outer: matches!(way, RelationMember::OuterMember { .. }),
-                })
-            } else {
-                None
-            }
-        })
-        .partition(|way| way.outer)
+        .filter_map(|relation| relation.shape.bounding_rect())
+        .reduce(covering)
+    else {
+        return Ok(None);
+    };
+
+    let land = land_polygons(coastline_ways, extent, store)?;
+    match ClipRegion::from_shape(land) {
+        Ok(land) => Ok(Some(land)),
+        Err(_) => {
+            warn!("No land found along the coastline, keeping the waters of all relations");
+            Ok(None)
+        }
+    }
 }
 
-fn build_polygons(ways: &mut Vec<Way>, nodes: &HashMap<i64, (f64, f64)>) -> Result<Vec<Polygon>> {
-    let mut polygons = Vec::<Polygon>::new();
-    let mut relation_data_complete = true;
+/// Closed ways that aren't members of one of the relations. The rings of a relation already
+/// make up its feature, emitting its member ways as well would cover the area twice.
+fn unclaimed_area_ways(area_ways: Vec<AreaWay>, relations: &[RelationWithMembers]) -> Vec<AreaWay> {
+    if area_ways.is_empty() {
+        return area_ways;
+    }
 
-    while !ways.is_empty() {
-        let start_node_id = ways.get(0).unwrap().node_ids.get(0).unwrap().clone();
-        let mut polygon_parts = Vec::<Coord>::new();
-        let mut search_node_id = start_node_id.clone();
-        if let Some((lng, lat)) = nodes.get(&start_node_id) {
-            polygon_parts.push(coord! {x: *lng, y: *lat});
+    let member_ways: HashSet<i64> = relations
+        .iter()
+        .flat_map(|relation| relation.members.iter().map(RelationMember::to_i64))
+        .collect();
+    area_ways
+        .into_iter()
+        .filter(|area_way| !member_ways.contains(&area_way.id))
+        .collect()
+}
+
+/// Turns closed ways into polygon features, their ids are prefixed with `way/` to keep them
+/// apart from relation ids.
+fn area_way_features(
+    area_ways: Vec<AreaWay>,
+    store: &dyn ElementStore,
+    clip: Option<&ClipRegion>,
+) -> Result<Vec<Feature>> {
+    let mut features = Vec::with_capacity(area_ways.len());
+    for area_way in area_ways {
+        let Some(node_ids) = store.way(area_way.id)? else {
+            continue;
+        };
+        let Some(locations) = store
+            .nodes(&node_ids)?
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+        else {
+            warn!("Way {} has missing nodes, skipping", area_way.id);
+            continue;
+        };
+
+        let polygon = Polygon::new(
+            locations
+                .into_iter()
+                .map(|(lon, lat)| coord! {x: lon, y: lat})
+                .collect(),
+            vec![],
+        );
+
+        let geometry = match clip {
+            Some(clip) => match clip.clip(MultiPolygon(vec![polygon])) {
+                Some(shape) => Value::from(&shape),
+                None => continue,
+            },
+            None => Value::from(&polygon),
+        };
+
+        features.push(Feature {
+            bbox: None,
+            geometry: Some(Geometry::new(geometry)),
+            id: Some(Id::String(way_feature_id(area_way.id))),
+            properties: Some(area_way.tags),
+            foreign_members: None,
+        });
+    }
+    Ok(features)
+}
+
+fn way_feature_id(id: i64) -> String {
+    format!("way/{}", id)
+}
+
+/// Member ways of a relation, grouped into outer, inner and ways without a role.
+fn extract_ways(
+    relation: &RelationWithMembers,
+    store: &dyn ElementStore,
+    diagnostic: &mut RelationDiagnostic,
+) -> Result<(Vec<Way>, Vec<Way>, Vec<Way>)> {
+    let mut outer_ways = Vec::new();
+    let mut inner_ways = Vec::new();
+    let mut unknown_ways = Vec::new();
+
+    for member in &relation.members {
+        let Some(node_ids) = store.way(member.to_i64())? else {
+            diagnostic.missing_ways.push(member.to_i64());
+            continue;
+        };
+
+        let way = Way {
+            id: member.to_i64(),
+            node_ids,
+            outer: matches!(member, RelationMember::OuterMember { .. }),
+        };
+        match member {
+            RelationMember::OuterMember(_) => outer_ways.push(way),
+            RelationMember::InnerMember(_) => inner_ways.push(way),
+            RelationMember::UnknownMember(_) => unknown_ways.push(way),
         }
+    }
 
-        loop {
-            if let Some(way) = find_match(&search_node_id, ways) {
-                let mut locations: Vec<Coord> = way
-                    .node_ids
-                    .iter()
-                    .skip(1)
-                    .map(|node_id| nodes.get(node_id))
-                    .flatten()
-                    .map(|(lon, lat)| coord! {x: lon.clone(), y: lat.clone()})
-                    .collect();
+    Ok((outer_ways, inner_ways, unknown_ways))
+}
 
-                polygon_parts.append(&mut locations);
+/// Chains ways into closed rings. A ring that can't be closed is recorded in the diagnostic
+/// and dropped, the remaining rings of the relation are still built.
+fn build_polygons(
+    ways: &mut Vec<Way>,
+    nodes: &dyn ElementStore,
+    gap_tolerance: f64,
+    diagnostic: &mut RelationDiagnostic,
+) -> Result<Vec<Polygon>> {
+    let mut polygons = Vec::<Polygon>::new();
 
-                search_node_id = way.node_ids.last().unwrap().clone();
+    while let Some(first_way) = ways.first() {
+        let Some(&start_node_id) = first_way.node_ids.first() else {
+            diagnostic.missing_ways.push(ways.swap_remove(0).id);
+            continue;
+        };
+        let mut ring_node_ids = vec![start_node_id];
+        let mut search_node_id = start_node_id;
+        let mut closed = false;
 
-                if search_node_id == start_node_id {
-                    break;
-                }
+        loop {
+            if let Some(way) = find_match(&search_node_id, ways) {
+                ring_node_ids.extend(way.node_ids.iter().skip(1));
+            } else if let Some(way) =
+                find_nearby_match(&search_node_id, &start_node_id, ways, nodes, gap_tolerance)?
+            {
+                diagnostic.bridged_gaps += 1;
+                ring_node_ids.extend(way.node_ids.iter());
             } else {
-                relation_data_complete = false;
+                break;
+            }
+
+            search_node_id = *ring_node_ids.last().unwrap();
+            if search_node_id == start_node_id {
+                closed = true;
                 break;
             }
         }
 
-        if !relation_data_complete {
-            break;
+        if !closed && within_gap(&search_node_id, &start_node_id, nodes, gap_tolerance)? {
+            diagnostic.bridged_gaps += 1;
+            ring_node_ids.push(start_node_id);
+            closed = true;
+        }
+
+        if !closed {
+            diagnostic.open_rings.push((start_node_id, search_node_id));
+            continue;
         }
-        let outline = LineString::new(polygon_parts);
-        polygons.push(Polygon::new(outline, vec![]));
+
+        let locations: Vec<Coord> = ring_node_ids
+            .iter()
+            .zip(nodes.nodes(&ring_node_ids)?)
+            .filter_map(|(node_id, location)| {
+                if location.is_none() {
+                    diagnostic.missing_nodes.push(*node_id);
+                }
+                location
+            })
+            .map(|(lon, lat)| coord! {x: lon, y: lat})
+            .collect();
+
+        polygons.push(Polygon::new(LineString::new(locations), vec![]));
     }
+
     Ok(polygons)
 }
-fn assemble_polygons(outer_polygons: &Vec<Polygon>, inner_polygons: &Vec<Polygon>) -> MultiPolygon {
+
+/// Decides the role of rings built from ways without a role. A ring nested in an odd number of
+/// the other rings of the relation is a hole, otherwise it is an outline.
+fn infer_roles(
+    outer_polygons: &mut Vec<Polygon>,
+    inner_polygons: &mut Vec<Polygon>,
+    unknown_polygons: Vec<Polygon>,
+) {
+    let depths: Vec<usize> = unknown_polygons
+        .iter()
+        .enumerate()
+        .map(|(index, polygon)| {
+            let unknown_depth = unknown_polygons
+                .iter()
+                .enumerate()
+                .filter(|(other, other_polygon)| {
+                    *other != index && polygon.is_within(*other_polygon)
+                })
+                .count();
+            let known_depth = outer_polygons
+                .iter()
+                .chain(inner_polygons.iter())
+                .filter(|other_polygon| polygon.is_within(*other_polygon))
+                .count();
+            unknown_depth + known_depth
+        })
+        .collect();
+
+    for (polygon, depth) in unknown_polygons.into_iter().zip(depths) {
+        if depth % 2 == 0 {
+            outer_polygons.push(polygon);
+        } else {
+            inner_polygons.push(polygon);
+        }
+    }
+}
+
+fn assemble_polygons(outer_polygons: &[Polygon], inner_polygons: &[Polygon]) -> MultiPolygon {
     let mut result_polygons = Vec::new();
 
     for outer_polygon in outer_polygons.iter() {
@@ -120,29 +396,63 @@ fn assemble_polygons(outer_polygons: &Vec<Polygon>, inner_polygons: &Vec<Polygon
 
 fn build_relations(
     relations: Vec<RelationWithMembers>,
-    ways: HashMap<i64, Vec<i64>>,
-    nodes: HashMap<i64, (f64, f64)>,
-) -> Result<Vec<RelationWithLocations>> {
+    store: &dyn ElementStore,
+    gap_tolerance: f64,
+) -> Result<(Vec<RelationWithLocations>, Vec<RelationDiagnostic>)> {
     let mut processed_relations = Vec::<RelationWithLocations>::new();
+    let mut diagnostics = Vec::<RelationDiagnostic>::new();
 
     for relation in relations {
-        let (mut outer_ways, mut inner_ways) = extract_ways(&relation, &ways);
+        let mut diagnostic = RelationDiagnostic {
+            relation_id: relation.id,
+            ..Default::default()
+        };
+        let (mut outer_ways, mut inner_ways, mut unknown_ways) =
+            extract_ways(&relation, store, &mut diagnostic)?;
 
-        let outer_polygons = build_polygons(&mut outer_ways, &nodes)?;
-        let inner_polygons = build_polygons(&mut inner_ways, &nodes)?;
+        let mut outer_polygons =
+            build_polygons(&mut outer_ways, store, gap_tolerance, &mut diagnostic)?;
+        let mut inner_polygons =
+            build_polygons(&mut inner_ways, store, gap_tolerance, &mut diagnostic)?;
+        let unknown_polygons =
+            build_polygons(&mut unknown_ways, store, gap_tolerance, &mut diagnostic)?;
+        infer_roles(&mut outer_polygons, &mut inner_polygons, unknown_polygons);
         let multi_polygon = assemble_polygons(&outer_polygons, &inner_polygons);
 
-        if outer_polygons.len() < 1 {
+        if diagnostic.has_issues() {
+            diagnostic.missing_nodes.sort_unstable();
+            diagnostic.missing_nodes.dedup();
+            warn!(
+                "Relation {}: {} missing ways, {} missing nodes, {} open rings, {} bridged gaps",
+                relation.id,
+                diagnostic.missing_ways.len(),
+                diagnostic.missing_nodes.len(),
+                diagnostic.open_rings.len(),
+                diagnostic.bridged_gaps
+            );
+            diagnostics.push(diagnostic);
+        }
+
+        if outer_polygons.is_empty() {
+            warn!(
+                "Relation {} has no complete outer ring, skipping",
+                relation.id
+            );
             continue;
         }
 
+        let mut tags = relation.tags;
+        if !relation.subareas.is_empty() {
+            tags.insert("subareas".to_owned(), serde_json::json!(relation.subareas));
+        }
+
         processed_relations.push(RelationWithLocations {
             id: relation.id,
             shape: multi_polygon,
-            tags: relation.tags,
+            tags,
         })
     }
-    Ok(processed_relations)
+    Ok((processed_relations, diagnostics))
 }
 
 fn find_match(node_id: &i64, ways: &mut Vec<Way>) -> Option<Way> {
@@ -164,3 +474,330 @@ fn find_match(node_id: &i64, ways: &mut Vec<Way>) -> Option<Way> {
 
     None
 }
+
+/// Finds a way with an end at most `gap_tolerance` meters away from `node_id`, oriented to
+/// continue from there. The start of the ring itself is never matched.
+fn find_nearby_match(
+    node_id: &i64,
+    start_node_id: &i64,
+    ways: &mut Vec<Way>,
+    nodes: &dyn ElementStore,
+    gap_tolerance: f64,
+) -> Result<Option<Way>> {
+    if gap_tolerance <= 0.0 {
+        return Ok(None);
+    }
+
+    let mut nearest: Option<(usize, bool, f64)> = None;
+    for (i, way) in ways.iter().enumerate() {
+        for (node, reverse) in [(way.node_ids.first(), false), (way.node_ids.last(), true)] {
+            let Some(node) = node else { continue };
+            if node == start_node_id {
+                continue;
+            }
+            if let Some(distance) = node_distance(node_id, node, nodes)? {
+                if distance <= gap_tolerance && nearest.is_none_or(|(_, _, d)| distance < d) {
+                    nearest = Some((i, reverse, distance));
+                }
+            }
+        }
+    }
+
+    Ok(nearest.map(|(i, reverse, _)| {
+        let mut way = ways.swap_remove(i);
+        if reverse {
+            way.node_ids.reverse();
+        }
+        way
+    }))
+}
+
+fn within_gap(
+    node_id: &i64,
+    other_node_id: &i64,
+    nodes: &dyn ElementStore,
+    gap_tolerance: f64,
+) -> Result<bool> {
+    Ok(node_distance(node_id, other_node_id, nodes)?
+        .is_some_and(|distance| distance <= gap_tolerance))
+}
+
+fn node_distance(
+    node_id: &i64,
+    other_node_id: &i64,
+    nodes: &dyn ElementStore,
+) -> Result<Option<f64>> {
+    let (Some((lon, lat)), Some((other_lon, other_lat))) =
+        (nodes.node(*node_id)?, nodes.node(*other_node_id)?)
+    else {
+        return Ok(None);
+    };
+    Ok(Some(Haversine::distance(
+        Point::new(lon, lat),
+        Point::new(other_lon, other_lat),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::element_store::MemoryStore;
+    use geo::Rect;
+
+    /// Stores way `way_id` as a closed square with its south-west corner at `(lon, lat)`, its
+    /// nodes numbered from `way_id * 10`.
+    fn insert_square(store: &MemoryStore, way_id: i64, (lon, lat): (f64, f64), size: f64) {
+        let corners = [
+            (lon, lat),
+            (lon + size, lat),
+            (lon + size, lat + size),
+            (lon, lat + size),
+        ];
+        let node_ids: Vec<i64> = (0..4).map(|i| way_id * 10 + i).collect();
+        store
+            .insert_nodes(node_ids.iter().copied().zip(corners).collect())
+            .unwrap();
+        let mut ring = node_ids.clone();
+        ring.push(node_ids[0]);
+        store.insert_ways(vec![(way_id, ring)]).unwrap();
+    }
+
+    fn relation(id: i64, members: Vec<RelationMember>) -> RelationWithMembers {
+        RelationWithMembers {
+            id,
+            members,
+            relation_members: vec![],
+            subareas: vec![],
+            tags: Default::default(),
+        }
+    }
+
+    fn area_way(id: i64) -> AreaWay {
+        AreaWay {
+            id,
+            tags: Default::default(),
+        }
+    }
+
+    fn feature_ids(features: &[Feature]) -> Vec<String> {
+        features
+            .iter()
+            .map(|feature| match &feature.id {
+                Some(Id::String(id)) => id.clone(),
+                id => panic!("Unexpected feature id {:?}", id),
+            })
+            .collect()
+    }
+
+    fn way(id: i64, node_ids: &[i64]) -> Way {
+        Way {
+            id,
+            node_ids: node_ids.to_vec(),
+            outer: true,
+        }
+    }
+
+    /// Nodes of a unit square at the equator, `5` lies about a meter north of `3`.
+    fn square_nodes() -> MemoryStore {
+        let store = MemoryStore::default();
+        store
+            .insert_nodes(vec![
+                (1, (0.0, 0.0)),
+                (2, (1.0, 0.0)),
+                (3, (1.0, 1.0)),
+                (4, (0.0, 1.0)),
+                (5, (1.0, 1.00001)),
+            ])
+            .unwrap();
+        store
+    }
+
+    fn diagnostic() -> RelationDiagnostic {
+        RelationDiagnostic {
+            relation_id: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bridges_gaps_within_the_tolerance() {
+        let store = square_nodes();
+        let mut ways = vec![way(1, &[1, 2, 3]), way(2, &[5, 4, 1])];
+        let mut diagnostic = diagnostic();
+
+        let polygons = build_polygons(&mut ways, &store, 5.0, &mut diagnostic).unwrap();
+
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].exterior().0.len(), 6);
+        assert_eq!(diagnostic.bridged_gaps, 1);
+        assert!(diagnostic.open_rings.is_empty());
+    }
+
+    #[test]
+    fn closes_a_ring_ending_near_its_start() {
+        let store = square_nodes();
+        let mut ways = vec![way(1, &[3, 4, 1, 2, 5])];
+        let mut diagnostic = diagnostic();
+
+        let polygons = build_polygons(&mut ways, &store, 5.0, &mut diagnostic).unwrap();
+
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(
+            polygons[0].exterior().0.first(),
+            polygons[0].exterior().0.last()
+        );
+        assert_eq!(diagnostic.bridged_gaps, 1);
+    }
+
+    #[test]
+    fn drops_chains_that_cannot_be_closed() {
+        let store = square_nodes();
+        let mut ways = vec![way(1, &[1, 2, 3]), way(2, &[5, 4, 1])];
+        let mut diagnostic = diagnostic();
+
+        // The gap of about a meter is wider than the tolerance
+        let polygons = build_polygons(&mut ways, &store, 0.5, &mut diagnostic).unwrap();
+
+        assert!(polygons.is_empty());
+        assert_eq!(diagnostic.bridged_gaps, 0);
+        assert_eq!(diagnostic.open_rings, vec![(1, 3), (5, 1)]);
+        assert!(ways.is_empty());
+    }
+
+    #[test]
+    fn records_empty_member_ways_instead_of_panicking() {
+        let store = square_nodes();
+        let mut ways = vec![way(1, &[]), way(2, &[1, 2, 3, 4, 1])];
+        let mut diagnostic = diagnostic();
+
+        let polygons = build_polygons(&mut ways, &store, 0.0, &mut diagnostic).unwrap();
+
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(diagnostic.missing_ways, vec![1]);
+    }
+
+    #[test]
+    fn reports_issues_per_relation() {
+        let store = square_nodes();
+        store
+            .insert_ways(vec![
+                (1, vec![1, 2, 3]),
+                (2, vec![5, 4, 1]),
+                (3, vec![1, 6, 3, 1]),
+                (4, vec![2, 4]),
+            ])
+            .unwrap();
+        let relations = vec![
+            relation(
+                100,
+                vec![
+                    RelationMember::OuterMember(1),
+                    RelationMember::OuterMember(2),
+                ],
+            ),
+            relation(
+                200,
+                vec![
+                    RelationMember::OuterMember(9),
+                    RelationMember::OuterMember(1),
+                    RelationMember::OuterMember(2),
+                ],
+            ),
+            relation(
+                300,
+                vec![
+                    RelationMember::OuterMember(3),
+                    RelationMember::OuterMember(4),
+                ],
+            ),
+        ];
+
+        let (relations, diagnostics) = build_relations(relations, &store, 5.0).unwrap();
+
+        assert_eq!(
+            relations
+                .iter()
+                .map(|relation| relation.id)
+                .collect::<Vec<_>>(),
+            vec![100, 200, 300]
+        );
+        let [bridged, missing_way, broken] = &diagnostics[..] else {
+            panic!("Expected three diagnostics, got {:?}", diagnostics);
+        };
+        assert_eq!(bridged.relation_id, 100);
+        assert_eq!(bridged.bridged_gaps, 1);
+        assert!(bridged.missing_ways.is_empty());
+        assert_eq!(missing_way.relation_id, 200);
+        assert_eq!(missing_way.missing_ways, vec![9]);
+        assert_eq!(broken.relation_id, 300);
+        assert_eq!(broken.open_rings, vec![(2, 4)]);
+        assert_eq!(broken.missing_nodes, vec![6]);
+    }
+
+    fn square(lon: f64, lat: f64, size: f64) -> Polygon {
+        Rect::new(
+            coord! {x: lon, y: lat},
+            coord! {x: lon + size, y: lat + size},
+        )
+        .to_polygon()
+    }
+
+    #[test]
+    fn infers_roles_by_nesting_parity() {
+        let mut outer_polygons = vec![];
+        let mut inner_polygons = vec![];
+        let unknown_polygons = vec![
+            square(2.0, 2.0, 2.0),
+            square(0.0, 0.0, 6.0),
+            square(2.5, 2.5, 1.0),
+            square(1.0, 1.0, 4.0),
+            square(10.0, 0.0, 1.0),
+        ];
+
+        infer_roles(&mut outer_polygons, &mut inner_polygons, unknown_polygons);
+
+        assert_eq!(
+            outer_polygons,
+            vec![
+                square(2.0, 2.0, 2.0),
+                square(0.0, 0.0, 6.0),
+                square(10.0, 0.0, 1.0)
+            ]
+        );
+        assert_eq!(
+            inner_polygons,
+            vec![square(2.5, 2.5, 1.0), square(1.0, 1.0, 4.0)]
+        );
+    }
+
+    #[test]
+    fn inferred_roles_count_rings_with_known_roles() {
+        let mut outer_polygons = vec![square(0.0, 0.0, 6.0)];
+        let mut inner_polygons = vec![square(1.0, 1.0, 4.0)];
+        let unknown_polygons = vec![square(2.0, 2.0, 2.0), square(5.25, 5.25, 0.5)];
+
+        infer_roles(&mut outer_polygons, &mut inner_polygons, unknown_polygons);
+
+        assert_eq!(
+            outer_polygons,
+            vec![square(0.0, 0.0, 6.0), square(2.0, 2.0, 2.0)]
+        );
+        assert_eq!(
+            inner_polygons,
+            vec![square(1.0, 1.0, 4.0), square(5.25, 5.25, 0.5)]
+        );
+    }
+
+    #[test]
+    fn member_ways_of_relations_are_not_extracted_again() {
+        let store = MemoryStore::default();
+        insert_square(&store, 1, (0.0, 0.0), 1.0);
+        insert_square(&store, 2, (5.0, 5.0), 1.0);
+        let relations = vec![relation(100, vec![RelationMember::OuterMember(1)])];
+
+        let area_ways = unclaimed_area_ways(vec![area_way(1), area_way(2)], &relations);
+        let features = area_way_features(area_ways, &store, None).unwrap();
+
+        assert_eq!(feature_ids(&features), vec!["way/2"]);
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use osmpbf::RelMemberType;
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+
+/// A relation as written in an OsmChange file.
+#[derive(Debug, Clone, Default)]
+pub struct ChangedRelation {
+    pub members: Vec<(RelMemberType, i64, String)>,
+    pub tags: Vec<(String, String)>,
+}
+
+/// A way as written in an OsmChange file.
+#[derive(Debug, Clone, Default)]
+pub struct ChangedWay {
+    pub node_ids: Vec<i64>,
+    pub tags: Vec<(String, String)>,
+}
+
+/// The elements created, modified or deleted by an OsmChange file.
+/// Deleted elements map to `None`, later changes of an element replace earlier ones.
+#[derive(Debug, Default)]
+pub struct OsmChange {
+    pub nodes: HashMap<i64, Option<(f64, f64)>>,
+    pub ways: HashMap<i64, Option<ChangedWay>>,
+    pub relations: HashMap<i64, Option<ChangedRelation>>,
+}
+
+enum PendingElement {
+    Node(i64, (f64, f64)),
+    Way(i64, ChangedWay),
+    Relation(i64, ChangedRelation),
+}
+
+impl OsmChange {
+    /// Reads an `.osc` file, or a gzip compressed one if the name ends with `.gz`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open change file {}", path.display()))?;
+
+        let reader: Box<dyn BufRead> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(BufReader::new(GzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+
+        Self::from_reader(reader)
+            .with_context(|| format!("Failed to parse change file {}", path.display()))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut reader = Reader::from_reader(reader);
+        let mut change = OsmChange::default();
+        let mut buf = Vec::new();
+        let mut deleting = false;
+        let mut pending: Option<PendingElement> = None;
+
+        loop {
+            let (element, is_empty) = match reader.read_event_into(&mut buf)? {
+                Event::Start(element) => (element, false),
+                Event::Empty(element) => (element, true),
+                Event::End(element) => {
+                    match element.name().as_ref() {
+                        b"delete" => deleting = false,
+                        b"node" | b"way" | b"relation" => {
+                            if let Some(element) = pending.take() {
+                                change.insert(element, deleting);
+                            }
+                        }
+                        _ => {}
+                    }
+                    buf.clear();
+                    continue;
+                }
+                Event::Eof => break,
+                _ => {
+                    buf.clear();
+                    continue;
+                }
+            };
+
+            match element.name().as_ref() {
+                b"delete" => deleting = !is_empty,
+                b"node" => {
+                    let id = parse_attribute(&element, b"id")?;
+                    let location = if deleting {
+                        (0.0, 0.0)
+                    } else {
+                        (
+                            parse_attribute(&element, b"lon")?,
+                            parse_attribute(&element, b"lat")?,
+                        )
+                    };
+                    pending = Some(PendingElement::Node(id, location));
+                }
+                b"way" => {
+                    pending = Some(PendingElement::Way(
+                        parse_attribute(&element, b"id")?,
+                        ChangedWay::default(),
+                    ));
+                }
+                b"relation" => {
+                    pending = Some(PendingElement::Relation(
+                        parse_attribute(&element, b"id")?,
+                        ChangedRelation::default(),
+                    ));
+                }
+                b"nd" => {
+                    if let Some(PendingElement::Way(_, way)) = pending.as_mut() {
+                        way.node_ids.push(parse_attribute(&element, b"ref")?);
+                    }
+                }
+                b"member" => {
+                    if let Some(PendingElement::Relation(_, relation)) = pending.as_mut() {
+                        let member_type = match attribute(&element, b"type")?.as_str() {
+                            "node" => RelMemberType::Node,
+                            "way" => RelMemberType::Way,
+                            "relation" => RelMemberType::Relation,
+                            member_type => bail!("Unknown member type {}", member_type),
+                        };
+                        relation.members.push((
+                            member_type,
+                            parse_attribute(&element, b"ref")?,
+                            attribute(&element, b"role").unwrap_or_default(),
+                        ));
+                    }
+                }
+                b"tag" => {
+                    let tags = match pending.as_mut() {
+                        Some(PendingElement::Way(_, way)) => Some(&mut way.tags),
+                        Some(PendingElement::Relation(_, relation)) => Some(&mut relation.tags),
+                        _ => None,
+                    };
+                    if let Some(tags) = tags {
+                        tags.push((attribute(&element, b"k")?, attribute(&element, b"v")?));
+                    }
+                }
+                _ => {}
+            }
+
+            if is_empty && matches!(element.name().as_ref(), b"node" | b"way" | b"relation") {
+                if let Some(element) = pending.take() {
+                    change.insert(element, deleting);
+                }
+            }
+            buf.clear();
+        }
+
+        Ok(change)
+    }
+
+    fn insert(&mut self, element: PendingElement, deleted: bool) {
+        match element {
+            PendingElement::Node(id, location) => {
+                self.nodes.insert(id, (!deleted).then_some(location));
+            }
+            PendingElement::Way(id, way) => {
+                self.ways.insert(id, (!deleted).then_some(way));
+            }
+            PendingElement::Relation(id, relation) => {
+                self.relations.insert(id, (!deleted).then_some(relation));
+            }
+        }
+    }
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<String> {
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        if attribute.key.as_ref() == name {
+            return Ok(attribute.unescape_value()?.into_owned());
+        }
+    }
+
+    bail!(
+        "Missing attribute {} on <{}>",
+        String::from_utf8_lossy(name),
+        String::from_utf8_lossy(element.name().as_ref())
+    )
+}
+
+fn parse_attribute<T>(element: &BytesStart, name: &[u8]) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(attribute(element, name)?.parse::<T>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+  <create>
+    <node id="1" version="1" lat="47.5" lon="8.25"/>
+    <way id="10" version="1">
+      <nd ref="1"/>
+      <nd ref="2"/>
+      <tag k="natural" v="coastline"/>
+    </way>
+    <relation id="100" version="1">
+      <member type="way" ref="10" role="outer"/>
+      <member type="node" ref="1" role="admin_centre"/>
+      <tag k="boundary" v="administrative"/>
+    </relation>
+  </create>
+  <modify>
+    <node id="2" version="2" lat="-33.75" lon="151.5"/>
+    <way id="11" version="2">
+      <nd ref="2"/>
+    </way>
+    <relation id="101" version="2">
+      <member type="relation" ref="100" role="subarea"/>
+      <member type="way" ref="11" role=""/>
+    </relation>
+    <node id="1" version="2" lat="47.75" lon="8.5"/>
+  </modify>
+  <delete>
+    <node id="3" version="3"/>
+    <way id="12" version="3"/>
+    <relation id="102" version="3"/>
+  </delete>
+</osmChange>
+"#;
+
+    #[test]
+    fn parses_created_modified_and_deleted_elements() {
+        let change = OsmChange::from_reader(CHANGE.as_bytes()).unwrap();
+
+        // The later modification of node 1 replaces its creation
+        assert_eq!(
+            change.nodes,
+            HashMap::from([
+                (1, Some((8.5, 47.75))),
+                (2, Some((151.5, -33.75))),
+                (3, None)
+            ])
+        );
+
+        assert_eq!(change.ways.len(), 3);
+        let created_way = change.ways[&10].as_ref().unwrap();
+        assert_eq!(created_way.node_ids, vec![1, 2]);
+        assert_eq!(
+            created_way.tags,
+            vec![("natural".to_owned(), "coastline".to_owned())]
+        );
+        assert_eq!(change.ways[&11].as_ref().unwrap().node_ids, vec![2]);
+        assert!(change.ways[&12].is_none());
+
+        assert_eq!(change.relations.len(), 3);
+        let created_relation = change.relations[&100].as_ref().unwrap();
+        assert_eq!(
+            created_relation.members,
+            vec![
+                (RelMemberType::Way, 10, "outer".to_owned()),
+                (RelMemberType::Node, 1, "admin_centre".to_owned())
+            ]
+        );
+        assert_eq!(
+            created_relation.tags,
+            vec![("boundary".to_owned(), "administrative".to_owned())]
+        );
+        assert_eq!(
+            change.relations[&101].as_ref().unwrap().members,
+            vec![
+                (RelMemberType::Relation, 100, "subarea".to_owned()),
+                (RelMemberType::Way, 11, String::new())
+            ]
+        );
+        assert!(change.relations[&102].is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_member_types() {
+        let change = r#"<osmChange><create><relation id="1">
+            <member type="area" ref="2" role="outer"/>
+        </relation></create></osmChange>"#;
+
+        assert!(OsmChange::from_reader(change.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+mod flatgeobuf;
+mod geojson_output;
+mod geoparquet;
+
+use std::{collections::HashMap, fmt, path::Path, str::FromStr};
+
+use anyhow::{bail, Result};
+use geo::{MultiPolygon, Polygon};
+use geojson::{feature::Id, Feature, Value};
+
+pub use flatgeobuf::FlatGeobufWriter;
+pub use geojson_output::{FeatureCollectionWriter, GeoJsonLinesWriter};
+pub use geoparquet::GeoParquetWriter;
+
+/// Writes features to a file. Features are encoded and written one at a time, or a row group at a
+/// time, never as a whole. They are passed as a slice as the columnar formats go over them twice,
+/// first for the schema and, for FlatGeobuf, the spatial index.
+pub trait FeatureWriter {
+    fn write(&self, path: &Path, features: &[Feature]) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One GeoJSON feature per line, as read by `process` and `update`
+    GeoJsonLines,
+    /// A single RFC 7946 FeatureCollection
+    FeatureCollection,
+    /// FlatGeobuf with a packed Hilbert R-tree index
+    FlatGeobuf,
+    /// GeoParquet with WKB geometries
+    GeoParquet,
+}
+
+impl OutputFormat {
+    /// Picks the format by file extension, anything unknown is written as GeoJSON lines.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("geojson") | Some("json") => OutputFormat::FeatureCollection,
+            Some("fgb") => OutputFormat::FlatGeobuf,
+            Some("parquet") | Some("geoparquet") => OutputFormat::GeoParquet,
+            _ => OutputFormat::GeoJsonLines,
+        }
+    }
+
+    pub fn writer(&self) -> Box<dyn FeatureWriter> {
+        match self {
+            OutputFormat::GeoJsonLines => Box::new(GeoJsonLinesWriter),
+            OutputFormat::FeatureCollection => Box::new(FeatureCollectionWriter),
+            OutputFormat::FlatGeobuf => Box::new(FlatGeobufWriter),
+            OutputFormat::GeoParquet => Box::new(GeoParquetWriter),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "geojsonl" | "geojson-lines" => Ok(OutputFormat::GeoJsonLines),
+            "geojson" | "feature-collection" => Ok(OutputFormat::FeatureCollection),
+            "flatgeobuf" | "fgb" => Ok(OutputFormat::FlatGeobuf),
+            "geoparquet" | "parquet" => Ok(OutputFormat::GeoParquet),
+            _ => bail!(
+                "Unknown output format {}, expected geojsonl, geojson, flatgeobuf or geoparquet",
+                format
+            ),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::GeoJsonLines => "geojsonl",
+            OutputFormat::FeatureCollection => "geojson",
+            OutputFormat::FlatGeobuf => "flatgeobuf",
+            OutputFormat::GeoParquet => "geoparquet",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Writes `features` to `path`, in the given format or the one matching its extension.
+pub fn write_features(
+    path: &Path,
+    features: &[Feature],
+    format: Option<OutputFormat>,
+) -> Result<()> {
+    format
+        .unwrap_or_else(|| OutputFormat::from_path(path))
+        .writer()
+        .write(path, features)
+}
+
+/// Type of an attribute column of the columnar formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ColumnType {
+    Bool,
+    Long,
+    Double,
+    String,
+    /// Values of mixed types or arrays and objects, stored as JSON text
+    Json,
+}
+
+impl ColumnType {
+    fn of(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::Null => None,
+            serde_json::Value::Bool(_) => Some(ColumnType::Bool),
+            serde_json::Value::Number(number) if number.is_i64() => Some(ColumnType::Long),
+            serde_json::Value::Number(_) => Some(ColumnType::Double),
+            serde_json::Value::String(_) => Some(ColumnType::String),
+            _ => Some(ColumnType::Json),
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnType::Long, ColumnType::Double) | (ColumnType::Double, ColumnType::Long) => {
+                ColumnType::Double
+            }
+            _ => ColumnType::Json,
+        }
+    }
+}
+
+struct Column {
+    name: String,
+    column_type: ColumnType,
+}
+
+/// Attribute columns of a set of features, in order of first appearance. The feature id is
+/// stored in an `id` column unless a property already has that name.
+struct Schema {
+    columns: Vec<Column>,
+    indexes: HashMap<String, usize>,
+}
+
+impl Schema {
+    fn new(features: &[Feature]) -> Self {
+        let mut schema = Schema {
+            columns: Vec::new(),
+            indexes: HashMap::new(),
+        };
+        for feature in features {
+            for (name, value) in feature_row(feature) {
+                let Some(column_type) = ColumnType::of(&value) else {
+                    continue;
+                };
+                match schema.indexes.get(&name) {
+                    Some(&index) => {
+                        let column = &mut schema.columns[index];
+                        column.column_type = column.column_type.merge(column_type);
+                    }
+                    None => {
+                        schema.indexes.insert(name.clone(), schema.columns.len());
+                        schema.columns.push(Column { name, column_type });
+                    }
+                }
+            }
+        }
+        schema
+    }
+
+    /// Non-null values of a feature with the index of their column.
+    fn values(&self, feature: &Feature) -> Vec<(usize, serde_json::Value)> {
+        let mut values: Vec<(usize, serde_json::Value)> = feature_row(feature)
+            .filter(|(_, value)| !value.is_null())
+            .filter_map(|(name, value)| Some((*self.indexes.get(&name)?, value)))
+            .collect();
+        values.sort_by_key(|(index, _)| *index);
+        values
+    }
+}
+
+fn feature_row(feature: &Feature) -> impl Iterator<Item = (String, serde_json::Value)> + '_ {
+    let id = match &feature.id {
+        Some(_) if feature.contains_property("id") => None,
+        Some(Id::String(id)) => Some(serde_json::Value::String(id.clone())),
+        Some(Id::Number(id)) => Some(serde_json::Value::Number(id.clone())),
+        None => None,
+    };
+
+    id.map(|id| ("id".to_owned(), id)).into_iter().chain(
+        feature
+            .properties_iter()
+            .map(|(name, value)| (name.clone(), value.clone())),
+    )
+}
+
+/// Value of a column as text, JSON columns hold the serialized value.
+fn column_text(value: &serde_json::Value, column_type: ColumnType) -> String {
+    match value {
+        serde_json::Value::String(text) if column_type == ColumnType::String => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Geometry of a feature as a MultiPolygon, `None` for features without geometry.
+fn feature_shape(feature: &Feature) -> Result<Option<MultiPolygon>> {
+    let Some(geometry) = &feature.geometry else {
+        return Ok(None);
+    };
+    match &geometry.value {
+        Value::MultiPolygon(_) => Ok(Some(MultiPolygon::try_from(geometry.value.clone())?)),
+        Value::Polygon(_) => Ok(Some(MultiPolygon(vec![Polygon::try_from(
+            geometry.value.clone(),
+        )?]))),
+        value => bail!("Unsupported geometry {}", value.type_name()),
+    }
+}
@@ -0,0 +1,557 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use flatbuffers::{
+    field_index_to_field_offset, FlatBufferBuilder, TableFinishedWIPOffset, VOffsetT, WIPOffset,
+};
+use geo::{BoundingRect, MultiPolygon, Polygon};
+use geojson::Feature;
+
+use super::{column_text, feature_shape, ColumnType, FeatureWriter, Schema};
+
+const MAGIC: &[u8; 8] = b"fgb\x03fgb\x00";
+const INDEX_NODE_SIZE: u16 = 16;
+const GEOMETRY_MULTI_POLYGON: u8 = 6;
+
+/// Writes a FlatGeobuf file with a packed Hilbert R-tree. Features are sorted along the Hilbert
+/// curve and encoded twice, once to size the index and once to write them, so only one encoded
+/// feature is held at a time.
+pub struct FlatGeobufWriter;
+
+impl FeatureWriter for FlatGeobufWriter {
+    fn write(&self, path: &Path, features: &[Feature]) -> Result<()> {
+        let schema = Schema::new(features);
+        let shapes = features
+            .iter()
+            .map(feature_shape)
+            .collect::<Result<Vec<Option<MultiPolygon>>>>()?;
+
+        let mut items: Vec<NodeItem> = shapes
+            .iter()
+            .enumerate()
+            .map(|(index, shape)| {
+                let mut item = NodeItem::empty(index as u64);
+                if let Some(rect) = shape.as_ref().and_then(|shape| shape.bounding_rect()) {
+                    item.min_x = rect.min().x;
+                    item.min_y = rect.min().y;
+                    item.max_x = rect.max().x;
+                    item.max_y = rect.max().y;
+                }
+                item
+            })
+            .collect();
+        let extent = items.iter().fold(NodeItem::empty(0), |mut extent, item| {
+            extent.expand(item);
+            extent
+        });
+        hilbert_sort(&mut items, &extent);
+
+        // Leaf offsets point to the feature in the data section, relative to its start
+        let order: Vec<usize> = items.iter().map(|item| item.offset as usize).collect();
+        let mut offset = 0;
+        for (item, &index) in items.iter_mut().zip(&order) {
+            item.offset = offset;
+            offset +=
+                encode_feature(&features[index], shapes[index].as_ref(), &schema)?.len() as u64;
+        }
+
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&encode_header(&schema, &extent, features.len()))?;
+        if !features.is_empty() {
+            for node in packed_rtree(items) {
+                node.write(&mut writer)?;
+            }
+        }
+        for index in order {
+            writer.write_all(&encode_feature(
+                &features[index],
+                shapes[index].as_ref(),
+                &schema,
+            )?)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn encode_header(schema: &Schema, extent: &NodeItem, features_count: usize) -> Vec<u8> {
+    let mut fbb = FlatBufferBuilder::new();
+    let columns: Vec<_> = schema
+        .columns
+        .iter()
+        .map(|column| {
+            let name = fbb.create_string(&column.name);
+            let start = fbb.start_table();
+            fbb.push_slot_always(slot(0), name);
+            fbb.push_slot_always(slot(1), column_type(column.column_type));
+            fbb.end_table(start)
+        })
+        .collect();
+    let columns = fbb.create_vector(&columns);
+
+    let org = fbb.create_string("EPSG");
+    let start = fbb.start_table();
+    fbb.push_slot_always(slot(0), org);
+    fbb.push_slot_always(slot(1), 4326i32);
+    let crs = fbb.end_table(start);
+
+    let name = fbb.create_string("");
+    let envelope = (features_count > 0)
+        .then(|| fbb.create_vector(&[extent.min_x, extent.min_y, extent.max_x, extent.max_y]));
+
+    let start = fbb.start_table();
+    fbb.push_slot_always(slot(0), name);
+    if let Some(envelope) = envelope {
+        fbb.push_slot_always(slot(1), envelope);
+    }
+    fbb.push_slot_always(slot(2), GEOMETRY_MULTI_POLYGON);
+    fbb.push_slot_always(slot(7), columns);
+    fbb.push_slot_always(slot(8), features_count as u64);
+    fbb.push_slot_always(slot(9), INDEX_NODE_SIZE);
+    fbb.push_slot_always(slot(10), crs);
+    let header = fbb.end_table(start);
+    fbb.finish_size_prefixed(header, None);
+    fbb.finished_data().to_vec()
+}
+
+/// Position of a field's entry in the vtable of its table.
+fn slot(field: VOffsetT) -> VOffsetT {
+    field_index_to_field_offset(field)
+}
+
+fn column_type(column_type: ColumnType) -> u8 {
+    match column_type {
+        ColumnType::Bool => 2,
+        ColumnType::Long => 7,
+        ColumnType::Double => 10,
+        ColumnType::String => 11,
+        ColumnType::Json => 12,
+    }
+}
+
+fn encode_feature(
+    feature: &Feature,
+    shape: Option<&MultiPolygon>,
+    schema: &Schema,
+) -> Result<Vec<u8>> {
+    let mut properties = Vec::new();
+    for (index, value) in schema.values(feature) {
+        let column_type = schema.columns[index].column_type;
+        properties.extend_from_slice(&(index as u16).to_le_bytes());
+        match column_type {
+            ColumnType::Bool => properties.push(u8::from(value.as_bool() == Some(true))),
+            ColumnType::Long => {
+                properties.extend_from_slice(&value.as_i64().unwrap_or_default().to_le_bytes())
+            }
+            ColumnType::Double => {
+                properties.extend_from_slice(&value.as_f64().unwrap_or_default().to_le_bytes())
+            }
+            ColumnType::String | ColumnType::Json => {
+                let text = column_text(&value, column_type);
+                properties.extend_from_slice(&(text.len() as u32).to_le_bytes());
+                properties.extend_from_slice(text.as_bytes());
+            }
+        }
+    }
+
+    let mut fbb = FlatBufferBuilder::new();
+    let geometry = match shape {
+        Some(shape) => {
+            let parts: Vec<_> = shape
+                .0
+                .iter()
+                .map(|polygon| polygon_geometry(&mut fbb, polygon))
+                .collect();
+            let parts = fbb.create_vector(&parts);
+            let start = fbb.start_table();
+            fbb.push_slot_always(slot(6), GEOMETRY_MULTI_POLYGON);
+            fbb.push_slot_always(slot(7), parts);
+            Some(fbb.end_table(start))
+        }
+        None => None,
+    };
+    let properties = fbb.create_vector(&properties);
+
+    let start = fbb.start_table();
+    if let Some(geometry) = geometry {
+        fbb.push_slot_always(slot(0), geometry);
+    }
+    fbb.push_slot_always(slot(1), properties);
+    let feature = fbb.end_table(start);
+    fbb.finish_size_prefixed(feature, None);
+    Ok(fbb.finished_data().to_vec())
+}
+
+fn polygon_geometry(
+    fbb: &mut FlatBufferBuilder,
+    polygon: &Polygon,
+) -> WIPOffset<TableFinishedWIPOffset> {
+    let mut xy = Vec::new();
+    let mut ends = Vec::new();
+    for ring in std::iter::once(polygon.exterior()).chain(polygon.interiors()) {
+        xy.extend(ring.coords().flat_map(|coord| [coord.x, coord.y]));
+        ends.push((xy.len() / 2) as u32);
+    }
+    let ends = fbb.create_vector(&ends);
+    let xy = fbb.create_vector(&xy);
+
+    let start = fbb.start_table();
+    fbb.push_slot_always(slot(0), ends);
+    fbb.push_slot_always(slot(1), xy);
+    fbb.end_table(start)
+}
+
+#[derive(Clone)]
+struct NodeItem {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+    offset: u64,
+}
+
+impl NodeItem {
+    fn empty(offset: u64) -> Self {
+        NodeItem {
+            min_x: f64::INFINITY,
+            min_y: f64::INFINITY,
+            max_x: f64::NEG_INFINITY,
+            max_y: f64::NEG_INFINITY,
+            offset,
+        }
+    }
+
+    fn expand(&mut self, other: &NodeItem) {
+        self.min_x = self.min_x.min(other.min_x);
+        self.min_y = self.min_y.min(other.min_y);
+        self.max_x = self.max_x.max(other.max_x);
+        self.max_y = self.max_y.max(other.max_y);
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for value in [self.min_x, self.min_y, self.max_x, self.max_y] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&self.offset.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+fn hilbert_sort(items: &mut [NodeItem], extent: &NodeItem) {
+    const HILBERT_MAX: f64 = ((1 << 16) - 1) as f64;
+    let width = extent.max_x - extent.min_x;
+    let height = extent.max_y - extent.min_y;
+    let scale = |value: f64, min: f64, size: f64| {
+        if size > 0.0 {
+            (HILBERT_MAX * (value - min) / size) as u32
+        } else {
+            0
+        }
+    };
+
+    items.sort_by_cached_key(|item| {
+        let x = scale((item.min_x + item.max_x) / 2.0, extent.min_x, width);
+        let y = scale((item.min_y + item.max_y) / 2.0, extent.min_y, height);
+        std::cmp::Reverse(hilbert(x, y))
+    });
+}
+
+/// Position of a point on the Hilbert curve of a 2^16 x 2^16 grid.
+fn hilbert(x: u32, y: u32) -> u32 {
+    let mut a = x ^ y;
+    let mut b = 0xFFFF ^ a;
+    let mut c = 0xFFFF ^ (x | y);
+    let mut d = x & (y ^ 0xFFFF);
+
+    let mut aa = a | (b >> 1);
+    let mut bb = (a >> 1) ^ a;
+    let mut cc = ((c >> 1) ^ (b & (d >> 1))) ^ c;
+    let mut dd = ((a & (c >> 1)) ^ (d >> 1)) ^ d;
+
+    a = aa;
+    b = bb;
+    c = cc;
+    d = dd;
+    aa = (a & (a >> 2)) ^ (b & (b >> 2));
+    bb = (a & (b >> 2)) ^ (b & ((a ^ b) >> 2));
+    cc ^= (a & (c >> 2)) ^ (b & (d >> 2));
+    dd ^= (b & (c >> 2)) ^ ((a ^ b) & (d >> 2));
+
+    a = aa;
+    b = bb;
+    c = cc;
+    d = dd;
+    aa = (a & (a >> 4)) ^ (b & (b >> 4));
+    bb = (a & (b >> 4)) ^ (b & ((a ^ b) >> 4));
+    cc ^= (a & (c >> 4)) ^ (b & (d >> 4));
+    dd ^= (b & (c >> 4)) ^ ((a ^ b) & (d >> 4));
+
+    a = aa;
+    b = bb;
+    c = cc;
+    d = dd;
+    cc ^= (a & (c >> 8)) ^ (b & (d >> 8));
+    dd ^= (b & (c >> 8)) ^ ((a ^ b) & (d >> 8));
+
+    a = cc ^ (cc >> 1);
+    b = dd ^ (dd >> 1);
+
+    let mut i0 = x ^ y;
+    let mut i1 = b | (0xFFFF ^ (i0 | a));
+
+    i0 = (i0 | (i0 << 8)) & 0x00FF00FF;
+    i0 = (i0 | (i0 << 4)) & 0x0F0F0F0F;
+    i0 = (i0 | (i0 << 2)) & 0x33333333;
+    i0 = (i0 | (i0 << 1)) & 0x55555555;
+
+    i1 = (i1 | (i1 << 8)) & 0x00FF00FF;
+    i1 = (i1 | (i1 << 4)) & 0x0F0F0F0F;
+    i1 = (i1 | (i1 << 2)) & 0x33333333;
+    i1 = (i1 | (i1 << 1)) & 0x55555555;
+
+    (i1 << 1) | i0
+}
+
+/// Builds the nodes of the packed R-tree from its sorted leaves, root first. Inner nodes point
+/// to the index of their first child.
+fn packed_rtree(leaves: Vec<NodeItem>) -> Vec<NodeItem> {
+    let node_size = usize::from(INDEX_NODE_SIZE);
+    let mut level_sizes = vec![leaves.len()];
+    let mut level_nodes = leaves.len();
+    // Even a single leaf gets a root above it
+    loop {
+        level_nodes = level_nodes.div_ceil(node_size);
+        level_sizes.push(level_nodes);
+        if level_nodes == 1 {
+            break;
+        }
+    }
+    let node_count: usize = level_sizes.iter().sum();
+
+    // Levels are stored root first, leaves last
+    let mut level_offsets = Vec::with_capacity(level_sizes.len());
+    let mut offset = node_count;
+    for size in &level_sizes {
+        offset -= size;
+        level_offsets.push(offset);
+    }
+
+    let mut nodes = vec![NodeItem::empty(0); node_count];
+    nodes[level_offsets[0]..].clone_from_slice(&leaves);
+    for level in 0..level_sizes.len() - 1 {
+        let start = level_offsets[level];
+        let end = start + level_sizes[level];
+        let parents = level_offsets[level + 1]..;
+        for (parent, first_child) in parents.zip((start..end).step_by(node_size)) {
+            let mut node = NodeItem::empty(first_child as u64);
+            for child in &nodes[first_child..end.min(first_child + node_size)] {
+                node.expand(child);
+            }
+            nodes[parent] = node;
+        }
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use geo::polygon;
+    use geojson::{feature::Id, JsonObject};
+    use process::{FeatureReader, FlatGeobufReader};
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const NODE_ITEM_SIZE: usize = 40;
+
+    fn square(x: f64, y: f64, size: f64) -> MultiPolygon {
+        MultiPolygon(vec![polygon![
+            (x: x, y: y),
+            (x: x + size, y: y),
+            (x: x + size, y: y + size),
+            (x: x, y: y + size),
+            (x: x, y: y),
+        ]])
+    }
+
+    fn feature(id: i64, shape: Option<&MultiPolygon>, properties: Value) -> Feature {
+        let properties: JsonObject = serde_json::from_value(properties).unwrap();
+        Feature {
+            bbox: None,
+            geometry: shape.map(|shape| geojson::Geometry::new(geojson::Value::from(shape))),
+            id: Some(Id::Number(id.into())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    }
+
+    fn write(dir: &TempDir, features: &[Feature]) -> (Vec<u8>, Vec<Feature>) {
+        let path = dir.path().join("test.fgb");
+        FlatGeobufWriter.write(&path, features).unwrap();
+        let read = FlatGeobufReader
+            .read(&path)
+            .unwrap()
+            .collect::<Result<Vec<Feature>>>()
+            .unwrap();
+        (fs::read(&path).unwrap(), read)
+    }
+
+    fn shape(feature: &Feature) -> Option<MultiPolygon> {
+        let geometry = feature.geometry.clone()?;
+        Some(geometry.value.try_into().unwrap())
+    }
+
+    fn read_node(bytes: &[u8]) -> NodeItem {
+        let value = |index: usize| bytes[index * 8..index * 8 + 8].try_into().unwrap();
+        NodeItem {
+            min_x: f64::from_le_bytes(value(0)),
+            min_y: f64::from_le_bytes(value(1)),
+            max_x: f64::from_le_bytes(value(2)),
+            max_y: f64::from_le_bytes(value(3)),
+            offset: u64::from_le_bytes(value(4)),
+        }
+    }
+
+    fn bounds(node: &NodeItem) -> [f64; 4] {
+        [node.min_x, node.min_y, node.max_x, node.max_y]
+    }
+
+    #[test]
+    fn writes_an_empty_file() {
+        let dir = TempDir::new().unwrap();
+        let (file, features) = write(&dir, &[]);
+
+        assert!(features.is_empty());
+        assert_eq!(&file[..8], MAGIC);
+        let header_size = u32::from_le_bytes(file[8..12].try_into().unwrap()) as usize;
+        assert_eq!(file.len(), 12 + header_size);
+    }
+
+    #[test]
+    fn round_trips_geometries_and_properties() {
+        let dir = TempDir::new().unwrap();
+        let with_hole = MultiPolygon(vec![polygon!(
+            exterior: [(x: 0.0, y: 0.0), (x: 4.0, y: 0.0), (x: 4.0, y: 4.0), (x: 0.0, y: 4.0)],
+            interiors: [[(x: 1.0, y: 1.0), (x: 1.0, y: 2.0), (x: 2.0, y: 2.0), (x: 2.0, y: 1.0)]],
+        )]);
+        let two_parts =
+            MultiPolygon([square(10.0, 10.0, 1.0).0, square(20.0, 20.0, 1.0).0].concat());
+        let (_, features) = write(
+            &dir,
+            &[
+                feature(
+                    1,
+                    Some(&with_hole),
+                    json!({"name": "Mitte", "population": 42, "area": 1.5, "capital": true, "mixed": 1}),
+                ),
+                feature(
+                    2,
+                    Some(&two_parts),
+                    json!({"name": "Pankow", "mixed": "two"}),
+                ),
+                feature(3, None, json!({"capital": false, "mixed": ["a"]})),
+            ],
+        );
+
+        assert_eq!(features.len(), 3);
+        let by_id = |id: i64| {
+            features
+                .iter()
+                .find(|feature| feature.property("id") == Some(&json!(id)))
+                .unwrap()
+        };
+
+        let first = by_id(1);
+        assert_eq!(shape(first), Some(with_hole));
+        assert_eq!(
+            first.properties,
+            Some(
+                serde_json::from_value(json!({
+                    "id": 1, "name": "Mitte", "population": 42, "area": 1.5, "capital": true,
+                    "mixed": 1
+                }))
+                .unwrap()
+            )
+        );
+
+        let second = by_id(2);
+        assert_eq!(shape(second), Some(two_parts));
+        assert_eq!(second.property("population"), None);
+        assert_eq!(second.property("mixed"), Some(&json!("two")));
+
+        let third = by_id(3);
+        assert_eq!(shape(third), None);
+        assert_eq!(third.property("capital"), Some(&json!(false)));
+        assert_eq!(third.property("mixed"), Some(&json!(["a"])));
+    }
+
+    #[test]
+    fn index_points_to_the_features() {
+        let dir = TempDir::new().unwrap();
+        // Three levels with 16 children per node, 40 leaves under 3 nodes under the root
+        let features: Vec<Feature> = (0..40)
+            .map(|index| {
+                let shape = square((index * 7 % 40) as f64, (index * 13 % 40) as f64, 0.5);
+                feature(index, Some(&shape), json!({}))
+            })
+            .collect();
+        let (file, read) = write(&dir, &features);
+
+        let header_size = u32::from_le_bytes(file[8..12].try_into().unwrap()) as usize;
+        let index_start = 12 + header_size;
+        let data_start = index_start + 44 * NODE_ITEM_SIZE;
+        let nodes: Vec<NodeItem> = file[index_start..data_start]
+            .chunks_exact(NODE_ITEM_SIZE)
+            .map(read_node)
+            .collect();
+
+        // The features follow the index back to back, in the order of the leaves
+        let mut offsets = Vec::new();
+        let mut position = data_start;
+        while position < file.len() {
+            offsets.push((position - data_start) as u64);
+            position +=
+                4 + u32::from_le_bytes(file[position..position + 4].try_into().unwrap()) as usize;
+        }
+        assert_eq!(position, file.len());
+        assert_eq!(offsets.len(), 40);
+        assert_eq!(read.len(), 40);
+
+        let leaves = &nodes[4..];
+        for ((leaf, offset), feature) in leaves.iter().zip(&offsets).zip(&read) {
+            assert_eq!(leaf.offset, *offset);
+            let rect = shape(feature).unwrap().bounding_rect().unwrap();
+            assert_eq!(
+                bounds(leaf),
+                [rect.min().x, rect.min().y, rect.max().x, rect.max().y]
+            );
+        }
+
+        for (parent, first_child) in [(0, 1), (1, 4), (2, 20), (3, 36)] {
+            let node = &nodes[parent];
+            assert_eq!(node.offset, first_child as u64);
+            let children = match parent {
+                0 => &nodes[1..4],
+                _ => &nodes[first_child..(first_child + 16).min(44)],
+            };
+            let extent = children
+                .iter()
+                .fold(NodeItem::empty(0), |mut extent, child| {
+                    extent.expand(child);
+                    extent
+                });
+            assert_eq!(bounds(node), bounds(&extent));
+        }
+        assert_eq!(bounds(&nodes[0]), [0.0, 0.0, 39.5, 39.5]);
+    }
+}
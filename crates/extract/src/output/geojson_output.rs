@@ -0,0 +1,70 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use geo::orient::{Direction, Orient};
+use geojson::{Feature, Geometry, Value};
+
+use super::{feature_shape, FeatureWriter};
+
+/// Writes one feature per line.
+pub struct GeoJsonLinesWriter;
+
+impl FeatureWriter for GeoJsonLinesWriter {
+    fn write(&self, path: &Path, features: &[Feature]) -> Result<()> {
+        let mut writer = create(path)?;
+        for feature in features {
+            serde_json::to_writer(&mut writer, feature)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes a FeatureCollection following RFC 7946, polygon outlines are wound
+/// counter-clockwise and holes clockwise.
+pub struct FeatureCollectionWriter;
+
+impl FeatureWriter for FeatureCollectionWriter {
+    fn write(&self, path: &Path, features: &[Feature]) -> Result<()> {
+        let mut writer = create(path)?;
+        writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+        for (index, feature) in features.iter().enumerate() {
+            if index > 0 {
+                writer.write_all(b",\n")?;
+            }
+            serde_json::to_writer(&mut writer, &right_hand_rule(feature)?)?;
+        }
+        writer.write_all(b"\n]}\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+fn right_hand_rule(feature: &Feature) -> Result<Feature> {
+    let mut feature = feature.clone();
+    let polygon = matches!(
+        feature.geometry.as_ref().map(|geometry| &geometry.value),
+        Some(Value::Polygon(_))
+    );
+
+    if let Some(shape) = feature_shape(&feature)? {
+        let shape = shape.orient(Direction::Default);
+        let value = match shape.0.as_slice() {
+            [outline] if polygon => Value::from(outline),
+            _ => Value::from(&shape),
+        };
+        feature.geometry = Some(Geometry::new(value));
+    }
+    Ok(feature)
+}
@@ -0,0 +1,417 @@
+use std::{fs::File, path::Path, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use geo::{BoundingRect, Geometry, Rect};
+use geojson::Feature;
+use geozero::{CoordDimensions, ToWkb};
+use parquet::{
+    basic::{LogicalType, Repetition, Type as PhysicalType},
+    data_type::{BoolType, ByteArray, ByteArrayType, DataType, DoubleType, Int64Type},
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedFileWriter, SerializedRowGroupWriter},
+    },
+    format::KeyValue,
+    schema::types::Type,
+};
+use serde_json::json;
+
+use super::{column_text, feature_shape, ColumnType, FeatureWriter, Schema};
+
+const ROW_GROUP_SIZE: usize = 1000;
+const GEOMETRY_COLUMN: &str = "geometry";
+
+/// Writes uncompressed GeoParquet 1.0 with WKB geometries, `ROW_GROUP_SIZE` features per row
+/// group. Attribute columns are optional, missing properties are stored as nulls.
+pub struct GeoParquetWriter;
+
+impl FeatureWriter for GeoParquetWriter {
+    fn write(&self, path: &Path, features: &[Feature]) -> Result<()> {
+        let schema = Schema::new(features);
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let properties = WriterProperties::builder()
+            .set_created_by("topodex".to_owned())
+            .build();
+        let mut writer =
+            SerializedFileWriter::new(file, parquet_schema(&schema)?, Arc::new(properties))?;
+
+        let mut bounds: Option<Rect> = None;
+        for chunk in features.chunks(ROW_GROUP_SIZE) {
+            let mut row_group = writer.next_row_group()?;
+
+            let mut geometries = Vec::with_capacity(chunk.len());
+            for feature in chunk {
+                let Some(shape) = feature_shape(feature)? else {
+                    geometries.push(None);
+                    continue;
+                };
+                if let Some(rect) = shape.bounding_rect() {
+                    bounds = Some(match bounds {
+                        Some(bounds) => union(bounds, rect),
+                        None => rect,
+                    });
+                }
+                let wkb = Geometry::MultiPolygon(shape).to_wkb(CoordDimensions::xy())?;
+                geometries.push(Some(ByteArray::from(wkb)));
+            }
+            write_column::<ByteArrayType>(&mut row_group, geometries)?;
+
+            let mut values: Vec<Vec<Option<serde_json::Value>>> = schema
+                .columns
+                .iter()
+                .map(|_| Vec::with_capacity(chunk.len()))
+                .collect();
+            for feature in chunk {
+                let mut feature_values = schema.values(feature).into_iter().peekable();
+                for (index, column_values) in values.iter_mut().enumerate() {
+                    let value = feature_values
+                        .next_if(|(value_index, _)| *value_index == index)
+                        .map(|(_, value)| value);
+                    column_values.push(value);
+                }
+            }
+            for (column, column_values) in schema.columns.iter().zip(values) {
+                let column_values = column_values.into_iter();
+                match column.column_type {
+                    ColumnType::Bool => write_column::<BoolType>(
+                        &mut row_group,
+                        column_values.map(|value| value.map(|value| value.as_bool() == Some(true))),
+                    )?,
+                    ColumnType::Long => write_column::<Int64Type>(
+                        &mut row_group,
+                        column_values
+                            .map(|value| value.map(|value| value.as_i64().unwrap_or_default())),
+                    )?,
+                    ColumnType::Double => write_column::<DoubleType>(
+                        &mut row_group,
+                        column_values
+                            .map(|value| value.map(|value| value.as_f64().unwrap_or_default())),
+                    )?,
+                    column_type @ (ColumnType::String | ColumnType::Json) => {
+                        write_column::<ByteArrayType>(
+                            &mut row_group,
+                            column_values.map(|value| {
+                                value.map(|value| {
+                                    ByteArray::from(column_text(&value, column_type).into_bytes())
+                                })
+                            }),
+                        )?
+                    }
+                }
+            }
+
+            row_group.close()?;
+        }
+
+        writer.append_key_value_metadata(KeyValue::new("geo".to_owned(), geo_metadata(bounds)));
+        writer.close()?;
+        Ok(())
+    }
+}
+
+fn parquet_schema(schema: &Schema) -> Result<Arc<Type>> {
+    let mut fields = vec![Arc::new(
+        Type::primitive_type_builder(GEOMETRY_COLUMN, PhysicalType::BYTE_ARRAY)
+            .with_repetition(Repetition::OPTIONAL)
+            .build()?,
+    )];
+    for column in &schema.columns {
+        let (physical_type, logical_type) = match column.column_type {
+            ColumnType::Bool => (PhysicalType::BOOLEAN, None),
+            ColumnType::Long => (PhysicalType::INT64, None),
+            ColumnType::Double => (PhysicalType::DOUBLE, None),
+            ColumnType::String => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            ColumnType::Json => (PhysicalType::BYTE_ARRAY, Some(LogicalType::Json)),
+        };
+        fields.push(Arc::new(
+            Type::primitive_type_builder(&column.name, physical_type)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(logical_type)
+                .build()?,
+        ));
+    }
+
+    Ok(Arc::new(
+        Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()?,
+    ))
+}
+
+/// Writes the next column of a row group, nulls become a definition level of zero.
+fn write_column<T: DataType>(
+    row_group: &mut SerializedRowGroupWriter<File>,
+    values: impl IntoIterator<Item = Option<T::T>>,
+) -> Result<()> {
+    let mut levels = Vec::new();
+    let mut present = Vec::new();
+    for value in values {
+        levels.push(i16::from(value.is_some()));
+        present.extend(value);
+    }
+
+    let Some(mut column) = row_group.next_column()? else {
+        bail!("Row group has fewer columns than the schema");
+    };
+    column
+        .typed::<T>()
+        .write_batch(&present, Some(&levels), None)?;
+    column.close()?;
+    Ok(())
+}
+
+fn geo_metadata(bounds: Option<Rect>) -> String {
+    let mut column = json!({
+        "encoding": "WKB",
+        "geometry_types": ["MultiPolygon"],
+    });
+    if let Some(bounds) = bounds {
+        column["bbox"] = json!([
+            bounds.min().x,
+            bounds.min().y,
+            bounds.max().x,
+            bounds.max().y
+        ]);
+    }
+    json!({
+        "version": "1.0.0",
+        "primary_column": GEOMETRY_COLUMN,
+        "columns": { GEOMETRY_COLUMN: column },
+    })
+    .to_string()
+}
+
+fn union(a: Rect, b: Rect) -> Rect {
+    Rect::new(
+        (a.min().x.min(b.min().x), a.min().y.min(b.min().y)),
+        (a.max().x.max(b.max().x), a.max().y.max(b.max().y)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{polygon, MultiPolygon};
+    use geojson::{feature::Id, JsonObject};
+    use geozero::{wkb::Wkb, ToGeo};
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::{Field, Row},
+    };
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn square(x: f64, y: f64) -> MultiPolygon {
+        MultiPolygon(vec![polygon![
+            (x: x, y: y),
+            (x: x + 1.0, y: y),
+            (x: x + 1.0, y: y + 1.0),
+            (x: x, y: y + 1.0),
+            (x: x, y: y),
+        ]])
+    }
+
+    fn feature(id: i64, shape: Option<&MultiPolygon>, properties: Value) -> Feature {
+        let properties: JsonObject = serde_json::from_value(properties).unwrap();
+        Feature {
+            bbox: None,
+            geometry: shape.map(|shape| geojson::Geometry::new(geojson::Value::from(shape))),
+            id: Some(Id::Number(id.into())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    }
+
+    fn write(dir: &TempDir, features: &[Feature]) -> SerializedFileReader<File> {
+        let path = dir.path().join("test.parquet");
+        GeoParquetWriter.write(&path, features).unwrap();
+        SerializedFileReader::new(File::open(path).unwrap()).unwrap()
+    }
+
+    fn rows(reader: &SerializedFileReader<File>) -> Vec<Row> {
+        reader
+            .get_row_iter(None)
+            .unwrap()
+            .collect::<parquet::errors::Result<Vec<Row>>>()
+            .unwrap()
+    }
+
+    fn field<'a>(row: &'a Row, name: &str) -> &'a Field {
+        row.get_column_iter()
+            .find(|(column, _)| column.as_str() == name)
+            .map(|(_, field)| field)
+            .unwrap()
+    }
+
+    fn geometry(row: &Row) -> Option<MultiPolygon> {
+        match field(row, GEOMETRY_COLUMN) {
+            Field::Bytes(bytes) => match Wkb(bytes.data()).to_geo().unwrap() {
+                Geometry::MultiPolygon(shape) => Some(shape),
+                geometry => panic!("Unexpected geometry {:?}", geometry),
+            },
+            Field::Null => None,
+            field => panic!("Unexpected geometry field {:?}", field),
+        }
+    }
+
+    fn geo_metadata(reader: &SerializedFileReader<File>) -> Value {
+        let metadata = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap();
+        let geo = metadata.iter().find(|entry| entry.key == "geo").unwrap();
+        serde_json::from_str(geo.value.as_deref().unwrap()).unwrap()
+    }
+
+    fn column_names(reader: &SerializedFileReader<File>) -> Vec<String> {
+        let schema = reader.metadata().file_metadata().schema_descr();
+        schema
+            .columns()
+            .iter()
+            .map(|column| column.name().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn writes_an_empty_file() {
+        let dir = TempDir::new().unwrap();
+        let reader = write(&dir, &[]);
+
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+        assert_eq!(reader.num_row_groups(), 0);
+        assert_eq!(column_names(&reader), [GEOMETRY_COLUMN]);
+        let geo = geo_metadata(&reader);
+        assert_eq!(geo["primary_column"], GEOMETRY_COLUMN);
+        assert!(geo["columns"][GEOMETRY_COLUMN].get("bbox").is_none());
+    }
+
+    #[test]
+    fn round_trips_geometries_and_properties() {
+        let dir = TempDir::new().unwrap();
+        let shape = square(13.0, 52.0);
+        let reader = write(
+            &dir,
+            &[
+                feature(
+                    1,
+                    Some(&shape),
+                    json!({"name": "Mitte", "population": 42, "area": 1.5, "capital": true}),
+                ),
+                feature(2, None, json!({"name": "Pankow", "capital": false})),
+            ],
+        );
+
+        assert_eq!(
+            column_names(&reader),
+            [
+                GEOMETRY_COLUMN,
+                "id",
+                "area",
+                "capital",
+                "name",
+                "population"
+            ]
+        );
+        let rows = rows(&reader);
+        assert_eq!(rows.len(), 2);
+
+        assert_eq!(geometry(&rows[0]), Some(shape));
+        assert_eq!(field(&rows[0], "id"), &Field::Long(1));
+        assert_eq!(field(&rows[0], "name"), &Field::Str("Mitte".to_owned()));
+        assert_eq!(field(&rows[0], "population"), &Field::Long(42));
+        assert_eq!(field(&rows[0], "area"), &Field::Double(1.5));
+        assert_eq!(field(&rows[0], "capital"), &Field::Bool(true));
+
+        assert_eq!(geometry(&rows[1]), None);
+        assert_eq!(field(&rows[1], "population"), &Field::Null);
+        assert_eq!(field(&rows[1], "area"), &Field::Null);
+        assert_eq!(field(&rows[1], "capital"), &Field::Bool(false));
+
+        assert_eq!(
+            geo_metadata(&reader)["columns"][GEOMETRY_COLUMN]["bbox"],
+            json!([13.0, 52.0, 14.0, 53.0])
+        );
+    }
+
+    #[test]
+    fn mixed_columns_become_json() {
+        let dir = TempDir::new().unwrap();
+        let reader = write(
+            &dir,
+            &[
+                feature(1, None, json!({"mixed": 1, "number": 1, "names": ["a"]})),
+                feature(2, None, json!({"mixed": "two", "number": 2.5})),
+                feature(3, None, json!({"mixed": null})),
+            ],
+        );
+
+        let schema = reader.metadata().file_metadata().schema_descr();
+        let logical_type = |name: &str| {
+            let column = schema.columns().iter().find(|c| c.name() == name).unwrap();
+            (column.physical_type(), column.logical_type())
+        };
+        assert_eq!(
+            logical_type("mixed"),
+            (PhysicalType::BYTE_ARRAY, Some(LogicalType::Json))
+        );
+        assert_eq!(
+            logical_type("names"),
+            (PhysicalType::BYTE_ARRAY, Some(LogicalType::Json))
+        );
+        assert_eq!(logical_type("number"), (PhysicalType::DOUBLE, None));
+
+        let rows = rows(&reader);
+        assert_eq!(field(&rows[0], "mixed"), &Field::Str("1".to_owned()));
+        assert_eq!(field(&rows[1], "mixed"), &Field::Str("\"two\"".to_owned()));
+        assert_eq!(field(&rows[2], "mixed"), &Field::Null);
+        assert_eq!(field(&rows[0], "names"), &Field::Str("[\"a\"]".to_owned()));
+        assert_eq!(field(&rows[0], "number"), &Field::Double(1.0));
+        assert_eq!(field(&rows[1], "number"), &Field::Double(2.5));
+    }
+
+    #[test]
+    fn splits_features_into_row_groups() {
+        let dir = TempDir::new().unwrap();
+        let count = 2 * ROW_GROUP_SIZE + 1;
+        let features: Vec<Feature> = (0..count)
+            .map(|index| {
+                let shape = square(index as f64, 0.0);
+                let properties = match index % 2 {
+                    0 => json!({"index": index}),
+                    _ => json!({}),
+                };
+                feature(index as i64, Some(&shape), properties)
+            })
+            .collect();
+        let reader = write(&dir, &features);
+
+        assert_eq!(reader.metadata().file_metadata().num_rows(), count as i64);
+        let row_group_rows: Vec<i64> = reader
+            .metadata()
+            .row_groups()
+            .iter()
+            .map(|row_group| row_group.num_rows())
+            .collect();
+        assert_eq!(
+            row_group_rows,
+            [ROW_GROUP_SIZE as i64, ROW_GROUP_SIZE as i64, 1]
+        );
+
+        let rows = rows(&reader);
+        for (index, row) in rows.iter().enumerate() {
+            assert_eq!(geometry(row), Some(square(index as f64, 0.0)));
+            let expected = match index % 2 {
+                0 => Field::Long(index as i64),
+                _ => Field::Null,
+            };
+            assert_eq!(field(row, "index"), &expected);
+        }
+        assert_eq!(
+            geo_metadata(&reader)["columns"][GEOMETRY_COLUMN]["bbox"],
+            json!([0.0, 0.0, count as f64, 1.0])
+        );
+    }
+}
@@ -1,122 +1,420 @@
 use anyhow::Result;
-use log::info;
-use osmpbf::{Element, Relation};
+use log::{info, warn};
+use osmpbf::{Element, RelMemberType, Relation};
 use serde_json::Value;
 use std::{
     collections::{HashMap, HashSet},
+    sync::Mutex,
     time::Instant,
 };
-use util::{RelationMember, RelationWithMembers, TopodexConfig};
+use util::{AreaWay, Coverage, MemberRoles, RelationMember, RelationWithMembers, TopodexConfig};
 
-use crate::element_collection_reader::ElementCollectReader;
+use crate::element_collection_reader::{BlobIndex, ElementCollectReader, ElementType};
+use crate::element_store::ElementStore;
+use crate::osm_change::{ChangedRelation, OsmChange};
 
+/// How many ids are marked as wanted in the element store at once.
+const WANTED_CHUNK: usize = 1 << 20;
+
+/// Elements read for an extract, their ways and nodes are kept in the element store.
+pub struct OsmElements {
+    pub relations: Vec<RelationWithMembers>,
+    pub area_ways: Vec<AreaWay>,
+    /// `natural=coastline` ways, only read for land coverage
+    pub coastline_ways: Vec<i64>,
+}
+
+/// Reads the matching relations, and closed ways and coastlines if enabled, and stores the ways
+/// and nodes they need in `store`. With a `change` the elements of the change file replace those
+/// of the PBF file and only the relations and closed ways touched by the change are returned,
+/// all relations if the coastline changed.
 pub fn read_osm_elements(
     path: &str,
     extract_config: &TopodexConfig,
-) -> Result<(
-    Vec<RelationWithMembers>,
-    HashMap<i64, Vec<i64>>,
-    HashMap<i64, (f64, f64)>,
-)> {
+    change: Option<&OsmChange>,
+    store: &dyn ElementStore,
+) -> Result<OsmElements> {
     let start = Instant::now();
-    let relations = read_relations(
-        &path,
-        &extract_config.filters,
-        &extract_config.extract_properties,
-    )?;
+    let (mut relations, blob_index) = read_relations(path, extract_config)?;
+    if let Some(change) = change {
+        relations.retain(|relation| !change.relations.contains_key(&relation.id));
+        relations.extend(change.relations.iter().filter_map(|(id, relation)| {
+            let relation = relation.as_ref()?;
+            let tags: Vec<(&str, &str)> = relation
+                .tags
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect();
+
+            tag_filter(&tags, extract_config).then(|| {
+                changed_relation(
+                    *id,
+                    relation,
+                    extract_tags(&tags, extract_config),
+                    extract_config.member_roles,
+                )
+            })
+        }));
+    }
     info!("Relations extract: {} seconds", start.elapsed().as_secs());
+    info!(
+        "Indexed blobs: {} with nodes, {} with ways, {} with relations",
+        blob_index.blob_count(ElementType::Node),
+        blob_index.blob_count(ElementType::Way),
+        blob_index.blob_count(ElementType::Relation)
+    );
 
     let start = Instant::now();
-    let ways_set: HashSet<i64> = relations
-        .iter()
-        .map(|relation| relation.members.iter().map(|member| member.to_i64()))
-        .flatten()
-        .collect();
+    let nested_relations = read_nested_relations(&blob_index, &relations, extract_config, change)?;
+    let mut changed_relations = HashSet::<i64>::new();
+    for relation in relations.iter_mut() {
+        let mut visited = HashSet::new();
+        relation.members = flatten_members(relation, &nested_relations, &mut visited);
+        if change.is_some_and(|change| visited.iter().any(|id| change.relations.contains_key(id))) {
+            changed_relations.insert(relation.id);
+        }
+    }
+    info!(
+        "Nested relations extract: {} relations in {} seconds",
+        nested_relations.len(),
+        start.elapsed().as_secs()
+    );
+
+    let start = Instant::now();
+    insert_wanted(
+        store,
+        ElementType::Way,
+        relations
+            .iter()
+            .flat_map(|relation| relation.members.iter().map(|member| member.to_i64())),
+    )?;
     info!("Ways set: {} seconds", start.elapsed().as_secs());
 
     let start = Instant::now();
-    let ways = read_ways(&path, &ways_set)?;
-    info!("Ways extract: {} seconds", start.elapsed().as_secs());
+    let (mut area_ways, coastline_ways) = read_ways(&blob_index, extract_config, change, store)?;
+    info!(
+        "Ways extract: {} closed ways, {} coastline ways in {} seconds",
+        area_ways.len(),
+        coastline_ways.len(),
+        start.elapsed().as_secs()
+    );
+
+    if let Some(change) = change {
+        let coastline_changed = ways_changed(coastline_ways.iter().copied(), change, store)?;
+        let mut affected = Vec::with_capacity(relations.len());
+        for relation in relations {
+            if coastline_changed
+                || changed_relations.contains(&relation.id)
+                || ways_changed(
+                    relation.members.iter().map(|member| member.to_i64()),
+                    change,
+                    store,
+                )?
+            {
+                affected.push(relation);
+            }
+        }
+        relations = affected;
+        let mut affected = Vec::with_capacity(area_ways.len());
+        for area_way in area_ways {
+            if ways_changed([area_way.id], change, store)? {
+                affected.push(area_way);
+            }
+        }
+        area_ways = affected;
+        info!(
+            "Affected by change: {} relations, {} closed ways",
+            relations.len(),
+            area_ways.len()
+        );
+    }
 
     let start = Instant::now();
-    let nodes_set: HashSet<i64> = ways
-        .iter()
-        .map(|(_, node_ids)| node_ids.clone())
-        .flatten()
-        .collect();
+    insert_wanted_way_nodes(
+        store,
+        relations
+            .iter()
+            .flat_map(|relation| relation.members.iter().map(|member| member.to_i64()))
+            .chain(area_ways.iter().map(|area_way| area_way.id))
+            .chain(coastline_ways.iter().copied()),
+    )?;
     info!("Nodes set: {} seconds", start.elapsed().as_secs());
 
     let start = Instant::now();
-    let nodes = read_nodes(&path, &nodes_set)?;
+    read_nodes(&blob_index, change, store)?;
     info!("Nodes extract: {} seconds", start.elapsed().as_secs());
 
-    Ok((relations, ways, nodes))
+    Ok(OsmElements {
+        relations,
+        area_ways,
+        coastline_ways,
+    })
 }
 
 fn read_relations(
     path: &str,
-    required_tags: &[(String, Option<String>)],
-    property_filters: &[(String, Option<String>)],
-) -> Result<Vec<RelationWithMembers>, osmpbf::Error> {
-    let relations = ElementCollectReader::from_path(path)?.elements(|element| match element {
-        Element::Relation(relation) => {
-            let match_tags = relation_filter(&relation, &required_tags);
-
-            if !match_tags {
-                return None;
+    extract_config: &TopodexConfig,
+) -> Result<(Vec<RelationWithMembers>, BlobIndex), osmpbf::Error> {
+    let relations =
+        ElementCollectReader::from_path(path)?.indexed_elements(|element| match element {
+            Element::Relation(relation) => {
+                let tags: Vec<(&str, &str)> = relation.tags().collect();
+                if !tag_filter(&tags, extract_config) {
+                    return None;
+                }
+
+                Some(pbf_relation(
+                    &relation,
+                    extract_tags(&tags, extract_config),
+                    extract_config.member_roles,
+                ))
             }
+            _ => None,
+        })?;
 
-            let members: Vec<RelationMember> = relation
-                .members()
-                .into_iter()
-                .filter_map(|member| match member.role() {
-                    Result::Ok("outer") => Some(RelationMember::OuterMember(member.member_id)),
-                    Result::Ok("inner") => Some(RelationMember::InnerMember(member.member_id)),
-                    Result::Ok(_) => None,
-                    Result::Err(_) => None,
-                })
-                .collect();
+    Ok(relations)
+}
 
-            let tags: serde_json::Map<String, Value> = relation
-                .tags()
-                .filter_map(|(key, value)| {
-                    for (fkey, rkey) in property_filters {
-                        if fkey == key {
-                            let nkey = (rkey.as_deref().unwrap_or(fkey)).to_owned();
-                            let nval = serde_json::Value::String(value.to_owned());
-                            return Some((nkey, nval));
-                        }
-                    }
-                    None
-                })
-                .collect();
+/// Keeps the tags matching `extract_properties` under their new names, and all other tags under
+/// their own names if `keep_all_tags` is set.
+fn extract_tags(
+    tags: &[(&str, &str)],
+    extract_config: &TopodexConfig,
+) -> serde_json::Map<String, Value> {
+    tags.iter()
+        .filter_map(|(key, value)| {
+            let nkey = extract_config
+                .extract_properties
+                .iter()
+                .find_map(|(fkey, rkey)| property_key(key, fkey, rkey.as_deref()))
+                .or_else(|| extract_config.keep_all_tags.then(|| (*key).to_owned()))?;
+            Some((nkey, serde_json::Value::String((*value).to_owned())))
+        })
+        .collect()
+}
 
-            let out_rel = RelationWithMembers {
-                id: relation.id(),
-                members,
-                tags,
-            };
+/// Name under which the tag `key` is kept if it matches `fkey`. A trailing `*` in `fkey` matches
+/// any suffix, which replaces the `*` in `rkey`.
+fn property_key(key: &str, fkey: &str, rkey: Option<&str>) -> Option<String> {
+    match fkey.strip_suffix('*') {
+        Some(prefix) => {
+            let suffix = key.strip_prefix(prefix)?;
+            Some(rkey.map_or_else(|| key.to_owned(), |rkey| rkey.replacen('*', suffix, 1)))
+        }
+        None => (fkey == key).then(|| rkey.unwrap_or(fkey).to_owned()),
+    }
+}
+
+/// Reads the relations referenced as `outer` or `inner` members, level by level, until all
+/// nested relations are known. Relations missing in the file are left out.
+fn read_nested_relations(
+    blob_index: &BlobIndex,
+    relations: &[RelationWithMembers],
+    extract_config: &TopodexConfig,
+    change: Option<&OsmChange>,
+) -> Result<HashMap<i64, RelationWithMembers>, osmpbf::Error> {
+    let mut nested_relations = HashMap::<i64, RelationWithMembers>::new();
+    let mut requested = HashSet::<i64>::new();
+    let mut pending: HashSet<i64> = relations
+        .iter()
+        .flat_map(|relation| {
+            relation
+                .relation_members
+                .iter()
+                .map(|member| member.to_i64())
+        })
+        .collect();
 
-            Some(out_rel)
+    while !pending.is_empty() {
+        let mut found = Vec::<RelationWithMembers>::new();
+        if let Some(change) = change {
+            found.extend(pending.iter().filter_map(|id| {
+                let relation = change.relations.get(id)?.as_ref()?;
+                Some(changed_relation(
+                    *id,
+                    relation,
+                    serde_json::Map::new(),
+                    extract_config.member_roles,
+                ))
+            }));
+            pending.retain(|id| !change.relations.contains_key(id));
         }
-        _ => None,
-    })?;
 
-    Ok(relations)
+        found.extend(
+            blob_index.elements(ElementType::Relation, |element| match element {
+                Element::Relation(relation) if pending.contains(&relation.id()) => {
+                    Some(pbf_relation(
+                        &relation,
+                        serde_json::Map::new(),
+                        extract_config.member_roles,
+                    ))
+                }
+                _ => None,
+            })?,
+        );
+
+        requested.extend(pending.drain());
+        requested.extend(found.iter().map(|relation| relation.id));
+        pending = found
+            .iter()
+            .flat_map(|relation| {
+                relation
+                    .relation_members
+                    .iter()
+                    .map(|member| member.to_i64())
+            })
+            .filter(|id| !requested.contains(id))
+            .collect();
+        nested_relations.extend(found.into_iter().map(|relation| (relation.id, relation)));
+    }
+
+    Ok(nested_relations)
+}
+
+fn pbf_relation(
+    relation: &Relation,
+    tags: serde_json::Map<String, Value>,
+    member_roles: MemberRoles,
+) -> RelationWithMembers {
+    relation_with_members(
+        relation.id(),
+        relation.members().map(|member| {
+            (
+                member.member_type.clone(),
+                member.member_id,
+                member.role().unwrap_or_default(),
+            )
+        }),
+        tags,
+        member_roles,
+    )
+}
+
+fn changed_relation(
+    id: i64,
+    relation: &ChangedRelation,
+    tags: serde_json::Map<String, Value>,
+    member_roles: MemberRoles,
+) -> RelationWithMembers {
+    relation_with_members(
+        id,
+        relation
+            .members
+            .iter()
+            .map(|(member_type, member_id, role)| (member_type.clone(), *member_id, role.as_str())),
+        tags,
+        member_roles,
+    )
+}
+
+fn relation_with_members<'a>(
+    id: i64,
+    relation_members_iter: impl Iterator<Item = (RelMemberType, i64, &'a str)>,
+    tags: serde_json::Map<String, Value>,
+    member_roles: MemberRoles,
+) -> RelationWithMembers {
+    let mut members = Vec::<RelationMember>::new();
+    let mut relation_members = Vec::<RelationMember>::new();
+    let mut subareas = Vec::<i64>::new();
+
+    for (member_type, member_id, role) in relation_members_iter {
+        let role = match (member_roles, role) {
+            (MemberRoles::Legacy | MemberRoles::Infer, "exclave") => "outer",
+            (MemberRoles::Legacy | MemberRoles::Infer, "enclave") => "inner",
+            _ => role,
+        };
+        match (member_type, role) {
+            (RelMemberType::Way, "outer") => members.push(RelationMember::OuterMember(member_id)),
+            (RelMemberType::Way, "inner") => members.push(RelationMember::InnerMember(member_id)),
+            (RelMemberType::Way, "") if member_roles == MemberRoles::Infer => {
+                members.push(RelationMember::UnknownMember(member_id))
+            }
+            (RelMemberType::Relation, "outer") => {
+                relation_members.push(RelationMember::OuterMember(member_id))
+            }
+            (RelMemberType::Relation, "inner") => {
+                relation_members.push(RelationMember::InnerMember(member_id))
+            }
+            (RelMemberType::Relation, "subarea") => subareas.push(member_id),
+            _ => {}
+        }
+    }
+
+    RelationWithMembers {
+        id,
+        members,
+        relation_members,
+        subareas,
+        tags,
+    }
 }
 
-fn relation_filter(relation: &Relation, required_tags: &[(String, Option<String>)]) -> bool {
-    for required_tag in required_tags {
+/// Resolves the way members of nested relations into the members of `relation`.
+/// Ways of a relation referenced as `inner` swap their roles, its outline is a hole of the parent.
+/// Ways without a role keep it, their rings are placed by the geometry of the parent.
+fn flatten_members(
+    relation: &RelationWithMembers,
+    nested_relations: &HashMap<i64, RelationWithMembers>,
+    visited: &mut HashSet<i64>,
+) -> Vec<RelationMember> {
+    let mut members = relation.members.clone();
+    visited.insert(relation.id);
+
+    for relation_member in &relation.relation_members {
+        let Some(nested_relation) = nested_relations.get(&relation_member.to_i64()) else {
+            warn!(
+                "Relation {} references missing relation {}",
+                relation.id,
+                relation_member.to_i64()
+            );
+            continue;
+        };
+
+        if !visited.insert(nested_relation.id) {
+            continue;
+        }
+
+        let nested_members = flatten_members(nested_relation, nested_relations, visited);
+        members.extend(
+            nested_members
+                .into_iter()
+                .map(|member| match (relation_member, member) {
+                    (RelationMember::OuterMember(_), member) => member,
+                    (RelationMember::InnerMember(_), RelationMember::OuterMember(id)) => {
+                        RelationMember::InnerMember(id)
+                    }
+                    (RelationMember::InnerMember(_), RelationMember::InnerMember(id)) => {
+                        RelationMember::OuterMember(id)
+                    }
+                    (_, member) => member,
+                }),
+        );
+    }
+
+    members
+}
+
+/// Whether the tags contain all of the config's `filters` and match its filter expression.
+fn tag_filter(tags: &[(&str, &str)], extract_config: &TopodexConfig) -> bool {
+    if !extract_config
+        .filter
+        .as_ref()
+        .is_none_or(|filter| filter.matches(tags))
+    {
+        return false;
+    }
+
+    for required_tag in &extract_config.filters {
         let req_val = required_tag.1.as_deref();
         let mut found_tag = false;
-        for (key, val) in relation.tags() {
-            if key == required_tag.0 && (req_val.is_none() || val == req_val.unwrap()) {
+        for (key, val) in tags {
+            if *key == required_tag.0 && (req_val.is_none() || *val == req_val.unwrap()) {
                 found_tag = true;
                 break;
             }
         }
 
-        if found_tag == false {
+        if !found_tag {
             return found_tag;
         }
     }
@@ -124,45 +422,408 @@ fn relation_filter(relation: &Relation, required_tags: &[(String, Option<String>
     true
 }
 
-fn read_ways(path: &str, ways_set: &HashSet<i64>) -> Result<HashMap<i64, Vec<i64>>, osmpbf::Error> {
-    let ways = ElementCollectReader::from_path(path)?
-        .elements(|element| match element {
+/// Id and node ids of a way, with its tags if it counts as an area and whether it is part of
+/// the coastline.
+type ReadWay = (i64, Vec<i64>, Option<serde_json::Map<String, Value>>, bool);
+
+/// Stores the wanted ways and, if enabled, the closed ways matching the filters and the
+/// coastline ways. The closed ways are returned as areas, next to the ids of the coastline ways.
+/// Whether the ways are wanted is looked up for all ways of a blob at once.
+fn read_ways(
+    blob_index: &BlobIndex,
+    extract_config: &TopodexConfig,
+    change: Option<&OsmChange>,
+    store: &dyn ElementStore,
+) -> Result<(Vec<AreaWay>, Vec<i64>)> {
+    let land = extract_config.coverage == Coverage::Land;
+    let area_ways = Mutex::new(Vec::<AreaWay>::new());
+    let coastline_ways = Mutex::new(Vec::<i64>::new());
+    let collect_ways = |ways: Vec<ReadWay>| -> Result<()> {
+        let ids: Vec<i64> = ways.iter().map(|(id, ..)| *id).collect();
+        let wanted = store.wanted(ElementType::Way, &ids)?;
+        let ways = ways
+            .into_iter()
+            .zip(wanted)
+            .filter(|((_, _, area_tags, coastline), member)| {
+                *member || area_tags.is_some() || *coastline
+            })
+            .map(|((id, node_ids, area_tags, coastline), _)| {
+                if let Some(tags) = area_tags {
+                    area_ways.lock().unwrap().push(AreaWay { id, tags });
+                }
+                if coastline {
+                    coastline_ways.lock().unwrap().push(id);
+                }
+                (id, node_ids)
+            })
+            .collect();
+        store.insert_ways(ways)
+    };
+
+    if let Some(change) = change {
+        collect_ways(
+            change
+                .ways
+                .iter()
+                .filter_map(|(id, way)| {
+                    let way = way.as_ref()?;
+                    let tags: Vec<(&str, &str)> = way
+                        .tags
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), value.as_str()))
+                        .collect();
+                    let area_tags = area_tags(&way.node_ids, &tags, extract_config);
+                    let coastline = land && is_coastline(&tags);
+
+                    Some((*id, way.node_ids.clone(), area_tags, coastline))
+                })
+                .collect(),
+        )?;
+    }
+    let changed = |id: i64| change.is_some_and(|c| c.ways.contains_key(&id));
+
+    blob_index.consume_elements(
+        ElementType::Way,
+        |element| match element {
             Element::Way(way) => {
                 let id = way.id();
-                if ways_set.contains(&id) {
-                    return Some((id, way.refs().collect::<Vec<i64>>()));
+                if changed(id) {
+                    return None;
                 }
-                None
+
+                let node_ids = way.refs().collect::<Vec<i64>>();
+                let tags = if extract_config.closed_ways || land {
+                    way.tags().collect::<Vec<_>>()
+                } else {
+                    Vec::new()
+                };
+                let area_tags = if extract_config.closed_ways && is_closed(&node_ids) {
+                    area_tags(&node_ids, &tags, extract_config)
+                } else {
+                    None
+                };
+                let coastline = land && is_coastline(&tags);
+
+                Some((id, node_ids, area_tags, coastline))
             }
             _ => None,
-        })?
-        .into_iter()
-        .collect();
+        },
+        collect_ways,
+    )?;
+
+    Ok((
+        area_ways.into_inner().unwrap(),
+        coastline_ways.into_inner().unwrap(),
+    ))
+}
+
+/// Tags of a closed way that counts as an area, if closed ways are extracted at all.
+fn area_tags(
+    node_ids: &[i64],
+    tags: &[(&str, &str)],
+    extract_config: &TopodexConfig,
+) -> Option<serde_json::Map<String, Value>> {
+    (extract_config.closed_ways && is_closed(node_ids) && tag_filter(tags, extract_config))
+        .then(|| extract_tags(tags, extract_config))
+}
 
-    Ok(ways)
+fn is_coastline(tags: &[(&str, &str)]) -> bool {
+    tags.contains(&("natural", "coastline"))
 }
 
+fn is_closed(node_ids: &[i64]) -> bool {
+    node_ids.len() >= 4 && node_ids.first() == node_ids.last()
+}
+
+/// Whether the change modifies or deletes one of the ways or one of their nodes.
+fn ways_changed(
+    way_ids: impl IntoIterator<Item = i64>,
+    change: &OsmChange,
+    store: &dyn ElementStore,
+) -> Result<bool> {
+    for way_id in way_ids {
+        if change.ways.contains_key(&way_id)
+            || store
+                .way(way_id)?
+                .is_some_and(|node_ids| node_ids.iter().any(|id| change.nodes.contains_key(id)))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Marks the nodes of the stored ways as wanted in the store, a chunk at a time.
+fn insert_wanted_way_nodes(
+    store: &dyn ElementStore,
+    way_ids: impl Iterator<Item = i64>,
+) -> Result<()> {
+    let mut node_ids = Vec::new();
+    for way_id in way_ids {
+        node_ids.extend(store.way(way_id)?.unwrap_or_default());
+        if node_ids.len() >= WANTED_CHUNK {
+            store.insert_wanted(ElementType::Node, std::mem::take(&mut node_ids))?;
+        }
+    }
+    store.insert_wanted(ElementType::Node, node_ids)
+}
+
+/// Marks the ids as wanted in the store, a chunk at a time.
+fn insert_wanted(
+    store: &dyn ElementStore,
+    element_type: ElementType,
+    ids: impl Iterator<Item = i64>,
+) -> Result<()> {
+    let mut ids = ids.peekable();
+    while ids.peek().is_some() {
+        store.insert_wanted(element_type, ids.by_ref().take(WANTED_CHUNK).collect())?;
+    }
+    Ok(())
+}
+
+/// Stores the wanted nodes, whether they are wanted is looked up for all nodes of a blob at
+/// once.
 fn read_nodes(
-    path: &str,
-    nodes_set: &HashSet<i64>,
-) -> Result<HashMap<i64, (f64, f64)>, osmpbf::Error> {
-    let nodes: HashMap<i64, (f64, f64)> = ElementCollectReader::from_path(path)?
-        .elements(|element| match element {
-            Element::Node(node) => {
-                if nodes_set.contains(&node.id()) {
-                    return Some((node.id(), (node.lon(), node.lat())));
-                }
-                None
+    blob_index: &BlobIndex,
+    change: Option<&OsmChange>,
+    store: &dyn ElementStore,
+) -> Result<()> {
+    let insert_wanted_nodes = |nodes: Vec<(i64, (f64, f64))>| -> Result<()> {
+        let ids: Vec<i64> = nodes.iter().map(|(id, _)| *id).collect();
+        let wanted = store.wanted(ElementType::Node, &ids)?;
+        store.insert_nodes(
+            nodes
+                .into_iter()
+                .zip(wanted)
+                .filter_map(|(node, wanted)| wanted.then_some(node))
+                .collect(),
+        )
+    };
+
+    if let Some(change) = change {
+        insert_wanted_nodes(
+            change
+                .nodes
+                .iter()
+                .filter_map(|(id, location)| Some((*id, (*location)?)))
+                .collect(),
+        )?;
+    }
+    let changed = |id: i64| change.is_some_and(|c| c.nodes.contains_key(&id));
+
+    blob_index.consume_elements(
+        ElementType::Node,
+        |element| match element {
+            Element::Node(node) if !changed(node.id()) => {
+                Some((node.id(), (node.lon(), node.lat())))
             }
-            Element::DenseNode(node) => {
-                if nodes_set.contains(&node.id()) {
-                    return Some((node.id(), (node.lon(), node.lat())));
-                }
-                None
+            Element::DenseNode(node) if !changed(node.id()) => {
+                Some((node.id(), (node.lon(), node.lat())))
             }
             _ => None,
-        })?
-        .into_iter()
-        .collect();
-    Ok(nodes)
+        },
+        insert_wanted_nodes,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use RelationMember::{InnerMember, OuterMember};
+
+    fn relation(
+        id: i64,
+        members: Vec<RelationMember>,
+        relation_members: Vec<RelationMember>,
+    ) -> RelationWithMembers {
+        RelationWithMembers {
+            id,
+            members,
+            relation_members,
+            subareas: vec![],
+            tags: serde_json::Map::new(),
+        }
+    }
+
+    fn by_id(relations: Vec<RelationWithMembers>) -> HashMap<i64, RelationWithMembers> {
+        relations
+            .into_iter()
+            .map(|relation| (relation.id, relation))
+            .collect()
+    }
+
+    fn config() -> TopodexConfig {
+        serde_json::from_value(serde_json::json!({
+            "extract_properties": [],
+            "process_property_name": "name",
+        }))
+        .unwrap()
+    }
+
+    fn changed_relation(members: &[(RelMemberType, i64, &str)]) -> Option<ChangedRelation> {
+        Some(ChangedRelation {
+            members: members
+                .iter()
+                .map(|(member_type, id, role)| (member_type.clone(), *id, (*role).to_owned()))
+                .collect(),
+            tags: vec![],
+        })
+    }
+
+    fn members_with_roles(member_roles: MemberRoles) -> RelationWithMembers {
+        relation_with_members(
+            1,
+            [
+                (RelMemberType::Way, 10, "outer"),
+                (RelMemberType::Way, 11, "inner"),
+                (RelMemberType::Way, 12, "exclave"),
+                (RelMemberType::Way, 13, "enclave"),
+                (RelMemberType::Way, 14, ""),
+                (RelMemberType::Way, 15, "label"),
+                (RelMemberType::Relation, 2, "exclave"),
+                (RelMemberType::Relation, 3, "subarea"),
+                (RelMemberType::Node, 4, "admin_centre"),
+            ]
+            .into_iter(),
+            serde_json::Map::new(),
+            member_roles,
+        )
+    }
+
+    #[test]
+    fn strict_roles_keep_only_outer_and_inner_members() {
+        let relation = members_with_roles(MemberRoles::Strict);
+
+        assert_eq!(relation.members, vec![OuterMember(10), InnerMember(11)]);
+        assert!(relation.relation_members.is_empty());
+        assert_eq!(relation.subareas, vec![3]);
+    }
+
+    #[test]
+    fn legacy_roles_map_exclaves_and_enclaves() {
+        let relation = members_with_roles(MemberRoles::Legacy);
+
+        assert_eq!(
+            relation.members,
+            vec![
+                OuterMember(10),
+                InnerMember(11),
+                OuterMember(12),
+                InnerMember(13),
+            ]
+        );
+        assert_eq!(relation.relation_members, vec![OuterMember(2)]);
+    }
+
+    #[test]
+    fn inferred_roles_keep_ways_without_a_role() {
+        let relation = members_with_roles(MemberRoles::Infer);
+
+        assert_eq!(
+            relation.members,
+            vec![
+                OuterMember(10),
+                InnerMember(11),
+                OuterMember(12),
+                InnerMember(13),
+                RelationMember::UnknownMember(14),
+            ]
+        );
+    }
+
+    #[test]
+    fn flattens_nested_relations_and_swaps_inner_roles() {
+        let parent = relation(
+            1,
+            vec![OuterMember(10)],
+            vec![OuterMember(2), InnerMember(3)],
+        );
+        let nested_relations = by_id(vec![
+            relation(2, vec![OuterMember(20), InnerMember(21)], vec![]),
+            relation(
+                3,
+                vec![OuterMember(30), InnerMember(31)],
+                vec![OuterMember(4)],
+            ),
+            relation(4, vec![OuterMember(40)], vec![]),
+        ]);
+
+        let members = flatten_members(&parent, &nested_relations, &mut HashSet::new());
+
+        assert_eq!(
+            members,
+            vec![
+                OuterMember(10),
+                OuterMember(20),
+                InnerMember(21),
+                InnerMember(30),
+                OuterMember(31),
+                InnerMember(40),
+            ]
+        );
+    }
+
+    #[test]
+    fn flattening_stops_at_cycles() {
+        let parent = relation(1, vec![OuterMember(10)], vec![OuterMember(2)]);
+        let nested_relations = by_id(vec![
+            relation(1, vec![OuterMember(10)], vec![OuterMember(2)]),
+            relation(
+                2,
+                vec![OuterMember(20)],
+                vec![OuterMember(1), OuterMember(2), InnerMember(5)],
+            ),
+        ]);
+
+        let members = flatten_members(&parent, &nested_relations, &mut HashSet::new());
+
+        assert_eq!(members, vec![OuterMember(10), OuterMember(20)]);
+    }
+
+    #[test]
+    fn reads_nested_relations_level_by_level() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("empty.osm.pbf");
+        fs::write(&path, []).unwrap();
+        let (_, blob_index) = ElementCollectReader::from_path(&path)
+            .unwrap()
+            .indexed_elements(|_| None::<()>)
+            .unwrap();
+        let change = OsmChange {
+            relations: HashMap::from([
+                (
+                    2,
+                    changed_relation(&[
+                        (RelMemberType::Way, 20, "outer"),
+                        (RelMemberType::Relation, 3, "inner"),
+                    ]),
+                ),
+                (
+                    3,
+                    changed_relation(&[
+                        (RelMemberType::Way, 30, "outer"),
+                        (RelMemberType::Relation, 2, "outer"),
+                        (RelMemberType::Relation, 5, "outer"),
+                    ]),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let relations = vec![relation(1, vec![], vec![OuterMember(2)])];
+
+        let nested_relations =
+            read_nested_relations(&blob_index, &relations, &config(), Some(&change)).unwrap();
+
+        let mut ids: Vec<i64> = nested_relations.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(
+            flatten_members(&relations[0], &nested_relations, &mut HashSet::new()),
+            vec![OuterMember(20), InnerMember(30)]
+        );
+    }
 }
@@ -0,0 +1,119 @@
+//! Times covering country shapes with cells, for the largest countries one by one and for all
+//! of them together. Criterion warms each case up and repeats it until the timings settle.
+//!
+//! Run with `cargo bench -p process`, or `cargo bench -p process -- h3` for the cases of one
+//! cell system. The shapes default to the low resolution countries of Natural Earth in
+//! `benches/data/countries.geojson`, whose borders are too coarse to split cells the way real
+//! extracts do. To time detailed borders, extract the countries of an OSM planet or region file
+//! and point `TOPODEX_BENCH_FEATURES` at the result:
+//!
+//! ```sh
+//! cargo run --release -p cli -- extract --osm-pbf-file planet.osm.pbf \
+//!     --config-path configs/extract_countries.json --features-output-path countries.geojson
+//! TOPODEX_BENCH_FEATURES=$PWD/countries.geojson cargo bench -p process
+//! ```
+
+use std::{cmp::Reverse, env, path::Path, time::Duration};
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use geo::{CoordsIter, Geometry};
+use geojson::Feature;
+use process::{InputFormat, extract_topologies, read_features, repair_features};
+use util::{CellSystemKind, LayerCells, TopodexConfig};
+
+/// How many of the features with the most vertices are timed on their own.
+const LARGEST_FEATURES: usize = 5;
+
+/// Cell system, deepest level and vertex limit of border cells.
+const CASES: [(CellSystemKind, usize, Option<usize>); 5] = [
+    (CellSystemKind::Geohash, 5, None),
+    (CellSystemKind::Geohash, 8, Some(16)),
+    (CellSystemKind::Quadkey, 12, None),
+    (CellSystemKind::S2, 12, Some(16)),
+    (CellSystemKind::H3, 7, Some(16)),
+];
+
+fn fill_polygon(c: &mut Criterion) {
+    let path = env::var("TOPODEX_BENCH_FEATURES").unwrap_or_else(|_| {
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/benches/data/countries.geojson"
+        )
+        .to_owned()
+    });
+    let features = repair_features(
+        read_features(Path::new(&path), Some(InputFormat::GeoJson))
+            .and_then(|features| features.collect())
+            .unwrap_or_else(|error| panic!("Failed to read {}: {:#}", path, error)),
+    );
+    let config: TopodexConfig = serde_json::from_value(serde_json::json!({
+        "extract_properties": [],
+        "process_property_name": "name",
+    }))
+    .unwrap();
+
+    let mut largest: Vec<(usize, &Feature)> = features
+        .iter()
+        .map(|feature| (vertices(feature), feature))
+        .collect();
+    largest.sort_by_key(|(vertices, _)| Reverse(*vertices));
+    largest.truncate(LARGEST_FEATURES);
+    let total_vertices = features.iter().map(vertices).sum::<usize>();
+
+    for (system, max_level, max_cell_vertices) in CASES {
+        let cells = LayerCells { system, max_level };
+        let mut group = c.benchmark_group(match max_cell_vertices {
+            Some(max_vertices) => format!(
+                "{} level {} at most {} vertices",
+                system, max_level, max_vertices
+            ),
+            None => format!("{} level {}", system, max_level),
+        });
+
+        for (feature_vertices, feature) in largest.iter() {
+            let name = feature
+                .property("name")
+                .and_then(|name| name.as_str())
+                .unwrap_or("?");
+            group.throughput(Throughput::Elements(*feature_vertices as u64));
+            group.bench_function(BenchmarkId::new(name, feature_vertices), |b| {
+                b.iter_batched(
+                    || vec![(*feature).clone()],
+                    |features| {
+                        extract_topologies(features, cells, max_cell_vertices, &config).unwrap()
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+
+        group.throughput(Throughput::Elements(total_vertices as u64));
+        group.bench_function(BenchmarkId::new("all", features.len()), |b| {
+            b.iter_batched(
+                || features.clone(),
+                |features| extract_topologies(features, cells, max_cell_vertices, &config).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+        group.finish();
+    }
+}
+
+fn vertices(feature: &Feature) -> usize {
+    feature
+        .geometry
+        .as_ref()
+        .and_then(|geometry| Geometry::<f64>::try_from(geometry.value.clone()).ok())
+        .map_or(0, |geometry| geometry.coords_count())
+}
+
+criterion_group! {
+    name = benches;
+    // Covering all countries takes seconds, fewer but longer samples keep a run within minutes
+    config = Criterion::default()
+        .sample_size(10)
+        .warm_up_time(Duration::from_secs(2))
+        .measurement_time(Duration::from_secs(10));
+    targets = fill_polygon
+}
+criterion_main!(benches);
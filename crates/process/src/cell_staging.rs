@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use rocksdb::{DB, Direction, IteratorMode, MergeOperands, WriteBatch};
+use util::{CellOption, bounded_rocksdb_options};
+
+/// Size in bytes at which a batch of cells is written out, which bounds the cells held in memory
+/// by each worker.
+pub const BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// Scratch RocksDB collecting the values of all cells while features are filled, so that the
+/// cells never have to be held in memory together. The options a cell gets from several features
+/// are merged by RocksDB. Keys are prefixed by the level of the cell, which keeps the cells of a
+/// level together and in order, siblings next to each other. The database is removed again when
+/// the staging is dropped.
+pub struct CellStaging {
+    db: Option<DB>,
+    path: PathBuf,
+}
+
+impl CellStaging {
+    pub fn open<P: AsRef<Path>>(path: P, memory_budget: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            bail!("Cell staging path {} already exists", path.display());
+        }
+
+        let mut options = bounded_rocksdb_options(memory_budget);
+        options.set_merge_operator_associative("concat_cell_options", concat_cell_options);
+        let db = DB::open(&options, &path)?;
+
+        Ok(CellStaging { db: Some(db), path })
+    }
+
+    fn db(&self) -> &DB {
+        self.db.as_ref().unwrap()
+    }
+
+    pub fn write(&self, batch: CellBatch) -> Result<()> {
+        if !batch.0.is_empty() {
+            self.db().write_without_wal(batch.0)?;
+        }
+        Ok(())
+    }
+
+    /// The cells of `level` with their options, in key order.
+    pub fn level(&self, level: usize) -> impl Iterator<Item = Result<(String, Vec<CellOption>)>> {
+        let prefix = [level as u8];
+        self.db()
+            .iterator(IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(move |entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |(key, _)| key.first() == Some(&prefix[0]))
+            })
+            .map(|entry| {
+                let (key, value) = entry?;
+                decode_cell(&key, &value)
+            })
+    }
+
+    /// All cells with their options, level by level.
+    pub fn cells(&self) -> impl Iterator<Item = Result<(String, Vec<CellOption>)>> {
+        self.db().iterator(IteratorMode::Start).map(|entry| {
+            let (key, value) = entry?;
+            decode_cell(&key, &value)
+        })
+    }
+}
+
+impl Drop for CellStaging {
+    fn drop(&mut self) {
+        self.db = None;
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Changes to the staged cells, written together by `CellStaging::write`.
+#[derive(Default)]
+pub struct CellBatch(WriteBatch);
+
+impl CellBatch {
+    /// Adds an option to those the cell already has.
+    pub fn add(&mut self, cell: &str, level: usize, option: &CellOption) {
+        self.0.merge(cell_key(cell, level), encode_option(option));
+    }
+
+    /// Replaces all options of the cell.
+    pub fn replace(&mut self, cell: &str, level: usize, options: &[CellOption]) {
+        let value: Vec<u8> = options.iter().flat_map(encode_option).collect();
+        self.0.put(cell_key(cell, level), value);
+    }
+
+    pub fn remove(&mut self, cell: &str, level: usize) {
+        self.0.delete(cell_key(cell, level));
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.0.size_in_bytes()
+    }
+}
+
+fn cell_key(cell: &str, level: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(cell.len() + 1);
+    key.push(level as u8);
+    key.extend_from_slice(cell.as_bytes());
+    key
+}
+
+/// The options of a cell are stored one after the other, each prefixed by its length, so
+/// merging them is appending.
+fn encode_option(option: &CellOption) -> Vec<u8> {
+    let encoded = bitcode::serialize(option).unwrap();
+    let mut bytes = Vec::with_capacity(encoded.len() + 4);
+    bytes.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&encoded);
+    bytes
+}
+
+fn decode_cell(key: &[u8], value: &[u8]) -> Result<(String, Vec<CellOption>)> {
+    let cell = String::from_utf8(key.get(1..).unwrap_or_default().to_vec())?;
+    let mut options = Vec::new();
+    let mut rest = value;
+    while !rest.is_empty() {
+        let length = rest
+            .get(..4)
+            .map(|length| u32::from_le_bytes(length.try_into().unwrap()) as usize)
+            .with_context(|| format!("Corrupt options of staged cell {}", cell))?;
+        let option = rest
+            .get(4..4 + length)
+            .with_context(|| format!("Corrupt options of staged cell {}", cell))?;
+        options.push(bitcode::deserialize(option)?);
+        rest = &rest[4 + length..];
+    }
+    Ok((cell, options))
+}
+
+fn concat_cell_options(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut merged = existing.map(<[u8]>::to_vec).unwrap_or_default();
+    for operand in operands {
+        merged.extend_from_slice(operand);
+    }
+    Some(merged)
+}
@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use geo::{Area, BooleanOps, CoordsIter, Euclidean, Length, MultiPolygon};
+use log::info;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use util::{CellOption, CellSystem, CompactShape, FeatureProperties};
+
+use crate::cell_staging::{BATCH_BYTES, CellBatch, CellStaging};
+use crate::priority_order;
+
+/// Precision of the compact shapes, see `CompactShape`.
+const SHAPE_PRECISION: f64 = 1e-7;
+
+/// Merges cells holding the same process value into their parents, level by level from the
+/// deepest cells up. Cells hold feature ids, features are compared by the process value in
+/// their `properties` so that the cells of several features carrying one value merge as well,
+/// keeping the id of the feature that comes first by priority. A cell whose values are all the
+/// same and that is covered by their shapes together is stored as that value directly, and
+/// siblings that all store the same value directly are replaced by their parent. Siblings
+/// holding parts of the same value are replaced by their parent holding the union of their
+/// shapes, as long as it has at most `max_cell_vertices` vertices, or without it no more than
+/// the most intricate of the siblings. Lookups resolve the same values afterwards, from fewer
+/// and coarser keys.
+///
+/// The staged cells are streamed level by level, a group of siblings at a time, so only the
+/// groups of one batch are held in memory.
+pub fn compact_cells(
+    staging: &CellStaging,
+    system: &dyn CellSystem,
+    max_level: usize,
+    max_cell_vertices: Option<usize>,
+    properties: &HashMap<String, FeatureProperties>,
+) -> Result<()> {
+    let root_level = match system.roots().first() {
+        Some(root) => system.level(root)?,
+        None => return Ok(()),
+    };
+    let compaction = Compaction {
+        system,
+        max_cell_vertices,
+        properties,
+    };
+    let mut merged = 0;
+
+    for level in (root_level..=max_level).rev() {
+        let mut groups = Vec::<Vec<(String, Vec<CellOption>)>>::new();
+        let mut groups_bytes = 0;
+        let mut group_parent = None;
+        for cell in staging.level(level) {
+            let (hash, options) = cell?;
+            let parent = system.parent(&hash);
+            if groups.is_empty() || parent.is_none() || parent != group_parent {
+                if groups_bytes >= BATCH_BYTES {
+                    merged +=
+                        compaction.compact_groups(staging, std::mem::take(&mut groups), level)?;
+                    groups_bytes = 0;
+                }
+                groups.push(Vec::new());
+                group_parent = parent;
+            }
+            groups_bytes += options_bytes(&options);
+            groups.last_mut().unwrap().push((hash, options));
+        }
+        merged += compaction.compact_groups(staging, groups, level)?;
+    }
+
+    info!("Compacted cells by merging {} groups of siblings", merged);
+    Ok(())
+}
+
+enum Siblings {
+    /// The children of `parent` are replaced by it, storing their value directly or with the
+    /// union of their shapes
+    Merged {
+        parent: String,
+        option: CellOption,
+        children: Vec<String>,
+    },
+    /// Cells now stored as a value directly as they are covered by it
+    Covered(Vec<(String, String)>),
+}
+
+struct Compaction<'a> {
+    system: &'a dyn CellSystem,
+    max_cell_vertices: Option<usize>,
+    properties: &'a HashMap<String, FeatureProperties>,
+}
+
+impl Compaction<'_> {
+    /// Compacts groups of siblings of `level` and stages the result, returns how many groups
+    /// were replaced by their parent.
+    fn compact_groups(
+        &self,
+        staging: &CellStaging,
+        groups: Vec<Vec<(String, Vec<CellOption>)>>,
+        level: usize,
+    ) -> Result<usize> {
+        let compacted = groups
+            .into_par_iter()
+            .map(|siblings| self.compact_siblings(siblings))
+            .collect::<Result<Vec<Siblings>>>()?;
+
+        let mut merged = 0;
+        let mut batch = CellBatch::default();
+        for siblings in compacted {
+            match siblings {
+                Siblings::Merged {
+                    parent,
+                    option,
+                    children,
+                } => {
+                    for child in children {
+                        batch.remove(&child, level);
+                    }
+                    batch.add(&parent, level - 1, &option);
+                    merged += 1;
+                }
+                Siblings::Covered(covered) => {
+                    for (hash, value) in covered {
+                        batch.replace(&hash, level, &[CellOption { value, shape: None }]);
+                    }
+                }
+            }
+        }
+        staging.write(batch)?;
+        Ok(merged)
+    }
+
+    /// Stores covered cells of a group of siblings directly and merges the siblings into their
+    /// parent if they all store the same value, directly or as parts of it.
+    fn compact_siblings(&self, siblings: Vec<(String, Vec<CellOption>)>) -> Result<Siblings> {
+        let mut covered = Vec::new();
+        let mut values = Vec::with_capacity(siblings.len());
+        for (hash, options) in siblings.iter() {
+            match self.covering_value(hash, options)? {
+                Some(value) => {
+                    values.push(Some(value.clone()));
+                    covered.push((hash.clone(), value));
+                }
+                None => values.push(match options.as_slice() {
+                    [CellOption { value, shape: None }] => Some(value.clone()),
+                    _ => None,
+                }),
+            }
+        }
+
+        let Some(parent) = self.system.parent(&siblings[0].0) else {
+            return Ok(Siblings::Covered(covered));
+        };
+        let children = |siblings: Vec<(String, Vec<CellOption>)>| {
+            siblings.into_iter().map(|(hash, _)| hash).collect()
+        };
+
+        // All children store the value directly, checked before building any shapes
+        if let Some(values) = values
+            .iter()
+            .map(Option::as_deref)
+            .collect::<Option<Vec<_>>>()
+            && let Some(value) = self.shared_value(values)
+            && self.system.children(&parent)?.len() == siblings.len()
+        {
+            return Ok(Siblings::Merged {
+                parent,
+                option: CellOption { value, shape: None },
+                children: children(siblings),
+            });
+        }
+
+        match self.merged_option(&parent, &siblings)? {
+            Some(option) => Ok(Siblings::Merged {
+                parent,
+                option,
+                children: children(siblings),
+            }),
+            None => Ok(Siblings::Covered(covered)),
+        }
+    }
+
+    /// The option of `parent` replacing its children if all their options share one value, the
+    /// union of their shapes encoded against the origin of the parent. The union may have at
+    /// most `max_cell_vertices` vertices, or without it as many as the most intricate child, so
+    /// lookups never test a larger shape than before.
+    fn merged_option(
+        &self,
+        parent: &str,
+        siblings: &[(String, Vec<CellOption>)],
+    ) -> Result<Option<CellOption>> {
+        let ids = siblings
+            .iter()
+            .flat_map(|(_, options)| options)
+            .map(|option| option.value.as_str());
+        let Some(value) = self.shared_value(ids) else {
+            return Ok(None);
+        };
+
+        let mut union = MultiPolygon(vec![]);
+        let mut child_vertices = 0;
+        for (hash, options) in siblings {
+            let shape = match cell_shape(hash, options, self.system)? {
+                Some(shape) => shape,
+                None => return Ok(None),
+            };
+            child_vertices = child_vertices.max(shape.coords_count());
+            union = union.union(&shape);
+        }
+
+        if covers_cell(&self.system.shape(parent)?, &union) {
+            return Ok(Some(CellOption { value, shape: None }));
+        }
+        if union.coords_count() > self.max_cell_vertices.unwrap_or(child_vertices) {
+            return Ok(None);
+        }
+        Ok(Some(CellOption {
+            value,
+            shape: Some(CompactShape::encode(&union, self.system.origin(parent)?)),
+        }))
+    }
+
+    /// The value of a cell if all its options share it and cover the cell together.
+    fn covering_value(&self, hash: &str, options: &[CellOption]) -> Result<Option<String>> {
+        let Some(value) = self.shared_value(options.iter().map(|option| option.value.as_str()))
+        else {
+            return Ok(None);
+        };
+        if options.iter().any(|option| option.shape.is_none()) {
+            return Ok((options.len() > 1).then_some(value));
+        }
+
+        let origin = self.system.origin(hash)?;
+        let covered = options
+            .iter()
+            .filter_map(|option| option.shape.as_ref()?.decode(origin))
+            .reduce(|covered, shape| covered.union(&shape))
+            .unwrap_or_else(|| MultiPolygon(vec![]));
+
+        Ok(covers_cell(&self.system.shape(hash)?, &covered).then_some(value))
+    }
+
+    /// The feature standing for all features of `ids` if they carry the same process value, the
+    /// one that comes first by priority. Features without properties only share a value with
+    /// themselves.
+    fn shared_value<'v>(&self, ids: impl IntoIterator<Item = &'v str>) -> Option<String> {
+        let process_value = |id: &'v str| {
+            self.properties
+                .get(id)
+                .and_then(|feature| feature.value.as_deref())
+                .ok_or(id)
+        };
+
+        let mut ids = ids.into_iter();
+        let mut first = ids.next()?;
+        let value = process_value(first);
+        for id in ids {
+            if process_value(id) != value {
+                return None;
+            }
+            if priority_order(id, first, self.properties).is_lt() {
+                first = id;
+            }
+        }
+        Some(first.to_owned())
+    }
+}
+
+/// The part of a cell covered by its options, `None` if a shape is corrupt.
+fn cell_shape(
+    hash: &str,
+    options: &[CellOption],
+    system: &dyn CellSystem,
+) -> Result<Option<MultiPolygon>> {
+    if options.iter().any(|option| option.shape.is_none()) {
+        return Ok(Some(system.shape(hash)?));
+    }
+
+    let origin = system.origin(hash)?;
+    let mut covered = MultiPolygon(vec![]);
+    for option in options {
+        match option.shape.as_ref().and_then(|shape| shape.decode(origin)) {
+            Some(shape) => covered = covered.union(&shape),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(covered))
+}
+
+fn options_bytes(options: &[CellOption]) -> usize {
+    options
+        .iter()
+        .map(|option| option.value.len() + option.shape.as_ref().map_or(0, |shape| shape.len()))
+        .sum()
+}
+
+/// Whether `covered` covers all of the shape of a cell. It may reach beyond the cell, as the
+/// shapes of the children of an H3 cell do. Uncovered slivers narrower than the precision of the
+/// shapes are ignored.
+pub fn covers_cell(cell: &MultiPolygon, covered: &MultiPolygon) -> bool {
+    let perimeter: f64 = cell
+        .iter()
+        .map(|polygon| polygon.exterior().length::<Euclidean>())
+        .sum();
+    cell.difference(covered).unsigned_area() <= perimeter * SHAPE_PRECISION
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{BoundingRect, Rect};
+    use serde_json::json;
+    use util::GeohashCells;
+
+    use super::*;
+
+    /// Two features carrying the value `DE` and one carrying `FR`, cells hold their ids
+    fn features() -> HashMap<String, FeatureProperties> {
+        [("de-1", "DE"), ("de-2", "DE"), ("fr", "FR")]
+            .into_iter()
+            .map(|(id, value)| {
+                let feature = FeatureProperties {
+                    id: Some(id.to_owned()),
+                    value: Some(value.to_owned()),
+                    properties: json!({ "value": value }).as_object().unwrap().clone(),
+                    priority: None,
+                };
+                (id.to_owned(), feature)
+            })
+            .collect()
+    }
+
+    fn compact(
+        siblings: Vec<(String, Vec<CellOption>)>,
+        max_cell_vertices: Option<usize>,
+    ) -> Siblings {
+        let properties = features();
+        let compaction = Compaction {
+            system: &GeohashCells,
+            max_cell_vertices,
+            properties: &properties,
+        };
+        compaction.compact_siblings(siblings).unwrap()
+    }
+
+    fn direct(hash: &str, id: &str) -> (String, Vec<CellOption>) {
+        let option = CellOption {
+            value: id.to_owned(),
+            shape: None,
+        };
+        (hash.to_owned(), vec![option])
+    }
+
+    /// The lower left quarter of a cell
+    fn quarter(hash: &str) -> MultiPolygon {
+        let cell = GeohashCells.shape(hash).unwrap().bounding_rect().unwrap();
+        let min = cell.min();
+        let max = min + (cell.max() - min) / 2.0;
+        MultiPolygon(vec![Rect::new(min, max).to_polygon()])
+    }
+
+    fn undecided(hash: &str, id: &str, shape: &MultiPolygon) -> (String, Vec<CellOption>) {
+        let origin = GeohashCells.origin(hash).unwrap();
+        let option = CellOption {
+            value: id.to_owned(),
+            shape: Some(CompactShape::encode(shape, origin)),
+        };
+        (hash.to_owned(), vec![option])
+    }
+
+    fn merged(siblings: Siblings) -> (String, CellOption, Vec<String>) {
+        match siblings {
+            Siblings::Merged {
+                parent,
+                option,
+                children,
+            } => (parent, option, children),
+            Siblings::Covered(_) => panic!("siblings not merged"),
+        }
+    }
+
+    #[test]
+    fn siblings_storing_one_feature_directly_merge() {
+        let children = GeohashCells.children("u").unwrap();
+        let siblings = children.iter().map(|hash| direct(hash, "fr")).collect();
+
+        let (parent, option, merged_children) = merged(compact(siblings, None));
+        assert_eq!(parent, "u");
+        assert_eq!(option.value, "fr");
+        assert_eq!(option.shape, None);
+        assert_eq!(merged_children, children);
+    }
+
+    #[test]
+    fn siblings_of_features_sharing_a_value_merge() {
+        let children = GeohashCells.children("u").unwrap();
+        let (west, east) = children.split_at(16);
+        let siblings = east
+            .iter()
+            .map(|hash| direct(hash, "de-2"))
+            .chain(west.iter().map(|hash| direct(hash, "de-1")))
+            .collect();
+
+        // The parent keeps the feature that comes first, by id as neither has a rank
+        let (parent, option, _) = merged(compact(siblings, None));
+        assert_eq!(parent, "u");
+        assert_eq!(option.value, "de-1");
+        assert_eq!(option.shape, None);
+    }
+
+    #[test]
+    fn siblings_of_different_values_stay() {
+        let children = GeohashCells.children("u").unwrap();
+        let siblings = children
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| direct(hash, if i == 0 { "fr" } else { "de-1" }))
+            .collect();
+
+        let compacted = compact(siblings, None);
+        assert!(matches!(compacted, Siblings::Covered(covered) if covered.is_empty()));
+    }
+
+    #[test]
+    fn direct_siblings_missing_a_child_stay() {
+        // Opposite corners of their parent, the union has more vertices than either
+        let siblings = vec![direct("u0", "fr"), direct("uz", "fr")];
+
+        let compacted = compact(siblings, None);
+        assert!(matches!(compacted, Siblings::Covered(covered) if covered.is_empty()));
+    }
+
+    #[test]
+    fn undecided_siblings_merge_into_a_parent_shape() {
+        let parts = [quarter("u0"), quarter("u1")];
+        let siblings = vec![
+            undecided("u0", "de-2", &parts[0]),
+            direct("u2", "de-1"),
+            undecided("u1", "de-2", &parts[1]),
+        ];
+
+        let (parent, option, children) = merged(compact(siblings, Some(32)));
+        assert_eq!(parent, "u");
+        assert_eq!(option.value, "de-1");
+        assert_eq!(children, ["u0", "u2", "u1"]);
+
+        let shape = option
+            .shape
+            .unwrap()
+            .decode(GeohashCells.origin("u").unwrap())
+            .unwrap();
+        let expected = parts[0]
+            .union(&parts[1])
+            .union(&GeohashCells.shape("u2").unwrap());
+        let difference = shape.xor(&expected).unsigned_area();
+        assert!(difference < 1e-9 * expected.unsigned_area());
+    }
+
+    #[test]
+    fn undecided_siblings_covering_the_parent_merge_directly() {
+        let children = GeohashCells.children("u").unwrap();
+        let mut siblings: Vec<_> = children[1..]
+            .iter()
+            .map(|hash| direct(hash, "fr"))
+            .collect();
+        let shape = GeohashCells.shape(&children[0]).unwrap();
+        siblings.push(undecided(&children[0], "fr", &shape));
+
+        let (parent, option, _) = merged(compact(siblings, None));
+        assert_eq!(parent, "u");
+        assert_eq!(option.shape, None);
+    }
+
+    #[test]
+    fn undecided_siblings_of_different_values_stay() {
+        let siblings = vec![
+            undecided("u0", "de-1", &quarter("u0")),
+            undecided("u1", "fr", &quarter("u1")),
+        ];
+
+        let compacted = compact(siblings, Some(32));
+        assert!(matches!(compacted, Siblings::Covered(covered) if covered.is_empty()));
+    }
+
+    #[test]
+    fn merged_shapes_stay_within_the_vertex_budget() {
+        let siblings = || {
+            vec![
+                undecided("u0", "fr", &quarter("u0")),
+                undecided("u1", "fr", &quarter("u1")),
+            ]
+        };
+
+        // Two quarters take more vertices than any single child
+        assert!(matches!(compact(siblings(), None), Siblings::Covered(_)));
+        assert!(matches!(compact(siblings(), Some(9)), Siblings::Covered(_)));
+        assert!(matches!(
+            compact(siblings(), Some(10)),
+            Siblings::Merged { .. }
+        ));
+    }
+
+    #[test]
+    fn staged_cells_of_adjacent_features_sharing_a_value_collapse() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = CellStaging::open(dir.path().join("staging"), 64 * 1024 * 1024).unwrap();
+
+        // Two features split `u0` between them, a third one covers `u1`
+        let mut batch = CellBatch::default();
+        let children = GeohashCells.children("u0").unwrap();
+        for (i, hash) in children.iter().enumerate() {
+            let (_, options) = direct(hash, if i < 16 { "de-1" } else { "de-2" });
+            batch.add(hash, 3, &options[0]);
+        }
+        for hash in GeohashCells.children("u1").unwrap() {
+            batch.add(&hash, 3, &direct(&hash, "fr").1[0]);
+        }
+        staging.write(batch).unwrap();
+
+        compact_cells(&staging, &GeohashCells, 3, None, &features()).unwrap();
+        let cells: Vec<(String, Vec<String>)> = staging
+            .cells()
+            .map(|cell| {
+                let (hash, options) = cell.unwrap();
+                assert!(options.iter().all(|option| option.shape.is_none()));
+                (
+                    hash,
+                    options.into_iter().map(|option| option.value).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            cells,
+            [
+                ("u0".to_owned(), vec!["de-1".to_owned()]),
+                ("u1".to_owned(), vec!["fr".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn covering_shapes_may_reach_beyond_the_cell() {
+        let square = |min_x: f64, min_y: f64, size: f64| {
+            let rect = Rect::new((min_x, min_y), (min_x + size, min_y + size));
+            MultiPolygon(vec![rect.to_polygon()])
+        };
+        let cell = square(0.0, 0.0, 1.0);
+
+        assert!(covers_cell(&cell, &cell));
+        assert!(covers_cell(&cell, &square(-0.5, -0.5, 2.0)));
+        // As large as the cell but shifted, half of the cell is left uncovered
+        assert!(!covers_cell(&cell, &square(0.5, 0.0, 1.0)));
+        assert!(!covers_cell(&cell, &square(0.0, 0.0, 0.9)));
+    }
+}
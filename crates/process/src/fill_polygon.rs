@@ -1,56 +1,68 @@
+use std::sync::Arc;
+
 use anyhow::Result;
-use geo::{BooleanOps, Contains, Intersects, MultiPolygon};
-use geohash::decode_bbox;
+use geo::{
+    BooleanOps, BoundingRect, Coord, CoordsIter, Intersects, Line, LinesIter, MultiPolygon, Point,
+    Rect,
+};
 use log::info;
-use util::{GeohashIndex, ShouldCheck};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rstar::{AABB, RTree};
+use util::{CellSystem, GeohashIndex};
+
+use crate::compaction::covers_cell;
 
+/// Covers a polygon with cells. Cells inside the polygon store its value directly, cells crossed
+/// by its border are split into their children. Without `max_cell_vertices` the border cells are
+/// split down to `max_level`. With it a border cell is kept as soon as the part of the polygon
+/// inside it has at most that many vertices, whatever its level, so intricate borders are split
+/// beyond `max_level` down to the deepest level of the cell system. Border cells store the part
+/// of the polygon inside them.
+///
+/// Only border cells are clipped. The children of a border cell share the part of the polygon
+/// inside it, and cells away from its edges are located without clipping them.
+///
+/// The cells of a level are checked in parallel on the rayon pool, so that a single huge polygon
+/// keeps all threads busy. They are still returned level by level in the order they were split
+/// in, the same as when checking them one after the other.
 pub fn fill_polygon(
     geo_polygon: MultiPolygon,
     polygon_value: String,
-    max_geohash_level: usize,
+    cells: &dyn CellSystem,
+    max_level: usize,
+    max_cell_vertices: Option<usize>,
 ) -> Result<Vec<GeohashIndex>> {
     let mut geohashes = Vec::<GeohashIndex>::new();
-    let geohash_possibilities: Vec<String> = "0123456789bcdefghjkmnpqrstuvwxyz"
-        .chars()
-        .map(|c| c.to_string())
-        .collect();
 
-    let mut geohashes_to_check: Vec<ShouldCheck> = geohash_possibilities
-        .iter()
-        .map(|c| ShouldCheck {
-            hash: c.to_string(),
-            area: geo_polygon.clone(),
-        })
+    let area = Arc::new(PreparedArea::new(geo_polygon));
+    let mut geohashes_to_check: Vec<(String, Arc<PreparedArea>)> = cells
+        .roots()
+        .into_iter()
+        .map(|hash| (hash, area.clone()))
         .collect();
 
     let start = std::time::Instant::now();
-    for geohash_level in 1..=max_geohash_level {
-        let mut next_geohashes_check = Vec::<ShouldCheck>::new();
-
-        for check in geohashes_to_check {
-            let rect = decode_bbox(&check.hash)?;
-            let mp = MultiPolygon(vec![rect.to_polygon()]);
-            if check.area.contains(&rect) {
-                geohashes.push(GeohashIndex::DirectValue {
-                    hash: check.hash.clone(),
-                    value: polygon_value.clone(),
-                })
-            } else if check.area.intersects(&rect) && geohash_level < max_geohash_level {
-                let intersecting_polygon = check.area.intersection(&mp);
-                let options_to_check: Vec<ShouldCheck> = geohash_possibilities
-                    .iter()
-                    .map(|h| ShouldCheck {
-                        hash: format!("{}{}", check.hash, h),
-                        area: intersecting_polygon.clone(),
-                    })
-                    .collect();
-                next_geohashes_check.extend(options_to_check);
-            } else if check.area.intersects(&rect) && geohash_level == max_geohash_level {
-                geohashes.push(GeohashIndex::PartialValue {
-                    hash: check.hash,
-                    value: polygon_value.clone(),
-                    shape: check.area.intersection(&mp),
-                });
+    while !geohashes_to_check.is_empty() {
+        let checked = geohashes_to_check
+            .into_par_iter()
+            .map(|(hash, area)| {
+                check_cell(
+                    hash,
+                    &area,
+                    &polygon_value,
+                    cells,
+                    max_level,
+                    max_cell_vertices,
+                )
+            })
+            .collect::<Result<Vec<Checked>>>()?;
+
+        let mut next_geohashes_check = Vec::<(String, Arc<PreparedArea>)>::new();
+        for check in checked {
+            match check {
+                Checked::Outside => {}
+                Checked::Stored(geohash_index) => geohashes.push(geohash_index),
+                Checked::Split(children) => next_geohashes_check.extend(children),
             }
         }
 
@@ -64,3 +76,346 @@ pub fn fill_polygon(
     );
     Ok(geohashes)
 }
+
+/// Outcome of checking a cell against the part of the polygon left to cover in it.
+enum Checked {
+    Outside,
+    /// Cell storing the value of the polygon, directly or with the part of it inside the cell
+    Stored(GeohashIndex),
+    /// Children of a border cell, to be checked against the part of the polygon inside it
+    Split(Vec<(String, Arc<PreparedArea>)>),
+}
+
+fn check_cell(
+    hash: String,
+    area: &PreparedArea,
+    polygon_value: &str,
+    cells: &dyn CellSystem,
+    max_level: usize,
+    max_cell_vertices: Option<usize>,
+) -> Result<Checked> {
+    let shape = cells.shape(&hash)?;
+    let Some(cell_bbox) = shape.bounding_rect() else {
+        return Ok(Checked::Outside);
+    };
+
+    Ok(match area.locate(cell_bbox) {
+        Location::Outside => Checked::Outside,
+        Location::Inside => Checked::Stored(GeohashIndex::DirectValue {
+            hash,
+            value: polygon_value.to_owned(),
+        }),
+        Location::Border => {
+            let intersecting_polygon = area.shape.intersection(&shape);
+            // A cell crossed by no more than a corner of the border can't get any simpler, its
+            // children would only split the corner up
+            let simple_enough = max_cell_vertices.is_some_and(|max_vertices| {
+                intersecting_polygon.coords_count() <= max_vertices.max(shape.coords_count() + 2)
+            });
+            let split_level = match max_cell_vertices {
+                Some(_) => cells.max_level(),
+                None => max_level,
+            };
+
+            if intersecting_polygon.0.is_empty() {
+                // Touches the cell only along its border
+                Checked::Outside
+            } else if covers_cell(&shape, &intersecting_polygon) {
+                Checked::Stored(GeohashIndex::DirectValue {
+                    hash,
+                    value: polygon_value.to_owned(),
+                })
+            } else if cells.level(&hash)? < split_level && !simple_enough {
+                let child_area = Arc::new(PreparedArea::new(intersecting_polygon));
+                Checked::Split(
+                    cells
+                        .children(&hash)?
+                        .into_iter()
+                        .map(|child| (child, child_area.clone()))
+                        .collect(),
+                )
+            } else {
+                Checked::Stored(GeohashIndex::PartialValue {
+                    hash,
+                    value: polygon_value.to_owned(),
+                    shape: intersecting_polygon,
+                })
+            }
+        }
+    })
+}
+
+/// Where a cell lies relative to an area.
+enum Location {
+    Outside,
+    Inside,
+    /// Edges of the area come close to the cell, it has to be clipped to tell
+    Border,
+}
+
+/// Part of a polygon left to cover, prepared to locate many cells against it: its bounding box
+/// and an R-tree of its edges.
+struct PreparedArea {
+    shape: MultiPolygon,
+    bbox: Option<Rect>,
+    edges: RTree<Line>,
+}
+
+impl PreparedArea {
+    fn new(shape: MultiPolygon) -> Self {
+        PreparedArea {
+            bbox: shape.bounding_rect(),
+            edges: RTree::bulk_load(shape.lines_iter().collect()),
+            shape,
+        }
+    }
+
+    /// Locates the cell with the bounding box `cell`. Without an edge of the area crossing the
+    /// bounding box, all of it and so all of the cell is either inside or outside of the area.
+    fn locate(&self, cell: Rect) -> Location {
+        let Some(bbox) = self.bbox else {
+            return Location::Outside;
+        };
+        if !bbox.intersects(&cell) {
+            return Location::Outside;
+        }
+
+        let envelope = AABB::from_corners(cell.min().into(), cell.max().into());
+        if self
+            .edges
+            .locate_in_envelope_intersecting(&envelope)
+            .any(|edge| edge.intersects(&cell))
+        {
+            return Location::Border;
+        }
+
+        if self.contains(cell.center(), bbox.max().x) {
+            Location::Inside
+        } else {
+            Location::Outside
+        }
+    }
+
+    /// Whether `point`, which lies on no edge, is inside the area. Counts the edges crossed by a
+    /// ray from it to the east edge `max_x` of the area.
+    fn contains(&self, point: Coord, max_x: f64) -> bool {
+        let ray = AABB::from_corners(point.into(), Point::new(max_x, point.y));
+        let crossings = self
+            .edges
+            .locate_in_envelope_intersecting(&ray)
+            .filter(|edge| {
+                let (a, b) = (edge.start, edge.end);
+                (a.y > point.y) != (b.y > point.y)
+                    && a.x + (point.y - a.y) * (b.x - a.x) / (b.y - a.y) > point.x
+            })
+            .count();
+        crossings % 2 == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{Area, Polygon, polygon};
+    use util::GeohashCells;
+
+    use super::*;
+
+    /// Diamond with its left and right corners on the line y = 52.1.
+    fn diamond() -> MultiPolygon {
+        MultiPolygon(vec![polygon![
+            (x: 13.1, y: 52.0),
+            (x: 13.2, y: 52.1),
+            (x: 13.1, y: 52.2),
+            (x: 13.0, y: 52.1),
+        ]])
+    }
+
+    /// Square with a square hole in its middle.
+    fn square_with_hole() -> MultiPolygon {
+        MultiPolygon(vec![polygon!(
+            exterior: [(x: 13.0, y: 52.0), (x: 13.4, y: 52.0), (x: 13.4, y: 52.4), (x: 13.0, y: 52.4)],
+            interiors: [[(x: 13.1, y: 52.1), (x: 13.1, y: 52.3), (x: 13.3, y: 52.3), (x: 13.3, y: 52.1)]],
+        )])
+    }
+
+    fn rect(min: (f64, f64), max: (f64, f64)) -> Rect {
+        Rect::new(min, max)
+    }
+
+    fn contains(area: &PreparedArea, x: f64, y: f64) -> bool {
+        area.contains(Coord { x, y }, area.bbox.unwrap().max().x)
+    }
+
+    /// Area covered by the cells, whole cells for direct values and the stored shape otherwise.
+    /// Matches the area of the polygon up to the rounding of the clipping.
+    fn covered_area(cells: &dyn CellSystem, indexes: &[GeohashIndex]) -> f64 {
+        indexes
+            .iter()
+            .map(|index| match index {
+                GeohashIndex::DirectValue { hash, .. } => {
+                    cells.shape(hash).unwrap().unsigned_area()
+                }
+                GeohashIndex::PartialValue { shape, .. } => shape.unsigned_area(),
+            })
+            .sum()
+    }
+
+    fn partial_cells(indexes: &[GeohashIndex]) -> Vec<(&str, &MultiPolygon)> {
+        indexes
+            .iter()
+            .filter_map(|index| match index {
+                GeohashIndex::PartialValue { hash, shape, .. } => Some((hash.as_str(), shape)),
+                GeohashIndex::DirectValue { .. } => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn locates_cells() {
+        let area = PreparedArea::new(square_with_hole());
+        let locate = |min, max| area.locate(rect(min, max));
+
+        assert!(matches!(
+            locate((13.01, 52.01), (13.05, 52.05)),
+            Location::Inside
+        ));
+        assert!(matches!(
+            locate((13.15, 52.15), (13.25, 52.25)),
+            Location::Outside
+        ));
+        assert!(matches!(
+            locate((14.0, 52.0), (14.1, 52.1)),
+            Location::Outside
+        ));
+        assert!(matches!(
+            locate((13.05, 52.05), (13.15, 52.15)),
+            Location::Border
+        ));
+        assert!(matches!(
+            locate((13.35, 52.35), (13.45, 52.45)),
+            Location::Border
+        ));
+    }
+
+    #[test]
+    fn rays_through_vertices_count_once() {
+        let area = PreparedArea::new(diamond());
+        // The ray passes the right corner, whose edges lie on both sides of it
+        assert!(contains(&area, 13.05, 52.1));
+        assert!(contains(&area, 13.15, 52.1));
+        // The ray passes both corners from outside
+        assert!(!contains(&area, 12.9, 52.1));
+        // The ray only touches the top or bottom corner, both edges lie on one side of it
+        assert!(!contains(&area, 13.05, 52.2));
+        assert!(!contains(&area, 13.05, 52.0));
+    }
+
+    #[test]
+    fn diamond_cells_through_its_corners_are_located() {
+        // Cells whose center rays run through the left and right corners
+        let area = PreparedArea::new(diamond());
+        assert!(matches!(
+            area.locate(rect((13.09, 52.09), (13.11, 52.11))),
+            Location::Inside
+        ));
+        assert!(matches!(
+            area.locate(rect((12.97, 52.09), (12.99, 52.11))),
+            Location::Outside
+        ));
+    }
+
+    #[test]
+    fn border_cells_reach_the_deepest_level_without_max_cell_vertices() {
+        let cells = &GeohashCells;
+        let indexes = fill_polygon(diamond(), "1".to_owned(), cells, 5, None).unwrap();
+
+        let partial = partial_cells(&indexes);
+        assert!(!partial.is_empty());
+        assert!(partial.iter().all(|(hash, _)| hash.len() == 5));
+        let area = diamond().unsigned_area();
+        assert!((covered_area(cells, &indexes) - area).abs() < area * 1e-6);
+    }
+
+    #[test]
+    fn max_cell_vertices_stops_splitting_simple_border_cells() {
+        let cells = &GeohashCells;
+        let max_vertices = 12;
+        let deep = fill_polygon(diamond(), "1".to_owned(), cells, 6, None).unwrap();
+        let adaptive =
+            fill_polygon(diamond(), "1".to_owned(), cells, 6, Some(max_vertices)).unwrap();
+
+        assert!(adaptive.len() < deep.len());
+        let partial = partial_cells(&adaptive);
+        assert!(partial.iter().any(|(hash, _)| hash.len() < 6));
+        for (_, shape) in partial {
+            assert!(shape.coords_count() <= max_vertices);
+        }
+
+        let area = diamond().unsigned_area();
+        assert!((covered_area(cells, &adaptive) - area).abs() < area * 1e-6);
+    }
+
+    #[test]
+    fn max_cell_vertices_splits_intricate_border_cells_beyond_max_level() {
+        // Square with 40 narrow teeth along a short stretch of its top edge
+        let mut exterior = vec![(13.0, 52.0), (13.4, 52.0), (13.4, 52.3)];
+        for tooth in (0..40).rev() {
+            let x = 13.1 + tooth as f64 * 0.0005;
+            exterior.extend([(x + 0.0005, 52.3), (x + 0.00025, 52.305), (x, 52.3)]);
+        }
+        exterior.push((13.0, 52.3));
+        let comb = MultiPolygon(vec![Polygon::new(exterior.into(), Vec::new())]);
+
+        let cells = &GeohashCells;
+        let max_vertices = 12;
+        let indexes =
+            fill_polygon(comb.clone(), "1".to_owned(), cells, 4, Some(max_vertices)).unwrap();
+
+        let partial = partial_cells(&indexes);
+        assert!(partial.iter().any(|(hash, _)| hash.len() > 4));
+        assert!(partial.iter().any(|(hash, _)| hash.len() <= 4));
+        for (_, shape) in partial {
+            assert!(shape.coords_count() <= max_vertices);
+        }
+
+        let area = comb.unsigned_area();
+        assert!((covered_area(cells, &indexes) - area).abs() < area * 1e-6);
+    }
+
+    /// Cells with their value and shape, in the order of their hashes.
+    fn sorted_cells(indexes: Vec<GeohashIndex>) -> Vec<(String, String, Option<MultiPolygon>)> {
+        let mut cells: Vec<_> = indexes
+            .into_iter()
+            .map(|index| match index {
+                GeohashIndex::DirectValue { hash, value } => (hash, value, None),
+                GeohashIndex::PartialValue { hash, value, shape } => (hash, value, Some(shape)),
+            })
+            .collect();
+        cells.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        cells
+    }
+
+    #[test]
+    fn cells_do_not_depend_on_the_number_of_threads() {
+        let fill = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                fill_polygon(
+                    square_with_hole(),
+                    "1".to_owned(),
+                    &GeohashCells,
+                    6,
+                    Some(16),
+                )
+            })
+            .unwrap()
+        };
+
+        let single = sorted_cells(fill(1));
+        assert!(!single.is_empty());
+        assert_eq!(single, sorted_cells(fill(4)));
+    }
+}
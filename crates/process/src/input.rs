@@ -0,0 +1,124 @@
+mod flatgeobuf;
+mod geojson_input;
+mod geopackage;
+mod shapefile;
+mod wkb;
+
+use std::{fmt, io::Read, path::Path, str::FromStr};
+
+use anyhow::{Result, anyhow, bail};
+use geojson::{Feature, feature::Id};
+
+pub use flatgeobuf::FlatGeobufReader;
+pub use geojson_input::GeoJsonReader;
+pub use geopackage::GeoPackageReader;
+pub use shapefile::ShapefileReader;
+
+/// Features of an input file in file order. A record that can't be read is returned as an error
+/// naming its line or record number, reading continues with the next record. Coordinates are
+/// WGS 84 longitude and latitude, the records of files in any other coordinate reference system
+/// are errors.
+pub type FeatureIter = Box<dyn Iterator<Item = Result<Feature>> + Send>;
+
+pub trait FeatureReader {
+    fn read(&self, path: &Path) -> Result<FeatureIter>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Newline-delimited GeoJSON features or a single FeatureCollection
+    GeoJson,
+    Shapefile,
+    GeoPackage,
+    FlatGeobuf,
+}
+
+impl InputFormat {
+    /// Picks the format by file extension, anything unknown is read as GeoJSON.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("shp") => InputFormat::Shapefile,
+            Some("gpkg") => InputFormat::GeoPackage,
+            Some("fgb") => InputFormat::FlatGeobuf,
+            _ => InputFormat::GeoJson,
+        }
+    }
+
+    pub fn reader(&self) -> Box<dyn FeatureReader> {
+        match self {
+            InputFormat::GeoJson => Box::new(GeoJsonReader),
+            InputFormat::Shapefile => Box::new(ShapefileReader),
+            InputFormat::GeoPackage => Box::new(GeoPackageReader),
+            InputFormat::FlatGeobuf => Box::new(FlatGeobufReader),
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "geojson" => Ok(InputFormat::GeoJson),
+            "shapefile" | "shp" => Ok(InputFormat::Shapefile),
+            "geopackage" | "gpkg" => Ok(InputFormat::GeoPackage),
+            "flatgeobuf" | "fgb" => Ok(InputFormat::FlatGeobuf),
+            _ => bail!(
+                "Unknown input format {}, expected geojson, shapefile, geopackage or flatgeobuf",
+                format
+            ),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            InputFormat::GeoJson => "geojson",
+            InputFormat::Shapefile => "shapefile",
+            InputFormat::GeoPackage => "geopackage",
+            InputFormat::FlatGeobuf => "flatgeobuf",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Reads the features of `path`, in the given format or the one matching its extension.
+/// Features without an id get their position in the file as id, as cells refer to their
+/// features by id.
+pub fn read_features(path: &Path, format: Option<InputFormat>) -> Result<FeatureIter> {
+    let features = format
+        .unwrap_or_else(|| InputFormat::from_path(path))
+        .reader()
+        .read(path)?;
+
+    Ok(Box::new(features.enumerate().map(|(position, feature)| {
+        let mut feature = feature?;
+        if feature.id.is_none() {
+            feature.id = Some(Id::Number(position.into()));
+        }
+        Ok(feature)
+    })))
+}
+
+/// Reads up to `length` bytes, fewer only at the end of the file. The buffer grows with the
+/// bytes read, so a corrupt length can't allocate more than the file holds.
+fn read_up_to<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    reader.take(length as u64).read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// The error of a record whose coordinates are in the reference system `crs`, which cells can't
+/// be filled from.
+fn unsupported_crs(crs: &str) -> anyhow::Error {
+    anyhow!(
+        "Coordinates are in {}, only WGS 84 longitude and latitude are supported",
+        crs
+    )
+}
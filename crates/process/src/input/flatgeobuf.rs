@@ -0,0 +1,743 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use flatbuffers::{
+    Follow, ForwardsUOffset, InvalidFlatbuffer, Table, Vector, Verifiable, Verifier,
+    field_index_to_field_offset as slot,
+};
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+use geojson::{Feature, Geometry, JsonObject, Value};
+use serde_json::Number;
+
+use super::{FeatureIter, FeatureReader, read_up_to, unsupported_crs};
+
+const MAGIC: &[u8; 3] = b"fgb";
+const NODE_ITEM_SIZE: u64 = 40;
+
+const GEOMETRY_UNKNOWN: u8 = 0;
+const GEOMETRY_POLYGON: u8 = 3;
+const GEOMETRY_MULTI_POLYGON: u8 = 6;
+
+/// Reads the features of a FlatGeobuf file one by one, skipping its spatial index. Files
+/// without a reference system are taken as WGS 84 longitude and latitude, the features of
+/// files in any other reference system are errors.
+pub struct FlatGeobufReader;
+
+impl FeatureReader for FlatGeobufReader {
+    fn read(&self, path: &Path) -> Result<FeatureIter> {
+        let mut reader = BufReader::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        );
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic[0..3] != MAGIC || &magic[4..7] != MAGIC {
+            bail!("{} is not a FlatGeobuf file", path.display());
+        }
+
+        let header = read_sized(&mut reader)?
+            .with_context(|| format!("{} has no header", path.display()))?;
+        let header = Header::parse(&header)
+            .with_context(|| format!("Invalid FlatGeobuf header in {}", path.display()))?;
+
+        let index_size = packed_rtree_size(header.features_count, header.index_node_size);
+        reader.seek(SeekFrom::Current(index_size as i64))?;
+
+        Ok(Box::new(FlatGeobufFeatures {
+            reader,
+            header,
+            record: 0,
+        }))
+    }
+}
+
+struct Column {
+    name: String,
+    column_type: u8,
+}
+
+struct Header {
+    geometry_type: u8,
+    columns: Vec<Column>,
+    features_count: u64,
+    index_node_size: u16,
+    /// Reference system of the file if it isn't WGS 84 longitude and latitude
+    crs: Option<String>,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let header = flatbuffers::size_prefixed_root::<HeaderTable>(bytes)?;
+        Ok(Header {
+            geometry_type: header.geometry_type().unwrap_or(GEOMETRY_UNKNOWN),
+            columns: read_columns(header.columns()),
+            features_count: header.features_count().unwrap_or(0),
+            index_node_size: header.index_node_size().unwrap_or(16),
+            crs: header.crs().and_then(other_crs),
+        })
+    }
+}
+
+/// The name of a reference system, `None` for WGS 84 longitude and latitude or an unknown
+/// one without a definition.
+fn other_crs(crs: CrsTable) -> Option<String> {
+    let org = crs.org().unwrap_or("EPSG");
+    let code = crs.code().unwrap_or(0);
+    let wgs84 = match code {
+        0 => crs
+            .code_string()
+            .is_some_and(|code| code.eq_ignore_ascii_case("CRS84")),
+        code => org.eq_ignore_ascii_case("EPSG") && code == 4326,
+    };
+    let unknown = code == 0 && crs.code_string().is_none() && crs.wkt().is_none();
+    if wgs84 || unknown {
+        return None;
+    }
+
+    Some(match crs.name() {
+        Some(name) => name.to_owned(),
+        None => {
+            let code = crs
+                .code_string()
+                .map_or_else(|| code.to_string(), str::to_owned);
+            format!("{}:{}", org, code)
+        }
+    })
+}
+
+fn read_columns(columns: Option<Vector<ForwardsUOffset<ColumnTable>>>) -> Vec<Column> {
+    columns
+        .iter()
+        .flatten()
+        .map(|column| Column {
+            name: column.name().unwrap_or_default().to_owned(),
+            column_type: column.column_type().unwrap_or(0),
+        })
+        .collect()
+}
+
+/// Size in bytes of the packed Hilbert R-tree stored between header and features.
+fn packed_rtree_size(features_count: u64, index_node_size: u16) -> u64 {
+    if index_node_size == 0 || features_count == 0 {
+        return 0;
+    }
+
+    let node_size = u64::from(index_node_size.max(2));
+    let mut level_nodes = features_count;
+    let mut nodes = level_nodes;
+    loop {
+        level_nodes = level_nodes.div_ceil(node_size);
+        nodes += level_nodes;
+        if level_nodes == 1 {
+            break;
+        }
+    }
+    nodes * NODE_ITEM_SIZE
+}
+
+/// Reads a buffer prefixed with its u32 length, together with the prefix as flatbuffers aligns
+/// its fields relative to it. `None` at the end of the file.
+fn read_sized<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut buffer = read_up_to(reader, 4)?;
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    let Ok(size) = <[u8; 4]>::try_from(buffer.as_slice()) else {
+        bail!("File ends inside a size prefix");
+    };
+
+    let size = u32::from_le_bytes(size) as usize;
+    buffer.extend(read_up_to(reader, size)?);
+    if buffer.len() < 4 + size {
+        bail!("File ends after {} of {} bytes", buffer.len() - 4, size);
+    }
+    Ok(Some(buffer))
+}
+
+struct FlatGeobufFeatures {
+    reader: BufReader<File>,
+    header: Header,
+    record: usize,
+}
+
+impl Iterator for FlatGeobufFeatures {
+    type Item = Result<Feature>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.record += 1;
+        let buffer = match read_sized(&mut self.reader) {
+            Ok(Some(buffer)) => buffer,
+            Ok(None) => return None,
+            Err(err) => {
+                return Some(Err(err).context(format!("Failed to read feature {}", self.record)));
+            }
+        };
+
+        if let Some(crs) = &self.header.crs {
+            return Some(
+                Err(unsupported_crs(crs))
+                    .with_context(|| format!("Invalid feature {}", self.record)),
+            );
+        }
+
+        Some(
+            read_feature(&buffer, &self.header)
+                .with_context(|| format!("Invalid feature {}", self.record)),
+        )
+    }
+}
+
+fn read_feature(buffer: &[u8], header: &Header) -> Result<Feature> {
+    let feature = flatbuffers::size_prefixed_root::<FeatureTable>(buffer)?;
+
+    let geometry = match feature.geometry() {
+        Some(geometry) => read_geometry(geometry, header.geometry_type)?,
+        None => None,
+    };
+
+    let feature_columns = read_columns(feature.columns());
+    let columns = if feature_columns.is_empty() {
+        &header.columns
+    } else {
+        &feature_columns
+    };
+    let properties = match feature.properties() {
+        Some(bytes) => read_properties(bytes.bytes(), columns)?,
+        None => JsonObject::new(),
+    };
+
+    Ok(Feature {
+        bbox: None,
+        geometry: geometry.map(|shape| Geometry::new(Value::from(&shape))),
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    })
+}
+
+fn read_geometry(geometry: GeometryTable, geometry_type: u8) -> Result<Option<MultiPolygon>> {
+    let geometry_type = match geometry_type {
+        GEOMETRY_UNKNOWN => geometry.geometry_type().unwrap_or(GEOMETRY_UNKNOWN),
+        geometry_type => geometry_type,
+    };
+
+    match geometry_type {
+        GEOMETRY_POLYGON => Ok(Some(MultiPolygon(vec![read_polygon(geometry)?]))),
+        GEOMETRY_MULTI_POLYGON => {
+            let polygons = geometry
+                .parts()
+                .iter()
+                .flatten()
+                .map(read_polygon)
+                .collect::<Result<Vec<Polygon>>>()?;
+            Ok(Some(MultiPolygon(polygons)))
+        }
+        GEOMETRY_UNKNOWN => Ok(None),
+        geometry_type => bail!("Unsupported geometry type {}", geometry_type),
+    }
+}
+
+fn read_polygon(geometry: GeometryTable) -> Result<Polygon> {
+    let coords: Vec<Coord> = match geometry.xy() {
+        Some(xy) => xy
+            .iter()
+            .collect::<Vec<f64>>()
+            .chunks_exact(2)
+            .map(|xy| Coord { x: xy[0], y: xy[1] })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let ends: Vec<usize> = match geometry.ends() {
+        Some(ends) => ends.iter().map(|end| end as usize).collect(),
+        None => vec![coords.len()],
+    };
+
+    let mut rings = Vec::<LineString>::new();
+    let mut start = 0;
+    for end in ends {
+        let Some(ring) = coords.get(start..end) else {
+            bail!("Ring end {} outside of {} coordinates", end, coords.len());
+        };
+        rings.push(LineString::new(ring.to_vec()));
+        start = end;
+    }
+
+    if rings.is_empty() {
+        return Ok(Polygon::new(LineString::new(vec![]), vec![]));
+    }
+    let exterior = rings.remove(0);
+    Ok(Polygon::new(exterior, rings))
+}
+
+fn read_properties(bytes: &[u8], columns: &[Column]) -> Result<JsonObject> {
+    let mut properties = JsonObject::new();
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let column_index =
+            u16::from_le_bytes(take_bytes(bytes, &mut position, 2)?.try_into().unwrap()) as usize;
+        let Some(column) = columns.get(column_index) else {
+            bail!("Unknown column {}", column_index);
+        };
+
+        let value = match column.column_type {
+            // Byte, UByte, Bool
+            0 => serde_json::Value::from(take_bytes(bytes, &mut position, 1)?[0] as i8),
+            1 => serde_json::Value::from(take_bytes(bytes, &mut position, 1)?[0]),
+            2 => serde_json::Value::Bool(take_bytes(bytes, &mut position, 1)?[0] != 0),
+            // Short, UShort, Int, UInt, Long, ULong
+            3 => serde_json::Value::from(i16::from_le_bytes(
+                take_bytes(bytes, &mut position, 2)?.try_into().unwrap(),
+            )),
+            4 => serde_json::Value::from(u16::from_le_bytes(
+                take_bytes(bytes, &mut position, 2)?.try_into().unwrap(),
+            )),
+            5 => serde_json::Value::from(i32::from_le_bytes(
+                take_bytes(bytes, &mut position, 4)?.try_into().unwrap(),
+            )),
+            6 => serde_json::Value::from(u32::from_le_bytes(
+                take_bytes(bytes, &mut position, 4)?.try_into().unwrap(),
+            )),
+            7 => serde_json::Value::from(i64::from_le_bytes(
+                take_bytes(bytes, &mut position, 8)?.try_into().unwrap(),
+            )),
+            8 => serde_json::Value::from(u64::from_le_bytes(
+                take_bytes(bytes, &mut position, 8)?.try_into().unwrap(),
+            )),
+            // Float, Double
+            9 => float_value(f32::from_le_bytes(
+                take_bytes(bytes, &mut position, 4)?.try_into().unwrap(),
+            ) as f64),
+            10 => float_value(f64::from_le_bytes(
+                take_bytes(bytes, &mut position, 8)?.try_into().unwrap(),
+            )),
+            // String, Json, DateTime, Binary
+            column_type @ 11..=14 => {
+                let length =
+                    u32::from_le_bytes(take_bytes(bytes, &mut position, 4)?.try_into().unwrap())
+                        as usize;
+                let value = take_bytes(bytes, &mut position, length)?;
+                match column_type {
+                    12 => serde_json::from_slice(value)?,
+                    14 => continue,
+                    _ => serde_json::Value::String(String::from_utf8_lossy(value).into_owned()),
+                }
+            }
+            column_type => bail!("Unknown column type {}", column_type),
+        };
+        properties.insert(column.name.clone(), value);
+    }
+
+    Ok(properties)
+}
+
+fn take_bytes<'a>(bytes: &'a [u8], position: &mut usize, length: usize) -> Result<&'a [u8]> {
+    let Some(value) = bytes.get(*position..*position + length) else {
+        bail!("Properties end unexpectedly at byte {}", position);
+    };
+    *position += length;
+    Ok(value)
+}
+
+fn float_value(value: f64) -> serde_json::Value {
+    Number::from_f64(value).map_or(serde_json::Value::Null, serde_json::Value::Number)
+}
+
+/// A table of the FlatGeobuf schema with the fields that are read, laid out like the code
+/// flatc generates. A buffer is checked by the flatbuffers verifier against these field types
+/// before any of its tables is followed, which makes the unchecked reads of the accessors sound.
+macro_rules! schema_table {
+    ($table:ident { $($field:ident: $index:literal => $field_type:ty,)* }) => {
+        #[derive(Clone, Copy)]
+        struct $table<'a>(Table<'a>);
+
+        impl<'a> Follow<'a> for $table<'a> {
+            type Inner = $table<'a>;
+
+            unsafe fn follow(buffer: &'a [u8], position: usize) -> Self::Inner {
+                // Safety: the caller passes the position of a table, as for `Table`
+                $table(unsafe { Table::follow(buffer, position) })
+            }
+        }
+
+        impl<'a> Verifiable for $table<'a> {
+            fn run_verifier(
+                verifier: &mut Verifier,
+                position: usize,
+            ) -> Result<(), InvalidFlatbuffer> {
+                verifier
+                    .visit_table(position)?
+                    $(.visit_field::<$field_type>(stringify!($field), slot($index), false)?)*
+                    .finish();
+                Ok(())
+            }
+        }
+
+        impl<'a> $table<'a> {
+            $(
+                fn $field(&self) -> Option<<$field_type as Follow<'a>>::Inner> {
+                    // Safety: the field was verified with this type
+                    unsafe { self.0.get::<$field_type>(slot($index), None) }
+                }
+            )*
+        }
+    };
+}
+
+schema_table!(HeaderTable {
+    geometry_type: 2 => u8,
+    columns: 7 => ForwardsUOffset<Vector<'a, ForwardsUOffset<ColumnTable<'a>>>>,
+    features_count: 8 => u64,
+    index_node_size: 9 => u16,
+    crs: 10 => ForwardsUOffset<CrsTable<'a>>,
+});
+
+schema_table!(CrsTable {
+    org: 0 => ForwardsUOffset<&'a str>,
+    code: 1 => i32,
+    name: 2 => ForwardsUOffset<&'a str>,
+    wkt: 4 => ForwardsUOffset<&'a str>,
+    code_string: 5 => ForwardsUOffset<&'a str>,
+});
+
+schema_table!(ColumnTable {
+    name: 0 => ForwardsUOffset<&'a str>,
+    column_type: 1 => u8,
+});
+
+schema_table!(FeatureTable {
+    geometry: 0 => ForwardsUOffset<GeometryTable<'a>>,
+    properties: 1 => ForwardsUOffset<Vector<'a, u8>>,
+    columns: 2 => ForwardsUOffset<Vector<'a, ForwardsUOffset<ColumnTable<'a>>>>,
+});
+
+schema_table!(GeometryTable {
+    ends: 0 => ForwardsUOffset<Vector<'a, u32>>,
+    xy: 1 => ForwardsUOffset<Vector<'a, f64>>,
+    geometry_type: 6 => u8,
+    parts: 7 => ForwardsUOffset<Vector<'a, ForwardsUOffset<GeometryTable<'a>>>>,
+});
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use flatbuffers::{FlatBufferBuilder, TableFinishedWIPOffset, WIPOffset};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    type Offset = WIPOffset<TableFinishedWIPOffset>;
+
+    const OUTLINE: &[(f64, f64)] = &[
+        (0.0, 0.0),
+        (10.0, 0.0),
+        (10.0, 10.0),
+        (0.0, 10.0),
+        (0.0, 0.0),
+    ];
+    const HOLE: &[(f64, f64)] = &[(2.0, 2.0), (2.0, 4.0), (4.0, 4.0), (4.0, 2.0), (2.0, 2.0)];
+
+    /// Position of a field's slot in the vtable.
+    fn slot(field: u16) -> u16 {
+        4 + field * 2
+    }
+
+    fn header(geometry_type: u8, columns: &[(&str, u8)], features_count: u64) -> Vec<u8> {
+        header_with_crs(geometry_type, columns, features_count, None)
+    }
+
+    /// Header with a reference system given as organization, code and name.
+    fn header_with_crs(
+        geometry_type: u8,
+        columns: &[(&str, u8)],
+        features_count: u64,
+        crs: Option<(&str, i32, &str)>,
+    ) -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let crs = crs.map(|(org, code, name)| {
+            let org = fbb.create_string(org);
+            let name = fbb.create_string(name);
+            let start = fbb.start_table();
+            fbb.push_slot_always(slot(0), org);
+            fbb.push_slot(slot(1), code, 0);
+            fbb.push_slot_always(slot(2), name);
+            fbb.end_table(start)
+        });
+        let columns: Vec<Offset> = columns
+            .iter()
+            .map(|(name, column_type)| {
+                let name = fbb.create_string(name);
+                let start = fbb.start_table();
+                fbb.push_slot_always(slot(0), name);
+                fbb.push_slot(slot(1), *column_type, 0);
+                fbb.end_table(start)
+            })
+            .collect();
+        let columns = fbb.create_vector(&columns);
+
+        let start = fbb.start_table();
+        fbb.push_slot(slot(2), geometry_type, 0);
+        fbb.push_slot_always(slot(7), columns);
+        fbb.push_slot(slot(8), features_count, 0);
+        fbb.push_slot(slot(9), 16u16, 16);
+        if let Some(crs) = crs {
+            fbb.push_slot_always(slot(10), crs);
+        }
+        let header = fbb.end_table(start);
+        fbb.finish_size_prefixed(header, None);
+        fbb.finished_data().to_vec()
+    }
+
+    fn polygon(fbb: &mut FlatBufferBuilder, rings: &[&[(f64, f64)]], geometry_type: u8) -> Offset {
+        let mut ends = Vec::new();
+        let mut xy = Vec::new();
+        for ring in rings {
+            xy.extend(ring.iter().flat_map(|(x, y)| [*x, *y]));
+            ends.push(xy.len() as u32 / 2);
+        }
+        let ends = fbb.create_vector(&ends);
+        let xy = fbb.create_vector(&xy);
+
+        let start = fbb.start_table();
+        fbb.push_slot_always(slot(0), ends);
+        fbb.push_slot_always(slot(1), xy);
+        fbb.push_slot(slot(6), geometry_type, GEOMETRY_UNKNOWN);
+        fbb.end_table(start)
+    }
+
+    fn feature(
+        geometry: impl FnOnce(&mut FlatBufferBuilder) -> Option<Offset>,
+        properties: &[u8],
+    ) -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let geometry = geometry(&mut fbb);
+        let properties = fbb.create_vector(properties);
+
+        let start = fbb.start_table();
+        if let Some(geometry) = geometry {
+            fbb.push_slot_always(slot(0), geometry);
+        }
+        fbb.push_slot_always(slot(1), properties);
+        let feature = fbb.end_table(start);
+        fbb.finish_size_prefixed(feature, None);
+        fbb.finished_data().to_vec()
+    }
+
+    fn outline_feature() -> Vec<u8> {
+        feature(|fbb| Some(polygon(fbb, &[OUTLINE], GEOMETRY_UNKNOWN)), &[])
+    }
+
+    fn property(column: u16, value: &[u8]) -> Vec<u8> {
+        [&column.to_le_bytes()[..], value].concat()
+    }
+
+    fn sized(value: &str) -> Vec<u8> {
+        [&(value.len() as u32).to_le_bytes()[..], value.as_bytes()].concat()
+    }
+
+    fn write_flatgeobuf(dir: &TempDir, header: &[u8], features: &[Vec<u8>]) -> PathBuf {
+        let mut file = b"fgb\x03fgb\x00".to_vec();
+        file.extend(header);
+        let header = Header::parse(header).unwrap();
+        let index_size = packed_rtree_size(header.features_count, header.index_node_size);
+        file.extend(vec![0u8; index_size as usize]);
+        file.extend(features.concat());
+
+        let path = dir.path().join("test.fgb");
+        fs::write(&path, file).unwrap();
+        path
+    }
+
+    fn read(path: &Path) -> Vec<Result<Feature>> {
+        FlatGeobufReader.read(path).unwrap().collect()
+    }
+
+    fn polygons(feature: &Feature) -> MultiPolygon {
+        let geometry = feature.geometry.clone().unwrap();
+        geometry.value.try_into().unwrap()
+    }
+
+    #[test]
+    fn packed_rtree_holds_all_levels() {
+        assert_eq!(packed_rtree_size(0, 16), 0);
+        assert_eq!(packed_rtree_size(5, 0), 0);
+        assert_eq!(packed_rtree_size(1, 16), 2 * NODE_ITEM_SIZE);
+        assert_eq!(packed_rtree_size(16, 16), 17 * NODE_ITEM_SIZE);
+        assert_eq!(packed_rtree_size(17, 16), 20 * NODE_ITEM_SIZE);
+    }
+
+    #[test]
+    fn reads_features_with_properties() {
+        let dir = TempDir::new().unwrap();
+        let header = header(
+            GEOMETRY_POLYGON,
+            &[("name", 11), ("population", 5), ("area", 10), ("tags", 12)],
+            2,
+        );
+        let properties = [
+            property(0, &sized("Mitte")),
+            property(1, &42i32.to_le_bytes()),
+            property(2, &1.5f64.to_le_bytes()),
+            property(3, &sized(r#"{"admin_level":"9"}"#)),
+        ]
+        .concat();
+        let features = [
+            feature(
+                |fbb| Some(polygon(fbb, &[OUTLINE, HOLE], GEOMETRY_UNKNOWN)),
+                &properties,
+            ),
+            feature(
+                |fbb| Some(polygon(fbb, &[OUTLINE], GEOMETRY_UNKNOWN)),
+                &property(0, &sized("Pankow")),
+            ),
+        ];
+        let path = write_flatgeobuf(&dir, &header, &features);
+
+        let features = read(&path);
+        assert_eq!(features.len(), 2);
+
+        let first = features[0].as_ref().unwrap();
+        let shape = polygons(first);
+        assert_eq!(shape.0.len(), 1);
+        assert_eq!(shape.0[0].exterior().0.len(), 5);
+        assert_eq!(shape.0[0].interiors().len(), 1);
+        let properties = first.properties.as_ref().unwrap();
+        assert_eq!(properties["name"], "Mitte");
+        assert_eq!(properties["population"], 42);
+        assert_eq!(properties["area"], 1.5);
+        assert_eq!(properties["tags"]["admin_level"], "9");
+
+        let second = features[1].as_ref().unwrap();
+        let properties = second.properties.as_ref().unwrap();
+        assert_eq!(properties.len(), 1);
+        assert_eq!(properties["name"], "Pankow");
+    }
+
+    #[test]
+    fn reads_geometry_types_of_mixed_files() {
+        let dir = TempDir::new().unwrap();
+        let header = header(GEOMETRY_UNKNOWN, &[], 0);
+        let multi_polygon = feature(
+            |fbb| {
+                let parts = [
+                    polygon(fbb, &[OUTLINE], GEOMETRY_POLYGON),
+                    polygon(fbb, &[HOLE], GEOMETRY_POLYGON),
+                ];
+                let parts = fbb.create_vector(&parts);
+                let start = fbb.start_table();
+                fbb.push_slot_always(slot(7), parts);
+                fbb.push_slot(slot(6), GEOMETRY_MULTI_POLYGON, GEOMETRY_UNKNOWN);
+                Some(fbb.end_table(start))
+            },
+            &[],
+        );
+        let features = [
+            feature(|fbb| Some(polygon(fbb, &[OUTLINE], GEOMETRY_POLYGON)), &[]),
+            multi_polygon,
+            feature(|_| None, &[]),
+        ];
+        let path = write_flatgeobuf(&dir, &header, &features);
+
+        let features = read(&path);
+        assert_eq!(features.len(), 3);
+        assert_eq!(polygons(features[0].as_ref().unwrap()).0.len(), 1);
+        assert_eq!(polygons(features[1].as_ref().unwrap()).0.len(), 2);
+        assert!(features[2].as_ref().unwrap().geometry.is_none());
+    }
+
+    #[test]
+    fn reports_the_number_of_bad_records() {
+        let dir = TempDir::new().unwrap();
+        let header = header(GEOMETRY_POLYGON, &[("name", 11)], 0);
+        let ring_past_coordinates = feature(
+            |fbb| {
+                let ends = fbb.create_vector(&[7u32]);
+                let xy = fbb.create_vector(&[0.0f64; 10]);
+                let start = fbb.start_table();
+                fbb.push_slot_always(slot(0), ends);
+                fbb.push_slot_always(slot(1), xy);
+                Some(fbb.end_table(start))
+            },
+            &[],
+        );
+        let unknown_column = feature(
+            |fbb| Some(polygon(fbb, &[OUTLINE], GEOMETRY_UNKNOWN)),
+            &property(1, &sized("Mitte")),
+        );
+        let mut truncated = outline_feature();
+        truncated.truncate(truncated.len() - 8);
+        let features = [
+            outline_feature(),
+            ring_past_coordinates,
+            unknown_column,
+            outline_feature(),
+            truncated,
+        ];
+        let path = write_flatgeobuf(&dir, &header, &features);
+
+        let features = read(&path);
+        assert_eq!(features.len(), 5);
+        assert!(features[0].is_ok());
+        let error = |index: usize| features[index].as_ref().unwrap_err();
+        assert_eq!(
+            format!("{:#}", error(1)),
+            "Invalid feature 2: Ring end 7 outside of 5 coordinates"
+        );
+        assert_eq!(
+            format!("{:#}", error(2)),
+            "Invalid feature 3: Unknown column 1"
+        );
+        assert!(features[3].is_ok());
+        assert_eq!(error(4).to_string(), "Failed to read feature 5");
+    }
+
+    #[test]
+    fn reports_a_truncated_size_prefix() {
+        let dir = TempDir::new().unwrap();
+        let header = header(GEOMETRY_POLYGON, &[], 0);
+        let path = write_flatgeobuf(&dir, &header, &[outline_feature(), vec![1, 0]]);
+
+        let features = read(&path);
+        assert_eq!(features.len(), 2);
+        assert!(features[0].is_ok());
+        assert_eq!(
+            format!("{:#}", features[1].as_ref().unwrap_err()),
+            "Failed to read feature 2: File ends inside a size prefix"
+        );
+    }
+
+    #[test]
+    fn rejects_features_in_other_reference_systems() {
+        let dir = TempDir::new().unwrap();
+        let features = [outline_feature(), outline_feature()];
+
+        let wgs84 = header_with_crs(GEOMETRY_POLYGON, &[], 0, Some(("EPSG", 4326, "WGS 84")));
+        let path = write_flatgeobuf(&dir, &wgs84, &features);
+        assert!(read(&path).iter().all(Result::is_ok));
+
+        let mercator = header_with_crs(
+            GEOMETRY_POLYGON,
+            &[],
+            0,
+            Some(("EPSG", 3857, "WGS 84 / Pseudo-Mercator")),
+        );
+        let path = write_flatgeobuf(&dir, &mercator, &features);
+        let features = read(&path);
+        for (number, feature) in features.iter().enumerate() {
+            assert_eq!(
+                format!("{:#}", feature.as_ref().unwrap_err()),
+                format!(
+                    "Invalid feature {}: Coordinates are in WGS 84 / Pseudo-Mercator, \
+                     only WGS 84 longitude and latitude are supported",
+                    number + 1
+                )
+            );
+        }
+    }
+}
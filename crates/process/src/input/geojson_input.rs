@@ -0,0 +1,74 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use geojson::{Feature, GeoJson};
+
+use super::{FeatureIter, FeatureReader};
+
+/// Reads newline-delimited GeoJSON features, as written by `extract`, or a single GeoJSON
+/// document holding a FeatureCollection or Feature.
+pub struct GeoJsonReader;
+
+impl FeatureReader for GeoJsonReader {
+    fn read(&self, path: &Path) -> Result<FeatureIter> {
+        let mut reader = BufReader::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        );
+
+        let mut skipped_lines = 0;
+        let mut first_line = String::new();
+        while first_line.trim().is_empty() {
+            first_line.clear();
+            if reader.read_line(&mut first_line)? == 0 {
+                return Ok(Box::new(std::iter::empty()));
+            }
+            skipped_lines += 1;
+        }
+
+        // A document spread over several lines or a collection on a single line
+        let trimmed = first_line.trim();
+        let single_line_feature = trimmed.starts_with('{') && trimmed.ends_with('}');
+        let document = match GeoJson::from_str(trimmed) {
+            Ok(GeoJson::Feature(_)) => None,
+            Ok(document) => Some(document),
+            Err(_) if single_line_feature => None,
+            Err(_) => {
+                let mut document = first_line.clone();
+                reader.read_to_string(&mut document)?;
+                Some(
+                    GeoJson::from_str(&document)
+                        .with_context(|| format!("Invalid GeoJSON document {}", path.display()))?,
+                )
+            }
+        };
+
+        match document {
+            Some(GeoJson::FeatureCollection(collection)) => {
+                Ok(Box::new(collection.features.into_iter().map(Ok)))
+            }
+            Some(GeoJson::Feature(feature)) => Ok(Box::new(std::iter::once(Ok(feature)))),
+            Some(GeoJson::Geometry(_)) => bail!("{} holds a bare geometry", path.display()),
+            None => {
+                let lines = std::iter::once(Ok(first_line)).chain(reader.lines());
+                Ok(Box::new(lines.enumerate().filter_map(
+                    move |(index, line)| {
+                        let line_number = index + skipped_lines;
+                        match line {
+                            Ok(line) if line.trim().is_empty() => None,
+                            Ok(line) => Some(Feature::from_str(&line).with_context(|| {
+                                format!("Invalid feature on line {}", line_number)
+                            })),
+                            Err(err) => Some(Err(anyhow!(err)
+                                .context(format!("Failed to read line {}", line_number)))),
+                        }
+                    },
+                )))
+            }
+        }
+    }
+}
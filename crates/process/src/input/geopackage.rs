@@ -0,0 +1,376 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, Result};
+use geojson::{Feature, Geometry, JsonObject, Value};
+use rusqlite::{Connection, OpenFlags, Row, types::ValueRef};
+use serde_json::Number;
+
+use super::{
+    FeatureIter, FeatureReader, unsupported_crs,
+    wkb::{gpkg_srs_id, read_gpkg_geometry},
+};
+
+/// Reads the features of all feature tables of a GeoPackage. The rows of a table are loaded
+/// at once, the tables one after another. Geometries in a reference system other than WGS 84
+/// longitude and latitude are errors.
+pub struct GeoPackageReader;
+
+impl FeatureReader for GeoPackageReader {
+    fn read(&self, path: &Path) -> Result<FeatureIter> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open GeoPackage {}", path.display()))?;
+
+        let tables = connection
+            .prepare(
+                "SELECT c.table_name, g.column_name FROM gpkg_contents c \
+                 JOIN gpkg_geometry_columns g ON g.table_name = c.table_name \
+                 WHERE c.data_type = 'features'",
+            )?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()
+            .with_context(|| format!("Failed to list feature tables of {}", path.display()))?;
+
+        let reference_systems = ReferenceSystems::read(&connection)
+            .with_context(|| format!("Failed to read reference systems of {}", path.display()))?;
+
+        Ok(Box::new(tables.into_iter().flat_map(
+            move |(table, geometry_column)| match read_table(
+                &connection,
+                &reference_systems,
+                &table,
+                &geometry_column,
+            ) {
+                Ok(features) => features,
+                Err(err) => vec![Err(err)],
+            },
+        )))
+    }
+}
+
+/// The spatial reference systems of a GeoPackage by `srs_id`, their name and whether they are
+/// WGS 84 longitude and latitude.
+struct ReferenceSystems(HashMap<i32, (String, bool)>);
+
+impl ReferenceSystems {
+    fn read(connection: &Connection) -> Result<Self> {
+        let reference_systems = connection
+            .prepare(
+                "SELECT srs_id, srs_name, organization, organization_coordsys_id \
+                 FROM gpkg_spatial_ref_sys",
+            )?
+            .query_map([], |row| {
+                let organization: String = row.get(2)?;
+                let wgs84 =
+                    organization.eq_ignore_ascii_case("EPSG") && row.get::<_, i32>(3)? == 4326;
+                Ok((row.get(0)?, (row.get(1)?, wgs84)))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ReferenceSystems(reference_systems))
+    }
+
+    /// Fails for a geometry blob in a reference system other than WGS 84 longitude and
+    /// latitude. The undefined geographic system 0 is taken as WGS 84.
+    fn check(&self, blob: &[u8]) -> Result<()> {
+        match gpkg_srs_id(blob) {
+            None | Some(0) => Ok(()),
+            Some(srs_id) => match self.0.get(&srs_id) {
+                Some((_, true)) => Ok(()),
+                Some((name, false)) => Err(unsupported_crs(name)),
+                None => Err(unsupported_crs(&format!("unknown srs_id {}", srs_id))),
+            },
+        }
+    }
+}
+
+fn read_table(
+    connection: &Connection,
+    reference_systems: &ReferenceSystems,
+    table: &str,
+    geometry_column: &str,
+) -> Result<Vec<Result<Feature>>> {
+    let mut statement = connection
+        .prepare(&format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")))
+        .with_context(|| format!("Failed to read table {}", table))?;
+    let columns: Vec<String> = statement
+        .column_names()
+        .into_iter()
+        .map(|column| column.to_owned())
+        .collect();
+
+    let mut rows = statement.query([])?;
+    let mut features = Vec::new();
+    let mut record = 0;
+    while let Some(row) = rows.next()? {
+        record += 1;
+        features.push(
+            read_row(row, reference_systems, &columns, geometry_column)
+                .with_context(|| format!("Invalid record {} in table {}", record, table)),
+        );
+    }
+
+    Ok(features)
+}
+
+fn read_row(
+    row: &Row,
+    reference_systems: &ReferenceSystems,
+    columns: &[String],
+    geometry_column: &str,
+) -> Result<Feature> {
+    let mut geometry = None;
+    let mut properties = JsonObject::new();
+
+    for (index, column) in columns.iter().enumerate() {
+        let value = row.get_ref(index)?;
+        if column == geometry_column {
+            if let ValueRef::Blob(blob) = value {
+                reference_systems.check(blob)?;
+                geometry =
+                    read_gpkg_geometry(blob)?.map(|shape| Geometry::new(Value::from(&shape)));
+            }
+            continue;
+        }
+
+        let value = match value {
+            ValueRef::Null => serde_json::Value::Null,
+            ValueRef::Integer(integer) => serde_json::Value::from(integer),
+            ValueRef::Real(real) => {
+                Number::from_f64(real).map_or(serde_json::Value::Null, serde_json::Value::Number)
+            }
+            ValueRef::Text(text) => {
+                serde_json::Value::String(String::from_utf8_lossy(text).into_owned())
+            }
+            ValueRef::Blob(_) => continue,
+        };
+        properties.insert(column.clone(), value);
+    }
+
+    Ok(Feature {
+        bbox: None,
+        geometry,
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::MultiPolygon;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const OUTLINE: &[(f64, f64)] = &[
+        (0.0, 0.0),
+        (10.0, 0.0),
+        (10.0, 10.0),
+        (0.0, 10.0),
+        (0.0, 0.0),
+    ];
+    const HOLE: &[(f64, f64)] = &[(2.0, 2.0), (2.0, 4.0), (4.0, 4.0), (4.0, 2.0), (2.0, 2.0)];
+
+    /// Little endian WKB polygon, `z` adds a third ordinate as ISO PolygonZ.
+    fn wkb_polygon(rings: &[&[(f64, f64)]], z: bool) -> Vec<u8> {
+        let mut wkb = vec![1];
+        wkb.extend(if z { 1003u32 } else { 3u32 }.to_le_bytes());
+        wkb.extend((rings.len() as u32).to_le_bytes());
+        for ring in rings {
+            wkb.extend((ring.len() as u32).to_le_bytes());
+            for (x, y) in ring.iter() {
+                wkb.extend(x.to_le_bytes());
+                wkb.extend(y.to_le_bytes());
+                if z {
+                    wkb.extend(5f64.to_le_bytes());
+                }
+            }
+        }
+        wkb
+    }
+
+    fn wkb_multi_polygon(polygons: &[Vec<u8>]) -> Vec<u8> {
+        let mut wkb = vec![1];
+        wkb.extend(6u32.to_le_bytes());
+        wkb.extend((polygons.len() as u32).to_le_bytes());
+        wkb.extend(polygons.concat());
+        wkb
+    }
+
+    /// GeoPackage blob with an envelope of `envelope` doubles.
+    fn gpkg_blob(envelope: usize, empty: bool, wkb: &[u8]) -> Vec<u8> {
+        let envelope_type = match envelope {
+            0 => 0,
+            4 => 1,
+            6 => 2,
+            8 => 4,
+            _ => unreachable!(),
+        };
+        let flags = 1 | envelope_type << 1 | u8::from(empty) << 4;
+        let mut blob = vec![b'G', b'P', 0, flags];
+        blob.extend(4326i32.to_le_bytes());
+        for _ in 0..envelope {
+            blob.extend(1f64.to_le_bytes());
+        }
+        blob.extend(wkb);
+        blob
+    }
+
+    fn write_geopackage(dir: &TempDir, geometries: &[Option<Vec<u8>>]) -> std::path::PathBuf {
+        let path = dir.path().join("test.gpkg");
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE gpkg_spatial_ref_sys (srs_name TEXT, srs_id INTEGER PRIMARY KEY,
+                    organization TEXT, organization_coordsys_id INTEGER);
+                 INSERT INTO gpkg_spatial_ref_sys VALUES
+                    ('Undefined cartesian SRS', -1, 'NONE', -1),
+                    ('Undefined geographic SRS', 0, 'NONE', 0),
+                    ('WGS 84 geodetic', 4326, 'EPSG', 4326),
+                    ('WGS 84', 7, 'epsg', 4326),
+                    ('ETRS89 / UTM zone 32N', 25832, 'EPSG', 25832);
+                 CREATE TABLE gpkg_contents (table_name TEXT, data_type TEXT);
+                 CREATE TABLE gpkg_geometry_columns (table_name TEXT, column_name TEXT);
+                 INSERT INTO gpkg_contents VALUES ('districts', 'features'), ('notes', 'attributes');
+                 INSERT INTO gpkg_geometry_columns VALUES ('districts', 'geom');
+                 CREATE TABLE notes (text TEXT);
+                 CREATE TABLE districts (fid INTEGER PRIMARY KEY, name TEXT, area REAL, geom BLOB);",
+            )
+            .unwrap();
+        for (index, geometry) in geometries.iter().enumerate() {
+            connection
+                .execute(
+                    "INSERT INTO districts (name, area, geom) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![format!("District {}", index + 1), 1.5, geometry],
+                )
+                .unwrap();
+        }
+        path
+    }
+
+    fn read(dir: &TempDir, geometries: &[Option<Vec<u8>>]) -> Vec<Result<Feature>> {
+        let path = write_geopackage(dir, geometries);
+        GeoPackageReader.read(&path).unwrap().collect()
+    }
+
+    fn polygons(feature: &Feature) -> MultiPolygon {
+        let geometry = feature.geometry.clone().unwrap();
+        geometry.value.try_into().unwrap()
+    }
+
+    #[test]
+    fn reads_polygons_and_attributes() {
+        let dir = TempDir::new().unwrap();
+        let features = read(
+            &dir,
+            &[
+                Some(gpkg_blob(4, false, &wkb_polygon(&[OUTLINE, HOLE], false))),
+                Some(gpkg_blob(
+                    0,
+                    false,
+                    &wkb_multi_polygon(&[
+                        wkb_polygon(&[OUTLINE], false),
+                        wkb_polygon(&[HOLE], false),
+                    ]),
+                )),
+                Some(gpkg_blob(6, false, &wkb_polygon(&[OUTLINE], true))),
+            ],
+        );
+        assert_eq!(features.len(), 3);
+
+        let first = features[0].as_ref().unwrap();
+        let shape = polygons(first);
+        assert_eq!(shape.0.len(), 1);
+        assert_eq!(shape.0[0].interiors().len(), 1);
+        let properties = first.properties.as_ref().unwrap();
+        assert_eq!(properties["fid"], 1);
+        assert_eq!(properties["name"], "District 1");
+        assert_eq!(properties["area"], 1.5);
+        assert!(!properties.contains_key("geom"));
+
+        assert_eq!(polygons(features[1].as_ref().unwrap()).0.len(), 2);
+
+        let z_shape = polygons(features[2].as_ref().unwrap());
+        assert_eq!(z_shape.0[0].exterior().0[1], geo::Coord { x: 10.0, y: 0.0 });
+    }
+
+    #[test]
+    fn empty_and_null_geometries_have_no_shape() {
+        let dir = TempDir::new().unwrap();
+        let features = read(&dir, &[Some(gpkg_blob(0, true, &[])), None]);
+        assert_eq!(features.len(), 2);
+        assert!(features[0].as_ref().unwrap().geometry.is_none());
+        assert!(features[1].as_ref().unwrap().geometry.is_none());
+    }
+
+    #[test]
+    fn reports_the_number_of_bad_records() {
+        let dir = TempDir::new().unwrap();
+        let polygon = wkb_polygon(&[OUTLINE], false);
+        let mut point = vec![1];
+        point.extend(1u32.to_le_bytes());
+        point.extend([0u8; 16]);
+        let features = read(
+            &dir,
+            &[
+                Some(gpkg_blob(0, false, &polygon)),
+                Some(gpkg_blob(0, false, &polygon[..polygon.len() - 4])),
+                Some(gpkg_blob(0, false, &point)),
+                Some(b"XP\0\x01\0\0\0\0".to_vec()),
+                Some(gpkg_blob(0, false, &polygon)),
+            ],
+        );
+
+        assert_eq!(features.len(), 5);
+        assert!(features[0].is_ok());
+        let error = |index: usize| features[index].as_ref().unwrap_err();
+        assert_eq!(error(1).to_string(), "Invalid record 2 in table districts");
+        assert_eq!(
+            format!("{:#}", error(2)),
+            "Invalid record 3 in table districts: \
+             Only Polygon and MultiPolygon geometries are supported"
+        );
+        assert_eq!(error(3).to_string(), "Invalid record 4 in table districts");
+        assert!(features[4].is_ok());
+    }
+
+    #[test]
+    fn rejects_records_in_other_reference_systems() {
+        let dir = TempDir::new().unwrap();
+        let in_srs = |srs_id: i32| {
+            let mut blob = gpkg_blob(0, false, &wkb_polygon(&[OUTLINE], false));
+            blob[4..8].copy_from_slice(&srs_id.to_le_bytes());
+            Some(blob)
+        };
+        let features = read(
+            &dir,
+            &[
+                in_srs(4326),
+                in_srs(7),
+                in_srs(0),
+                in_srs(25832),
+                in_srs(-1),
+                in_srs(3857),
+            ],
+        );
+
+        assert!(features[..3].iter().all(Result::is_ok));
+        let error = |index: usize| format!("{:#}", features[index].as_ref().unwrap_err());
+        assert_eq!(
+            error(3),
+            "Invalid record 4 in table districts: Coordinates are in ETRS89 / UTM zone 32N, \
+             only WGS 84 longitude and latitude are supported"
+        );
+        assert_eq!(
+            error(4),
+            "Invalid record 5 in table districts: Coordinates are in Undefined cartesian SRS, \
+             only WGS 84 longitude and latitude are supported"
+        );
+        assert_eq!(
+            error(5),
+            "Invalid record 6 in table districts: Coordinates are in unknown srs_id 3857, \
+             only WGS 84 longitude and latitude are supported"
+        );
+    }
+}
@@ -0,0 +1,610 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, ErrorKind, Read},
+    path::Path,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use encoding_rs::{Encoding, UTF_8};
+use geo::{Contains, Coord, LineString, MultiPolygon, Polygon, Winding};
+use geojson::{Feature, Geometry, JsonObject, Value};
+use serde_json::Number;
+
+use super::{FeatureIter, FeatureReader, read_up_to, unsupported_crs};
+
+/// Reads the polygons of a `.shp` file together with the attributes of the `.dbf` file next
+/// to it. Strings are decoded with the encoding named by the `.cpg` file, UTF-8 without one.
+/// Coordinates are taken as WGS 84 longitude and latitude without a `.prj` file, the records of
+/// a file in any other reference system are errors.
+pub struct ShapefileReader;
+
+impl FeatureReader for ShapefileReader {
+    fn read(&self, path: &Path) -> Result<FeatureIter> {
+        let mut shapes = BufReader::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        );
+        let mut header = [0u8; 100];
+        shapes.read_exact(&mut header)?;
+        if i32::from_be_bytes(header[0..4].try_into().unwrap()) != 9994 {
+            bail!("{} is not a shapefile", path.display());
+        }
+
+        let prj_path = path.with_extension("prj");
+        let crs = read_sidecar(&prj_path)
+            .with_context(|| format!("Failed to read {}", prj_path.display()))?
+            .and_then(|wkt| other_crs(&wkt));
+
+        let cpg_path = path.with_extension("cpg");
+        let encoding = match read_sidecar(&cpg_path)? {
+            Some(cpg) => cpg_encoding(&cpg)
+                .with_context(|| format!("Failed to read {}", cpg_path.display()))?,
+            None => UTF_8,
+        };
+
+        let dbf_path = path.with_extension("dbf");
+        let attributes = match File::open(&dbf_path) {
+            Ok(file) => Some(DbfReader::new(BufReader::new(file), encoding).with_context(
+                || format!("Failed to read attributes from {}", dbf_path.display()),
+            )?),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Box::new(ShapefileFeatures {
+            shapes,
+            attributes,
+            crs,
+            record: 0,
+        }))
+    }
+}
+
+/// Reads a file next to the shapefile, `None` if there is none.
+fn read_sidecar(path: &Path) -> io::Result<Option<String>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(String::from_utf8_lossy(&bytes).into_owned())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// The name of the coordinate reference system of a `.prj` file, `None` for WGS 84 longitude
+/// and latitude.
+fn other_crs(wkt: &str) -> Option<String> {
+    let wkt = wkt.trim();
+    let upper = wkt.to_ascii_uppercase();
+    let geographic = ["GEOGCS[", "GEOGCRS[", "GEOGRAPHICCRS["]
+        .iter()
+        .any(|keyword| upper.starts_with(keyword));
+    let wgs84 = ["WGS_1984", "WGS 1984", "WGS 84", "WGS84"]
+        .iter()
+        .any(|datum| upper.contains(datum));
+    if geographic && wgs84 {
+        return None;
+    }
+    Some(wkt.split('"').nth(1).unwrap_or(wkt).to_owned())
+}
+
+/// The encoding named by a `.cpg` file, an encoding label or a Windows code page like
+/// `1252` or `ANSI 1252`.
+fn cpg_encoding(cpg: &str) -> Result<&'static Encoding> {
+    let label = cpg.trim().to_ascii_lowercase();
+    let code = label.strip_prefix("ansi ").unwrap_or(&label);
+    let label = match code {
+        "65001" | "utf8" => "utf-8".to_owned(),
+        "866" => "ibm866".to_owned(),
+        "874" => "windows-874".to_owned(),
+        "932" => "shift_jis".to_owned(),
+        "936" => "gbk".to_owned(),
+        "949" => "euc-kr".to_owned(),
+        "950" => "big5".to_owned(),
+        code if code.len() == 4 && code.starts_with("125") => format!("windows-{}", code),
+        code if code.starts_with("8859") => format!("iso-8859-{}", &code[4..]),
+        _ => label.clone(),
+    };
+    Encoding::for_label(label.as_bytes())
+        .with_context(|| format!("Unknown encoding {}", cpg.trim()))
+}
+
+struct ShapefileFeatures {
+    shapes: BufReader<File>,
+    attributes: Option<DbfReader>,
+    /// Reference system of the file if it isn't WGS 84 longitude and latitude
+    crs: Option<String>,
+    record: usize,
+}
+
+impl Iterator for ShapefileFeatures {
+    type Item = Result<Feature>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record_header = match read_up_to(&mut self.shapes, 8) {
+            Ok(record_header) if record_header.is_empty() => return None,
+            Ok(record_header) => record_header,
+            Err(err) => return Some(Err(err)),
+        };
+        self.record += 1;
+
+        let content = match record_header.get(4..8) {
+            Some(length) => match i32::from_be_bytes(length.try_into().unwrap()) {
+                length if length < 0 => Err(anyhow!("Negative content length {}", length)),
+                length => read_up_to(&mut self.shapes, length as usize * 2).and_then(|content| {
+                    if content.len() < length as usize * 2 {
+                        bail!("File ends inside the record");
+                    }
+                    Ok(content)
+                }),
+            },
+            None => Err(anyhow!("File ends inside the record header")),
+        };
+        let content = match content {
+            Ok(content) => content,
+            Err(err) => {
+                return Some(
+                    Err(err).with_context(|| format!("Truncated shapefile record {}", self.record)),
+                );
+            }
+        };
+
+        let properties = match self.attributes.as_mut().map(DbfReader::next_record) {
+            Some(Ok(properties)) => Some(properties),
+            Some(Err(err)) => {
+                return Some(
+                    Err(err).with_context(|| format!("Invalid dbf record {}", self.record)),
+                );
+            }
+            None => None,
+        };
+
+        if let Some(crs) = &self.crs {
+            return Some(
+                Err(unsupported_crs(crs))
+                    .with_context(|| format!("Invalid shapefile record {}", self.record)),
+            );
+        }
+
+        Some(
+            read_shape(&content)
+                .map(|shape| Feature {
+                    bbox: None,
+                    geometry: shape.map(|shape| Geometry::new(Value::from(&shape))),
+                    id: None,
+                    properties,
+                    foreign_members: None,
+                })
+                .with_context(|| format!("Invalid shapefile record {}", self.record)),
+        )
+    }
+}
+
+/// Reads a Polygon, PolygonZ or PolygonM shape, `None` for a null shape. Rings are grouped
+/// into polygons by their winding order, clockwise rings are outlines and counter-clockwise
+/// rings holes of the outline containing them.
+fn read_shape(content: &[u8]) -> Result<Option<MultiPolygon>> {
+    let read_i32 = |offset: usize| -> Result<i32> {
+        match content.get(offset..offset + 4) {
+            Some(bytes) => Ok(i32::from_le_bytes(bytes.try_into().unwrap())),
+            None => bail!("Shape ends unexpectedly at byte {}", offset),
+        }
+    };
+    let read_f64 = |offset: usize| -> Result<f64> {
+        match content.get(offset..offset + 8) {
+            Some(bytes) => Ok(f64::from_le_bytes(bytes.try_into().unwrap())),
+            None => bail!("Shape ends unexpectedly at byte {}", offset),
+        }
+    };
+
+    match read_i32(0)? {
+        0 => return Ok(None),
+        5 | 15 | 25 => {}
+        shape_type => bail!("Unsupported shape type {}", shape_type),
+    }
+
+    let part_count = read_i32(36)?.max(0) as usize;
+    let point_count = read_i32(40)?.max(0) as usize;
+    let parts = (0..part_count)
+        .map(|part| read_i32(44 + part * 4).map(|start| start.max(0) as usize))
+        .collect::<Result<Vec<usize>>>()?;
+    let points_offset = 44 + part_count * 4;
+
+    let mut outlines = Vec::<Polygon>::new();
+    let mut holes = Vec::<LineString>::new();
+    for (index, start) in parts.iter().enumerate() {
+        let end = parts.get(index + 1).copied().unwrap_or(point_count);
+        let ring = (*start..end)
+            .map(|point| {
+                let offset = points_offset + point * 16;
+                Ok(Coord {
+                    x: read_f64(offset)?,
+                    y: read_f64(offset + 8)?,
+                })
+            })
+            .collect::<Result<Vec<Coord>>>()
+            .map(LineString::new)?;
+
+        if ring.is_cw() {
+            outlines.push(Polygon::new(ring, vec![]));
+        } else {
+            holes.push(ring);
+        }
+    }
+
+    // Some writers ignore the winding order, a lone ring is always an outline
+    if outlines.is_empty() && holes.len() == 1 {
+        outlines.push(Polygon::new(holes.remove(0), vec![]));
+    }
+
+    for hole in holes {
+        let outline = hole
+            .0
+            .first()
+            .and_then(|first| outlines.iter_mut().find(|outline| outline.contains(first)));
+        match outline {
+            Some(outline) => outline.interiors_push(hole),
+            None => outlines.push(Polygon::new(hole, vec![])),
+        }
+    }
+
+    Ok(Some(MultiPolygon(outlines)))
+}
+
+struct DbfField {
+    name: String,
+    field_type: u8,
+    length: usize,
+}
+
+struct DbfReader {
+    reader: BufReader<File>,
+    fields: Vec<DbfField>,
+    record_length: usize,
+    encoding: &'static Encoding,
+}
+
+impl DbfReader {
+    fn new(mut reader: BufReader<File>, encoding: &'static Encoding) -> Result<Self> {
+        let mut header = [0u8; 32];
+        reader.read_exact(&mut header)?;
+        let header_length = u16::from_le_bytes([header[8], header[9]]) as usize;
+        let record_length = u16::from_le_bytes([header[10], header[11]]) as usize;
+
+        let mut descriptors = vec![0u8; header_length.saturating_sub(32)];
+        reader.read_exact(&mut descriptors)?;
+        let fields = descriptors
+            .chunks_exact(32)
+            .take_while(|descriptor| descriptor[0] != 0x0d)
+            .map(|descriptor| DbfField {
+                name: encoding
+                    .decode_without_bom_handling(&descriptor[0..11])
+                    .0
+                    .trim_end_matches('\0')
+                    .to_owned(),
+                field_type: descriptor[11],
+                length: descriptor[16] as usize,
+            })
+            .collect();
+
+        Ok(DbfReader {
+            reader,
+            fields,
+            record_length,
+            encoding,
+        })
+    }
+
+    fn next_record(&mut self) -> Result<JsonObject> {
+        let mut record = vec![0u8; self.record_length];
+        self.reader.read_exact(&mut record)?;
+
+        let mut properties = JsonObject::new();
+        let mut offset = 1;
+        for field in &self.fields {
+            let Some(raw) = record.get(offset..offset + field.length) else {
+                bail!("Record shorter than its fields");
+            };
+            offset += field.length;
+
+            let text = self.encoding.decode_without_bom_handling(raw).0;
+            let text = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+            let value = match field.field_type {
+                _ if text.is_empty() => serde_json::Value::Null,
+                b'N' | b'F' => match text.parse::<i64>() {
+                    Ok(integer) => serde_json::Value::from(integer),
+                    Err(_) => text
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .map_or(serde_json::Value::Null, serde_json::Value::Number),
+                },
+                b'L' => match text {
+                    "T" | "t" | "Y" | "y" => serde_json::Value::Bool(true),
+                    "F" | "f" | "N" | "n" => serde_json::Value::Bool(false),
+                    _ => serde_json::Value::Null,
+                },
+                _ => serde_json::Value::String(text.to_owned()),
+            };
+            properties.insert(field.name.clone(), value);
+        }
+
+        Ok(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const OUTLINE: &[(f64, f64)] = &[
+        (0.0, 0.0),
+        (0.0, 10.0),
+        (10.0, 10.0),
+        (10.0, 0.0),
+        (0.0, 0.0),
+    ];
+    const HOLE: &[(f64, f64)] = &[(2.0, 2.0), (4.0, 2.0), (4.0, 4.0), (2.0, 4.0), (2.0, 2.0)];
+
+    fn polygon_shape(shape_type: i32, rings: &[&[(f64, f64)]]) -> Vec<u8> {
+        let point_count: usize = rings.iter().map(|ring| ring.len()).sum();
+        let mut content = shape_type.to_le_bytes().to_vec();
+        content.extend([0u8; 32]);
+        content.extend((rings.len() as i32).to_le_bytes());
+        content.extend((point_count as i32).to_le_bytes());
+        let mut start = 0;
+        for ring in rings {
+            content.extend((start as i32).to_le_bytes());
+            start += ring.len();
+        }
+        for (x, y) in rings.iter().flat_map(|ring| ring.iter()) {
+            content.extend(x.to_le_bytes());
+            content.extend(y.to_le_bytes());
+        }
+        content
+    }
+
+    fn record(number: i32, content: &[u8]) -> Vec<u8> {
+        let mut record = number.to_be_bytes().to_vec();
+        record.extend((content.len() as i32 / 2).to_be_bytes());
+        record.extend(content);
+        record
+    }
+
+    fn write_shapefile(dir: &TempDir, records: &[u8]) -> PathBuf {
+        let mut header = [0u8; 100];
+        header[0..4].copy_from_slice(&9994i32.to_be_bytes());
+        header[24..28].copy_from_slice(&((100 + records.len() as i32) / 2).to_be_bytes());
+        header[28..32].copy_from_slice(&1000i32.to_le_bytes());
+        header[32..36].copy_from_slice(&5i32.to_le_bytes());
+
+        let path = dir.path().join("test.shp");
+        fs::write(&path, [&header[..], records].concat()).unwrap();
+        path
+    }
+
+    /// Writes the `.dbf` next to `path` in UTF-8, fields as name, type and length.
+    fn write_dbf(path: &Path, fields: &[(&str, u8, u8)], records: &[&[&str]]) {
+        write_encoded_dbf(path, fields, records, UTF_8);
+    }
+
+    fn write_encoded_dbf(
+        path: &Path,
+        fields: &[(&str, u8, u8)],
+        records: &[&[&str]],
+        encoding: &'static Encoding,
+    ) {
+        let record_length = 1 + fields.iter().map(|field| field.2 as u16).sum::<u16>();
+        let mut dbf = vec![0u8; 32];
+        dbf[0] = 3;
+        dbf[4..8].copy_from_slice(&(records.len() as u32).to_le_bytes());
+        dbf[8..10].copy_from_slice(&(33 + 32 * fields.len() as u16).to_le_bytes());
+        dbf[10..12].copy_from_slice(&record_length.to_le_bytes());
+        for (name, field_type, length) in fields {
+            let mut descriptor = [0u8; 32];
+            descriptor[..name.len()].copy_from_slice(name.as_bytes());
+            descriptor[11] = *field_type;
+            descriptor[16] = *length;
+            dbf.extend(descriptor);
+        }
+        dbf.push(0x0d);
+        for record in records {
+            dbf.push(b' ');
+            for (value, (_, _, length)) in record.iter().zip(fields) {
+                let mut value = encoding.encode(value).0.into_owned();
+                value.resize(*length as usize, b' ');
+                dbf.extend(value);
+            }
+        }
+        dbf.push(0x1a);
+        fs::write(path.with_extension("dbf"), dbf).unwrap();
+    }
+
+    fn read(path: &Path) -> Vec<Result<Feature>> {
+        ShapefileReader.read(path).unwrap().collect()
+    }
+
+    fn polygons(feature: &Feature) -> MultiPolygon {
+        let geometry = feature.geometry.clone().unwrap();
+        geometry.value.try_into().unwrap()
+    }
+
+    #[test]
+    fn reads_polygons_with_attributes() {
+        let dir = TempDir::new().unwrap();
+        let records = [
+            record(1, &polygon_shape(5, &[OUTLINE, HOLE])),
+            record(2, &0i32.to_le_bytes()),
+        ]
+        .concat();
+        let path = write_shapefile(&dir, &records);
+        write_dbf(
+            &path,
+            &[("NAME", b'C', 10), ("POP", b'N', 8), ("CAPITAL", b'L', 1)],
+            &[&["Mitte", "42", "T"], &["Empty", "", "F"]],
+        );
+
+        let features = read(&path);
+        assert_eq!(features.len(), 2);
+
+        let first = features[0].as_ref().unwrap();
+        let shape = polygons(first);
+        assert_eq!(shape.0.len(), 1);
+        assert_eq!(shape.0[0].interiors().len(), 1);
+        let properties = first.properties.as_ref().unwrap();
+        assert_eq!(properties["NAME"], "Mitte");
+        assert_eq!(properties["POP"], 42);
+        assert_eq!(properties["CAPITAL"], true);
+
+        let second = features[1].as_ref().unwrap();
+        assert!(second.geometry.is_none());
+        let properties = second.properties.as_ref().unwrap();
+        assert_eq!(properties["POP"], serde_json::Value::Null);
+        assert_eq!(properties["CAPITAL"], false);
+    }
+
+    #[test]
+    fn groups_rings_by_winding_order() {
+        let dir = TempDir::new().unwrap();
+        let second_outline: Vec<(f64, f64)> = OUTLINE.iter().map(|(x, y)| (x + 20.0, *y)).collect();
+        let path = write_shapefile(
+            &dir,
+            &record(1, &polygon_shape(15, &[OUTLINE, &second_outline, HOLE])),
+        );
+
+        let features = read(&path);
+        let shape = polygons(features[0].as_ref().unwrap());
+        assert_eq!(shape.0.len(), 2);
+        assert_eq!(shape.0[0].interiors().len(), 1);
+        assert!(shape.0[1].interiors().is_empty());
+        assert!(features[0].as_ref().unwrap().properties.is_none());
+    }
+
+    #[test]
+    fn reports_the_number_of_bad_records() {
+        let dir = TempDir::new().unwrap();
+        let point = [1i32.to_le_bytes(), [0; 4], [0; 4], [0; 4], [0; 4]].concat();
+        let mut truncated = record(5, &polygon_shape(5, &[OUTLINE]));
+        truncated.truncate(truncated.len() - 3);
+        let records = [
+            record(1, &polygon_shape(5, &[OUTLINE])),
+            record(2, &point),
+            record(3, &polygon_shape(5, &[&OUTLINE[..2]])[..40]),
+            record(4, &polygon_shape(5, &[OUTLINE])),
+            truncated,
+        ]
+        .concat();
+        let path = write_shapefile(&dir, &records);
+
+        let features = read(&path);
+        assert_eq!(features.len(), 5);
+        assert!(features[0].is_ok());
+        let error = |index: usize| features[index].as_ref().unwrap_err();
+        assert_eq!(error(1).to_string(), "Invalid shapefile record 2");
+        assert_eq!(
+            format!("{:#}", error(1)),
+            "Invalid shapefile record 2: Unsupported shape type 1"
+        );
+        assert_eq!(error(2).to_string(), "Invalid shapefile record 3");
+        assert!(features[3].is_ok());
+        assert_eq!(error(4).to_string(), "Truncated shapefile record 5");
+    }
+
+    #[test]
+    fn reports_a_truncated_record_header() {
+        let dir = TempDir::new().unwrap();
+        let records = [record(1, &polygon_shape(5, &[OUTLINE])), vec![0, 0, 0, 2]].concat();
+        let path = write_shapefile(&dir, &records);
+
+        let features = read(&path);
+        assert_eq!(features.len(), 2);
+        assert!(features[0].is_ok());
+        assert_eq!(
+            features[1].as_ref().unwrap_err().to_string(),
+            "Truncated shapefile record 2"
+        );
+    }
+
+    #[test]
+    fn reports_missing_attribute_records() {
+        let dir = TempDir::new().unwrap();
+        let records = [
+            record(1, &polygon_shape(5, &[OUTLINE])),
+            record(2, &polygon_shape(5, &[OUTLINE])),
+        ]
+        .concat();
+        let path = write_shapefile(&dir, &records);
+        write_dbf(&path, &[("NAME", b'C', 10)], &[&["Mitte"]]);
+        // Drop the end of file marker, the second record has no attributes
+        let dbf = fs::read(path.with_extension("dbf")).unwrap();
+        fs::write(path.with_extension("dbf"), &dbf[..dbf.len() - 1]).unwrap();
+
+        let features = read(&path);
+        assert!(features[0].is_ok());
+        assert_eq!(
+            features[1].as_ref().unwrap_err().to_string(),
+            "Invalid dbf record 2"
+        );
+    }
+
+    #[test]
+    fn decodes_attributes_with_the_cpg_encoding() {
+        let dir = TempDir::new().unwrap();
+        let path = write_shapefile(&dir, &record(1, &polygon_shape(5, &[OUTLINE])));
+        let encoding = Encoding::for_label(b"windows-1252").unwrap();
+        write_encoded_dbf(&path, &[("NAME", b'C', 10)], &[&["Dürnstein"]], encoding);
+
+        for cpg in ["1252", "ANSI 1252", "windows-1252"] {
+            fs::write(path.with_extension("cpg"), cpg).unwrap();
+            let features = read(&path);
+            let properties = features[0].as_ref().unwrap().properties.clone().unwrap();
+            assert_eq!(properties["NAME"], "Dürnstein", "{}", cpg);
+        }
+
+        fs::write(path.with_extension("cpg"), "88591").unwrap();
+        let features = read(&path);
+        let properties = features[0].as_ref().unwrap().properties.clone().unwrap();
+        assert_eq!(properties["NAME"], "Dürnstein");
+
+        fs::write(path.with_extension("cpg"), "EBCDIC 500").unwrap();
+        assert!(ShapefileReader.read(&path).is_err());
+    }
+
+    #[test]
+    fn rejects_records_in_other_reference_systems() {
+        let dir = TempDir::new().unwrap();
+        let records = [
+            record(1, &polygon_shape(5, &[OUTLINE])),
+            record(2, &polygon_shape(5, &[OUTLINE])),
+        ]
+        .concat();
+        let path = write_shapefile(&dir, &records);
+
+        fs::write(
+            path.with_extension("prj"),
+            r#"GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984",SPHEROID["WGS_1984",6378137.0,298.257223563]],PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]]"#,
+        )
+        .unwrap();
+        assert!(read(&path).iter().all(Result::is_ok));
+
+        fs::write(
+            path.with_extension("prj"),
+            r#"PROJCS["ETRS_1989_UTM_Zone_32N",GEOGCS["GCS_ETRS_1989",DATUM["D_ETRS_1989",SPHEROID["GRS_1980",6378137.0,298.257222101]],PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]],PROJECTION["Transverse_Mercator"],UNIT["Meter",1.0]]"#,
+        )
+        .unwrap();
+        let features = read(&path);
+        for (number, feature) in features.iter().enumerate() {
+            let err = feature.as_ref().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid shapefile record {}", number + 1)
+            );
+            assert_eq!(
+                err.root_cause().to_string(),
+                "Coordinates are in ETRS_1989_UTM_Zone_32N, only WGS 84 longitude and latitude \
+                 are supported"
+            );
+        }
+    }
+}
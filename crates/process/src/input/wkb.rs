@@ -0,0 +1,32 @@
+use anyhow::{Result, bail};
+use geo::{Geometry, MultiPolygon};
+use geozero::{ToGeo, wkb::GpkgWkb};
+
+/// The `srs_id` of a GeoPackage geometry blob, `None` for bytes without a GeoPackage header.
+pub fn gpkg_srs_id(bytes: &[u8]) -> Option<i32> {
+    if !bytes.starts_with(b"GP") {
+        return None;
+    }
+    let flags = *bytes.get(3)?;
+    let srs_id = bytes.get(4..8)?.try_into().ok()?;
+    Some(if flags & 1 == 1 {
+        i32::from_le_bytes(srs_id)
+    } else {
+        i32::from_be_bytes(srs_id)
+    })
+}
+
+/// Reads the Polygon or MultiPolygon of a GeoPackage geometry blob, `None` if it is flagged as
+/// empty. Z and M values are skipped.
+pub fn read_gpkg_geometry(bytes: &[u8]) -> Result<Option<MultiPolygon>> {
+    // geozero reads past the empty flag, an empty blob may hold no WKB at all
+    if bytes.starts_with(b"GP") && bytes.get(3).is_some_and(|flags| flags & 0b0001_0000 != 0) {
+        return Ok(None);
+    }
+
+    match GpkgWkb(bytes).to_geo()? {
+        Geometry::Polygon(polygon) => Ok(Some(MultiPolygon(vec![polygon]))),
+        Geometry::MultiPolygon(polygons) => Ok(Some(polygons)),
+        _ => bail!("Only Polygon and MultiPolygon geometries are supported"),
+    }
+}
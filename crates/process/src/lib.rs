@@ -1,109 +1,773 @@
+mod cell_staging;
+mod compaction;
 mod fill_polygon;
+mod input;
+mod repair;
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use cell_staging::{BATCH_BYTES, CellBatch, CellStaging};
+use compaction::compact_cells;
 use fill_polygon::fill_polygon;
-use geo::{MultiPolygon, Polygon};
-use geojson::{Feature, Value};
+use geo::{ChamberlainDuquetteArea, MultiPolygon, Polygon, Simplify};
+use geojson::{Feature, JsonObject, Value, feature::Id};
 use log::info;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use rocksdb::DB;
-use std::collections::HashMap;
-use util::rocksdb_options;
-use util::{GeohashIndex, GeohashValue, TopodexConfig, UndecidedValue};
+use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
+use repair::repair_feature;
+use rocksdb::WriteBatch;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    sync::{
+        Mutex,
+        atomic::{self, AtomicUsize},
+    },
+};
+use util::{
+    CellOption, CellSystem, CompactShape, CompactValue, FeatureProperties, GeohashIndex,
+    GeohashValue, LayerBuild, LayerCells, Priority, TopodexConfig,
+};
+use util::{
+    feature_cells_layer, open_layered_db, properties_layer, read_layer_build, write_layer_build,
+};
 
+pub use repair::repair_features;
+
+pub use input::{
+    FeatureIter, FeatureReader, FlatGeobufReader, GeoJsonReader, GeoPackageReader, InputFormat,
+    ShapefileReader, read_features,
+};
+
+/// Covers the features with cells of `cells.system`, see `fill_polygon` for how deep cells are
+/// split.
 pub fn extract_topologies(
     features: Vec<Feature>,
-    max_geohash_level: usize,
+    cells: LayerCells,
+    max_cell_vertices: Option<usize>,
     config: &TopodexConfig,
 ) -> Result<Vec<GeohashIndex>> {
-    let geohashes: Vec<GeohashIndex> = features
+    Ok(feature_cells(features, cells, max_cell_vertices, config)
+        .flatten()
+        .collect())
+}
+
+/// The cells of each feature, filled on the rayon workers as they are consumed. The cells hold
+/// the id of their feature. Features without an id or a process value or with a geometry other
+/// than a polygon have no cells.
+pub fn feature_cells(
+    features: Vec<Feature>,
+    cells: LayerCells,
+    max_cell_vertices: Option<usize>,
+    config: &TopodexConfig,
+) -> impl ParallelIterator<Item = Vec<GeohashIndex>> + '_ {
+    features
         .into_par_iter()
-        .map(|feature| {
-            if let Some(geometry) = feature.geometry {
-                let feature_shape_option = match &geometry.value {
-                    Value::MultiPolygon(_) => {
-                        let geo_polygon: MultiPolygon<f64> = MultiPolygon::try_from(geometry)?;
-                        Some(geo_polygon)
-                    }
-                    Value::Polygon(_) => {
-                        let geo: Polygon<f64> = Polygon::try_from(geometry)?;
-                        Some(MultiPolygon(vec![geo]))
-                    }
-                    _ => {
-                        bail!("Unsupported geometry {:?}", &geometry.value);
-                    }
-                };
+        .filter_map(move |feature| fill_feature(feature, cells, max_cell_vertices, config).ok())
+}
 
-                if let (Some(feature_shape), Some(shape_value)) = (
-                    feature_shape_option,
-                    feature.properties.and_then(|properties| {
-                        properties
-                            .get(&config.process_property_name)
-                            .and_then(|property_value| {
-                                property_value
-                                    .as_str()
-                                    .map(|property_value_str| property_value_str.to_owned())
-                            })
-                    }),
-                ) {
-                    return fill_polygon(feature_shape, shape_value, max_geohash_level);
-                }
+fn fill_feature(
+    feature: Feature,
+    cells: LayerCells,
+    max_cell_vertices: Option<usize>,
+    config: &TopodexConfig,
+) -> Result<Vec<GeohashIndex>> {
+    let id = feature_id(&feature);
+    if let Some(geometry) = feature.geometry {
+        let feature_shape_option = match &geometry.value {
+            Value::MultiPolygon(_) => {
+                let geo_polygon: MultiPolygon<f64> = MultiPolygon::try_from(geometry)?;
+                Some(geo_polygon)
             }
-            Ok(Vec::new())
-        })
-        .filter_map(Result::ok)
-        .flatten()
-        .collect();
+            Value::Polygon(_) => {
+                let geo: Polygon<f64> = Polygon::try_from(geometry)?;
+                Some(MultiPolygon(vec![geo]))
+            }
+            _ => {
+                bail!("Unsupported geometry {:?}", &geometry.value);
+            }
+        };
+
+        if let (Some(feature_shape), Some(id), Some(_)) = (
+            feature_shape_option,
+            id,
+            feature_value(&feature.properties, config),
+        ) {
+            return fill_polygon(
+                feature_shape,
+                id,
+                cells.cells(),
+                cells.max_level,
+                max_cell_vertices,
+            );
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Collects the properties of every feature that carries a process value, keyed by the id of
+/// the feature, with its value and its rank by the configured priority.
+pub fn feature_properties(
+    features: &[Feature],
+    config: &TopodexConfig,
+) -> HashMap<String, FeatureProperties> {
+    features
+        .iter()
+        .filter_map(|feature| feature_property(feature, config))
+        .collect()
+}
+
+fn feature_property(
+    feature: &Feature,
+    config: &TopodexConfig,
+) -> Option<(String, FeatureProperties)> {
+    let value = feature_value(&feature.properties, config)?;
+    let id = feature_id(feature)?;
 
-    Ok(geohashes)
+    Some((
+        id.clone(),
+        FeatureProperties {
+            id: Some(id),
+            value: Some(value),
+            properties: feature.properties.clone().unwrap_or_default(),
+            priority: feature_priority(feature, &config.priority),
+        },
+    ))
 }
 
-pub fn save_geohash_index(geohashes: Vec<GeohashIndex>, path: &str) -> Result<()> {
-    let mut map = HashMap::<String, GeohashValue>::new();
+pub fn feature_id(feature: &Feature) -> Option<String> {
+    match feature.id.as_ref()? {
+        Id::String(id) => Some(id.clone()),
+        Id::Number(id) => Some(id.to_string()),
+    }
+}
 
-    for geohash_index in geohashes {
-        match geohash_index {
-            GeohashIndex::DirectValue { hash, value } => {
-                map.insert(hash, GeohashValue::DirectValue { value });
+fn feature_priority(feature: &Feature, priority: &Priority) -> Option<f64> {
+    let area = || {
+        let geometry = feature.geometry.as_ref()?;
+        let shape = match &geometry.value {
+            Value::MultiPolygon(_) => MultiPolygon::try_from(geometry.value.clone()).ok()?,
+            Value::Polygon(_) => {
+                MultiPolygon(vec![Polygon::try_from(geometry.value.clone()).ok()?])
             }
-            GeohashIndex::PartialValue { hash, value, shape } => {
-                if let Some(GeohashValue::Undecided { options }) = map.get_mut(&hash) {
-                    options.push(UndecidedValue { value, shape });
-                } else {
-                    map.insert(
-                        hash,
-                        GeohashValue::Undecided {
-                            options: vec![UndecidedValue { value, shape }],
-                        },
-                    );
-                }
+            _ => return None,
+        };
+        Some(shape.chamberlain_duquette_unsigned_area())
+    };
+
+    match priority {
+        Priority::SmallestArea => area(),
+        Priority::LargestArea => area().map(|area| -area),
+        Priority::Property(key) => {
+            let value = feature.property(key)?;
+            value
+                .as_f64()
+                .or_else(|| value.as_str()?.trim().parse::<f64>().ok())
+                .filter(|value| value.is_finite())
+                .map(|value| -value)
+        }
+    }
+}
+
+/// The process value of a feature, the property named by `process_property_name`.
+pub fn feature_value(properties: &Option<JsonObject>, config: &TopodexConfig) -> Option<String> {
+    properties
+        .as_ref()?
+        .get(&config.process_property_name)?
+        .as_str()
+        .map(|property_value_str| property_value_str.to_owned())
+}
+
+/// Approximate length of a degree, tolerances in meters are converted with it. Degrees of
+/// longitude only get shorter away from the equator, so the actual error stays below it.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Writes the cells of a layer and records how they were built, together with the cells each
+/// feature was filled into so that updates find them again. The shapes of border cells are
+/// simplified with the simplify tolerance of `build` in meters if given.
+///
+/// The cells are staged in a scratch RocksDB next to the index at `{path}.staging` as they
+/// arrive, merged per key and compacted there, then written to the layer in batches. Cells are
+/// only held in memory a batch at a time, the scratch RocksDB stays within `memory_budget` bytes.
+pub fn save_geohash_index(
+    geohashes: impl IntoParallelIterator<Item = Vec<GeohashIndex>>,
+    properties: HashMap<String, FeatureProperties>,
+    path: &str,
+    layer: &str,
+    build: LayerBuild,
+    memory_budget: usize,
+) -> Result<()> {
+    write_layer(geohashes, || properties, path, layer, build, memory_budget)
+}
+
+/// Indexes features as they are read, like `save_geohash_index` does with their cells. Each
+/// feature is repaired and filled on the rayon workers as it arrives, only the properties of
+/// the features are kept until all cells are staged.
+pub fn index_features(
+    features: impl Iterator<Item = Feature> + Send,
+    path: &str,
+    build: LayerBuild,
+    memory_budget: usize,
+    config: &TopodexConfig,
+) -> Result<()> {
+    let properties = Mutex::new(HashMap::new());
+    let geohashes = features.par_bridge().filter_map(|feature| {
+        let feature = repair_feature(feature)?;
+        if let Some((id, feature_properties)) = feature_property(&feature, config) {
+            properties.lock().unwrap().insert(id, feature_properties);
+        }
+        fill_feature(feature, build.cells, build.max_cell_vertices, config).ok()
+    });
+
+    write_layer(
+        geohashes,
+        || std::mem::take(&mut *properties.lock().unwrap()),
+        path,
+        &config.layer,
+        build,
+        memory_budget,
+    )
+}
+
+/// Stages `geohashes`, then writes the layer with the properties of the features, which are
+/// only taken once every cell is staged.
+fn write_layer(
+    geohashes: impl IntoParallelIterator<Item = Vec<GeohashIndex>>,
+    properties: impl FnOnce() -> HashMap<String, FeatureProperties>,
+    path: &str,
+    layer: &str,
+    build: LayerBuild,
+    memory_budget: usize,
+) -> Result<()> {
+    let LayerBuild {
+        cells,
+        max_cell_vertices,
+        simplify_tolerance,
+    } = build;
+    let system = cells.cells();
+    let staging = CellStaging::open(
+        format!("{}.staging", path.trim_end_matches('/')),
+        memory_budget,
+    )?;
+    let db = open_layered_db(path, layer)?;
+
+    let staged = AtomicUsize::new(0);
+    geohashes.into_par_iter().try_for_each(|geohash_indexes| {
+        staged.fetch_add(geohash_indexes.len(), atomic::Ordering::Relaxed);
+        let feature_cells_cf = db
+            .cf_handle(&feature_cells_layer(layer))
+            .with_context(|| format!("Feature cells of layer {} missing in {}", layer, path))?;
+        let mut feature_cells = WriteBatch::default();
+        for (id, hashes) in cells_by_feature(&geohash_indexes) {
+            feature_cells.put_cf(
+                &feature_cells_cf,
+                id.as_bytes(),
+                bitcode::serialize(&hashes).unwrap(),
+            );
+        }
+        db.write_without_wal(feature_cells)?;
+
+        let mut batch = CellBatch::default();
+        for geohash_index in geohash_indexes {
+            let (hash, option) = cell_option(geohash_index, system, simplify_tolerance)?;
+            batch.add(&hash, system.level(&hash)?, &option);
+            if batch.size_in_bytes() >= BATCH_BYTES {
+                staging.write(std::mem::take(&mut batch))?;
             }
         }
+        staging.write(batch)
+    })?;
+    info!("Staged {} cells", staged.into_inner());
+    let properties = properties();
+    compact_cells(
+        &staging,
+        system,
+        build.lookup_level(),
+        max_cell_vertices,
+        &properties,
+    )?;
+
+    let layer_cf = db
+        .cf_handle(layer)
+        .with_context(|| format!("Layer {} missing in {}", layer, path))?;
+    let (mut written, mut shape_bytes, mut overlapping) = (0, 0, 0);
+    let mut batch = WriteBatch::default();
+
+    for cell in staging.cells() {
+        let (hash, options) = cell?;
+        shape_bytes += options
+            .iter()
+            .filter_map(|option| option.shape.as_ref())
+            .map(|shape| shape.len())
+            .sum::<usize>();
+        if options.len() > 1 && options.iter().any(|option| option.shape.is_none()) {
+            overlapping += 1;
+        }
+
+        let Some(value) = cell_value(options, &properties) else {
+            continue;
+        };
+        batch.put_cf(
+            &layer_cf,
+            hash.as_bytes(),
+            bitcode::serialize(&value).unwrap(),
+        );
+        written += 1;
+
+        if batch.size_in_bytes() >= BATCH_BYTES {
+            db.write_without_wal(std::mem::take(&mut batch))?;
+            info!("Wrote {} cells to DB", written);
+        }
     }
+    db.write_without_wal(batch)?;
+    info!(
+        "Encoded border cell shapes in {} bytes, {} cells covered by several features",
+        shape_bytes, overlapping
+    );
 
-    let rocksdb_options = rocksdb_options();
-    let db = DB::open(&rocksdb_options, path)?;
-    let mut map_iterator = map.iter();
-    let mut counter = 0;
+    db.flush_cf(&layer_cf)?;
+    write_layer_build(&db, layer, &build)?;
+    info!(
+        "Wrote {} {} cells down to level {} to DB",
+        written,
+        cells.system,
+        build.lookup_level()
+    );
+
+    let properties_cf = db
+        .cf_handle(&properties_layer(layer))
+        .with_context(|| format!("Properties of layer {} missing in {}", layer, path))?;
     let mut batch = rocksdb::WriteBatch::default();
+    for (id, feature_properties) in properties.iter() {
+        batch.put_cf(
+            &properties_cf,
+            id.as_bytes(),
+            serde_json::to_vec(feature_properties)?,
+        );
+    }
+    db.write_without_wal(batch)?;
+    db.flush_cf(&properties_cf)?;
+    let feature_cells_cf = db
+        .cf_handle(&feature_cells_layer(layer))
+        .with_context(|| format!("Feature cells of layer {} missing in {}", layer, path))?;
+    db.flush_cf(&feature_cells_cf)?;
+    info!("Wrote properties of {} features to DB", properties.len());
 
-    loop {
-        if let Some((hash, value)) = map_iterator.next() {
-            batch.put(hash.as_bytes(), bitcode::serialize(value).unwrap());
-            counter += 1;
-        } else {
-            break;
+    Ok(())
+}
+
+/// Replaces the cells of the `removed` features, given by id, with those of the `added` features
+/// in an existing layer. The added features are filled the way the layer was processed, as
+/// recorded with it. The removed features are looked up in the recorded cells of each feature and
+/// also removed from the ancestors of those cells, where compaction may have moved them. Cells
+/// left without any feature are deleted, as are the properties of features that no longer appear
+/// in `properties`. Added cells are not compacted.
+///
+/// Compaction merges the cells of features sharing a process value under the id of one of them,
+/// so features sharing a value with a changed feature have to be removed and added again too.
+pub fn update_geohash_index(
+    removed: &HashSet<String>,
+    added: Vec<Feature>,
+    properties: HashMap<String, FeatureProperties>,
+    path: &str,
+    config: &TopodexConfig,
+) -> Result<()> {
+    let layer = config.layer.as_str();
+    let db = open_layered_db(path, layer)?;
+    let Some(build) = read_layer_build(&db, layer)? else {
+        bail!(
+            "Layer {} of {} has no record of how it was processed, process it again to update it",
+            layer,
+            path
+        );
+    };
+    let system = build.cells.cells();
+    info!(
+        "Updating layer {} of {} cells down to level {}, at most {:?} vertices per border cell",
+        layer, build.cells.system, build.cells.max_level, build.max_cell_vertices
+    );
+    let added = extract_topologies(added, build.cells, build.max_cell_vertices, config)?;
+
+    let layer_cf = db
+        .cf_handle(layer)
+        .with_context(|| format!("Layer {} missing in {}", layer, path))?;
+    let properties_cf = db
+        .cf_handle(&properties_layer(layer))
+        .with_context(|| format!("Properties of layer {} missing in {}", layer, path))?;
+    let feature_cells_cf = db
+        .cf_handle(&feature_cells_layer(layer))
+        .with_context(|| format!("Feature cells of layer {} missing in {}", layer, path))?;
+
+    let mut removed_cells = Vec::<String>::new();
+    let recorded = db.multi_get_cf(removed.iter().map(|id| (&feature_cells_cf, id.as_bytes())));
+    for cells in recorded {
+        if let Some(cells) = cells? {
+            removed_cells.extend(bitcode::deserialize::<Vec<String>>(&cells)?);
         }
+    }
 
-        if counter > 10000 {
-            db.write_without_wal(batch)?;
-            info!("Wrote 10000 items to DB");
-            counter = 0;
-            batch = rocksdb::WriteBatch::default();
+    // Compaction may have moved the removed features up to the ancestors of their cells
+    let mut hashes: Vec<String> = removed_cells
+        .iter()
+        .flat_map(|hash| system.ancestors(hash))
+        .chain(
+            added
+                .iter()
+                .map(|geohash_index| geohash_index_hash(geohash_index).to_owned()),
+        )
+        .collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    let mut map = HashMap::<String, Vec<CellOption>>::new();
+    let existing = db.multi_get_cf(hashes.iter().map(|hash| (&layer_cf, hash.as_bytes())));
+    for (hash, value) in hashes.iter().zip(existing) {
+        if let Some(value) = value? {
+            let value = bitcode::deserialize::<GeohashValue>(&value)?;
+            let mut options = cell_options(hash, value, system, build.simplify_tolerance)?;
+            options.retain(|option| !removed.contains(&option.value));
+            map.insert(hash.clone(), options);
+        }
+    }
+
+    let mut batch = rocksdb::WriteBatch::default();
+    let added_cells = cells_by_feature(&added);
+    for id in removed {
+        if !added_cells.contains_key(id.as_str()) {
+            batch.delete_cf(&feature_cells_cf, id.as_bytes());
+        }
+    }
+    for (id, cells) in added_cells.iter() {
+        batch.put_cf(
+            &feature_cells_cf,
+            id.as_bytes(),
+            bitcode::serialize(cells).unwrap(),
+        );
+    }
+    let ids: HashSet<String> = removed
+        .iter()
+        .cloned()
+        .chain(added_cells.keys().map(|id| id.to_string()))
+        .collect();
+
+    for geohash_index in added {
+        merge_geohash_index(&mut map, geohash_index, system, build.simplify_tolerance)?;
+    }
+
+    for hash in hashes.iter() {
+        let options = map.remove(hash).unwrap_or_default();
+        match cell_value(options, &properties) {
+            Some(value) => batch.put_cf(
+                &layer_cf,
+                hash.as_bytes(),
+                bitcode::serialize(&value).unwrap(),
+            ),
+            None => batch.delete_cf(&layer_cf, hash.as_bytes()),
         }
     }
+    for id in ids.iter() {
+        match properties.get(id) {
+            Some(feature_properties) => batch.put_cf(
+                &properties_cf,
+                id.as_bytes(),
+                serde_json::to_vec(feature_properties)?,
+            ),
+            None => batch.delete_cf(&properties_cf, id.as_bytes()),
+        }
+    }
+    db.write(batch)?;
+    info!(
+        "Updated {} cells of {} features in DB",
+        hashes.len(),
+        ids.len()
+    );
+
+    Ok(())
+}
 
-    db.flush()?;
+/// The cells filled with each feature, by feature id.
+fn cells_by_feature(geohash_indexes: &[GeohashIndex]) -> HashMap<&str, Vec<&str>> {
+    let mut cells = HashMap::<&str, Vec<&str>>::new();
+    for geohash_index in geohash_indexes {
+        cells
+            .entry(geohash_index_value(geohash_index))
+            .or_default()
+            .push(geohash_index_hash(geohash_index));
+    }
+    cells
+}
 
+fn merge_geohash_index(
+    map: &mut HashMap<String, Vec<CellOption>>,
+    geohash_index: GeohashIndex,
+    system: &dyn CellSystem,
+    simplify_tolerance: Option<f64>,
+) -> Result<()> {
+    let (hash, option) = cell_option(geohash_index, system, simplify_tolerance)?;
+    map.entry(hash).or_default().push(option);
     Ok(())
 }
+
+/// The cell of a filled index with the option it adds to the cell.
+fn cell_option(
+    geohash_index: GeohashIndex,
+    system: &dyn CellSystem,
+    simplify_tolerance: Option<f64>,
+) -> Result<(String, CellOption)> {
+    Ok(match geohash_index {
+        GeohashIndex::DirectValue { hash, value } => (hash, CellOption { value, shape: None }),
+        GeohashIndex::PartialValue { hash, value, shape } => {
+            let shape = compact_shape(&hash, &shape, system, simplify_tolerance)?;
+            (
+                hash,
+                CellOption {
+                    value,
+                    shape: Some(shape),
+                },
+            )
+        }
+    })
+}
+
+/// Values of a stored cell, border cells written by earlier versions are encoded again.
+fn cell_options(
+    hash: &str,
+    value: GeohashValue,
+    system: &dyn CellSystem,
+    simplify_tolerance: Option<f64>,
+) -> Result<Vec<CellOption>> {
+    Ok(match value {
+        GeohashValue::DirectValue { value } => vec![CellOption { value, shape: None }],
+        GeohashValue::Undecided { options } => options
+            .into_iter()
+            .map(|option| {
+                Ok(CellOption {
+                    shape: Some(compact_shape(
+                        hash,
+                        &option.shape,
+                        system,
+                        simplify_tolerance,
+                    )?),
+                    value: option.value,
+                })
+            })
+            .collect::<Result<_>>()?,
+        GeohashValue::UndecidedCompact { options } => options
+            .into_iter()
+            .map(|option| CellOption {
+                value: option.value,
+                shape: Some(option.shape),
+            })
+            .collect(),
+        GeohashValue::Overlapping { options } => options,
+    })
+}
+
+/// Orders the values of a cell by priority and picks the most compact way to store them. The
+/// shapes of a value that also covers all of the cell are dropped. `None` for an empty cell.
+/// Options arrive in no particular order, ties are broken by their shapes so that the stored
+/// value doesn't depend on it.
+fn cell_value(
+    mut options: Vec<CellOption>,
+    properties: &HashMap<String, FeatureProperties>,
+) -> Option<GeohashValue> {
+    options.sort_by(|a, b| {
+        priority_order(&a.value, &b.value, properties).then_with(|| a.shape.cmp(&b.shape))
+    });
+    let mut covering = HashSet::<String>::new();
+    options.retain(|option| match option.shape {
+        None => covering.insert(option.value.clone()),
+        Some(_) => !covering.contains(&option.value),
+    });
+
+    match options.as_slice() {
+        [] => None,
+        [CellOption { shape: None, .. }] => Some(GeohashValue::DirectValue {
+            value: options.pop()?.value,
+        }),
+        _ if options.iter().all(|option| option.shape.is_some()) => {
+            Some(GeohashValue::UndecidedCompact {
+                options: options
+                    .into_iter()
+                    .filter_map(|option| {
+                        Some(CompactValue {
+                            shape: option.shape?,
+                            value: option.value,
+                        })
+                    })
+                    .collect(),
+            })
+        }
+        _ => Some(GeohashValue::Overlapping { options }),
+    }
+}
+
+/// Orders features by their rank, features without a rank last and ties by id.
+pub(crate) fn priority_order(
+    a: &str,
+    b: &str,
+    properties: &HashMap<String, FeatureProperties>,
+) -> Ordering {
+    let rank = |id: &str| properties.get(id).and_then(|feature| feature.priority);
+    match (rank(a), rank(b)) {
+        (Some(rank_a), Some(rank_b)) => rank_a.total_cmp(&rank_b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+    .then_with(|| a.cmp(b))
+}
+
+/// Encodes the part of a feature inside the cell `hash`, simplified first if a tolerance in
+/// meters is given.
+fn compact_shape(
+    hash: &str,
+    shape: &MultiPolygon,
+    system: &dyn CellSystem,
+    simplify_tolerance: Option<f64>,
+) -> Result<CompactShape> {
+    let origin = system.origin(hash)?;
+    Ok(match simplify_tolerance {
+        Some(tolerance) => {
+            CompactShape::encode(&shape.simplify(&(tolerance / METERS_PER_DEGREE)), origin)
+        }
+        None => CompactShape::encode(shape, origin),
+    })
+}
+
+fn geohash_index_hash(geohash_index: &GeohashIndex) -> &str {
+    match geohash_index {
+        GeohashIndex::DirectValue { hash, .. } => hash,
+        GeohashIndex::PartialValue { hash, .. } => hash,
+    }
+}
+
+fn geohash_index_value(geohash_index: &GeohashIndex) -> &str {
+    match geohash_index {
+        GeohashIndex::DirectValue { value, .. } => value,
+        GeohashIndex::PartialValue { value, .. } => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{Rect, coord};
+    use geojson::Geometry;
+    use rocksdb::IteratorMode;
+    use serde_json::json;
+    use tempfile::TempDir;
+    use util::CellSystemKind;
+
+    use super::*;
+
+    fn config() -> TopodexConfig {
+        serde_json::from_value(json!({
+            "extract_properties": [],
+            "process_property_name": "value",
+        }))
+        .unwrap()
+    }
+
+    fn square(id: &str, lon: f64, lat: f64, size: f64) -> Feature {
+        let polygon = Rect::new(
+            coord! {x: lon, y: lat},
+            coord! {x: lon + size, y: lat + size},
+        )
+        .to_polygon();
+        Feature {
+            bbox: None,
+            geometry: Some(Geometry::new(Value::from(&polygon))),
+            id: Some(Id::String(id.to_owned())),
+            properties: json!({ "value": id.to_uppercase() }).as_object().cloned(),
+            foreign_members: None,
+        }
+    }
+
+    /// The ids held by each cell of the layer, by cell. The build records kept next to the
+    /// cells of the default layer are skipped.
+    fn cell_ids(db: &rocksdb::DB, layer: &str) -> HashMap<String, Vec<String>> {
+        let layer_cf = db.cf_handle(layer).unwrap();
+        db.iterator_cf(&layer_cf, IteratorMode::Start)
+            .map(|entry| entry.unwrap())
+            .filter(|(hash, _)| !hash.contains(&b'.'))
+            .map(|(hash, value)| {
+                let ids = match bitcode::deserialize::<GeohashValue>(&value).unwrap() {
+                    GeohashValue::DirectValue { value } => vec![value],
+                    GeohashValue::Undecided { options } => {
+                        options.into_iter().map(|option| option.value).collect()
+                    }
+                    GeohashValue::UndecidedCompact { options } => {
+                        options.into_iter().map(|option| option.value).collect()
+                    }
+                    GeohashValue::Overlapping { options } => {
+                        options.into_iter().map(|option| option.value).collect()
+                    }
+                };
+                (String::from_utf8(hash.to_vec()).unwrap(), ids)
+            })
+            .collect()
+    }
+
+    fn holding<'a>(cells: &'a HashMap<String, Vec<String>>, id: &str) -> Vec<&'a str> {
+        cells
+            .iter()
+            .filter(|(_, ids)| ids.iter().any(|cell_id| cell_id == id))
+            .map(|(hash, _)| hash.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn updates_remove_features_from_compacted_ancestor_cells() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index").to_str().unwrap().to_owned();
+        let config = config();
+        let build = LayerBuild {
+            cells: LayerCells {
+                system: CellSystemKind::Geohash,
+                max_level: 3,
+            },
+            max_cell_vertices: None,
+            simplify_tolerance: None,
+        };
+        let features = vec![square("a", 0.0, 0.0, 12.0), square("b", 30.0, 0.0, 5.0)];
+        let properties = feature_properties(&features, &config);
+        let geohashes = extract_topologies(features, build.cells, None, &config).unwrap();
+        save_geohash_index(
+            vec![geohashes],
+            properties.clone(),
+            &path,
+            &config.layer,
+            build,
+            64 << 20,
+        )
+        .unwrap();
+
+        let db = open_layered_db(&path, &config.layer).unwrap();
+        let cells = cell_ids(&db, &config.layer);
+        assert!(
+            holding(&cells, "a").iter().any(|hash| hash.len() < 3),
+            "Compaction should have moved a up to ancestor cells"
+        );
+        drop(db);
+
+        let mut remaining = properties;
+        remaining.remove("a");
+        update_geohash_index(
+            &HashSet::from(["a".to_owned()]),
+            vec![],
+            remaining,
+            &path,
+            &config,
+        )
+        .unwrap();
+
+        let db = open_layered_db(&path, &config.layer).unwrap();
+        let cells = cell_ids(&db, &config.layer);
+        assert!(holding(&cells, "a").is_empty());
+        assert!(!holding(&cells, "b").is_empty());
+        let feature_cells_cf = db.cf_handle(&feature_cells_layer(&config.layer)).unwrap();
+        assert!(db.get_cf(&feature_cells_cf, b"a").unwrap().is_none());
+        assert!(db.get_cf(&feature_cells_cf, b"b").unwrap().is_some());
+        let properties_cf = db.cf_handle(&properties_layer(&config.layer)).unwrap();
+        assert!(db.get_cf(&properties_cf, b"a").unwrap().is_none());
+        assert!(db.get_cf(&properties_cf, b"b").unwrap().is_some());
+    }
+}
@@ -0,0 +1,251 @@
+use geo::{
+    Area, BooleanOps, ConvexHull, Line, LineString, MultiPolygon, Polygon, RemoveRepeatedPoints,
+    Winding,
+    line_intersection::LineIntersection,
+    orient::{Direction, Orient},
+    sweep::Intersections,
+};
+use geojson::{Feature, Geometry, Value, feature::Id};
+use log::{info, warn};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+/// What was changed on the geometry of a feature.
+#[derive(Default)]
+struct Repairs {
+    repeated_points: bool,
+    degenerate_rings: bool,
+    self_intersections: bool,
+    orientation: bool,
+}
+
+impl Repairs {
+    fn any(&self) -> bool {
+        self.repeated_points || self.degenerate_rings || self.self_intersections || self.orientation
+    }
+
+    fn describe(&self) -> String {
+        [
+            (self.repeated_points, "removed repeated points"),
+            (self.degenerate_rings, "dropped degenerate rings"),
+            (self.self_intersections, "resolved self-intersections"),
+            (self.orientation, "fixed ring orientation"),
+        ]
+        .into_iter()
+        .filter_map(|(repaired, description)| repaired.then_some(description))
+        .collect::<Vec<&str>>()
+        .join(", ")
+    }
+}
+
+/// Makes the polygons of all features valid before they are indexed. Repeated points and rings
+/// without area are removed, self-intersecting or overlapping rings are resolved and rings are
+/// wound as RFC 7946 asks, outlines counter-clockwise and holes clockwise. Features left
+/// without any area are dropped, every repair is logged.
+pub fn repair_features(features: Vec<Feature>) -> Vec<Feature> {
+    let features_count = features.len();
+    let repaired: Vec<(Feature, bool)> = features.into_par_iter().filter_map(repair).collect();
+
+    let repaired_count = repaired.iter().filter(|(_, repaired)| *repaired).count();
+    info!(
+        "Repaired {} features, dropped {} without area",
+        repaired_count,
+        features_count - repaired.len()
+    );
+    repaired.into_iter().map(|(feature, _)| feature).collect()
+}
+
+/// Makes the polygons of a single feature valid like `repair_features`, `None` if no area is
+/// left.
+pub fn repair_feature(feature: Feature) -> Option<Feature> {
+    repair(feature).map(|(feature, _)| feature)
+}
+
+/// The valid feature and whether it had to be repaired.
+fn repair(feature: Feature) -> Option<(Feature, bool)> {
+    let Some(shape) = feature_shape(&feature) else {
+        return Some((feature, false));
+    };
+
+    let (shape, repairs) = repair_shape(shape);
+    let feature_name = match &feature.id {
+        Some(Id::String(id)) => id.clone(),
+        Some(Id::Number(id)) => id.to_string(),
+        None => "without id".to_owned(),
+    };
+    if shape.0.is_empty() {
+        warn!(
+            "Feature {} has no area left after repair, skipping",
+            feature_name
+        );
+        return None;
+    }
+    if !repairs.any() {
+        return Some((feature, false));
+    }
+
+    warn!("Repaired feature {}: {}", feature_name, repairs.describe());
+    Some((
+        Feature {
+            geometry: Some(Geometry::new(Value::from(&shape))),
+            ..feature
+        },
+        true,
+    ))
+}
+
+fn feature_shape(feature: &Feature) -> Option<MultiPolygon> {
+    let geometry = feature.geometry.as_ref()?;
+    match &geometry.value {
+        Value::MultiPolygon(_) => MultiPolygon::try_from(geometry.value.clone()).ok(),
+        Value::Polygon(_) => Polygon::try_from(geometry.value.clone())
+            .ok()
+            .map(|polygon| MultiPolygon(vec![polygon])),
+        _ => None,
+    }
+}
+
+fn repair_shape(shape: MultiPolygon) -> (MultiPolygon, Repairs) {
+    let mut repairs = Repairs::default();
+
+    let deduplicated = shape.remove_repeated_points();
+    repairs.repeated_points = deduplicated != shape;
+
+    let mut polygons = Vec::with_capacity(deduplicated.0.len());
+    for polygon in deduplicated {
+        let (exterior, interiors) = polygon.into_inner();
+        if !has_area(&exterior) {
+            repairs.degenerate_rings = true;
+            continue;
+        }
+        let interiors_count = interiors.len();
+        let interiors: Vec<LineString> = interiors.into_iter().filter(has_area).collect();
+        repairs.degenerate_rings |= interiors.len() != interiors_count;
+        polygons.push(Polygon::new(exterior, interiors));
+    }
+    let mut shape = MultiPolygon(polygons);
+
+    if has_self_intersections(&shape) {
+        repairs.self_intersections = true;
+        shape = make_valid(shape);
+    }
+
+    let oriented = shape.0.iter().all(|polygon| {
+        polygon.exterior().is_ccw() && polygon.interiors().iter().all(|ring| ring.is_cw())
+    });
+    if !oriented {
+        repairs.orientation = true;
+        shape = shape.orient(Direction::Default);
+    }
+
+    (shape, repairs)
+}
+
+/// Whether a ring encloses any area. The hull is checked as the signed areas of a
+/// self-intersecting ring may cancel out.
+fn has_area(ring: &LineString) -> bool {
+    ring.0.len() >= 4 && ring.convex_hull().unsigned_area() > 0.0
+}
+
+/// Whether any two ring segments cross or overlap. Segments touching in a vertex are allowed.
+fn has_self_intersections(shape: &MultiPolygon) -> bool {
+    let segments: Vec<Line> = shape
+        .0
+        .iter()
+        .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()))
+        .flat_map(|ring| ring.lines())
+        .collect();
+
+    Intersections::from_iter(segments).any(|(_, _, intersection)| match intersection {
+        LineIntersection::SinglePoint { is_proper, .. } => is_proper,
+        LineIntersection::Collinear { .. } => true,
+    })
+}
+
+/// Rebuilds the polygons with the overlay of `BooleanOps`, which splits crossing rings and
+/// removes spikes. The polygons are merged one by one so that overlapping outlines are united
+/// instead of cancelling each other out.
+fn make_valid(shape: MultiPolygon) -> MultiPolygon {
+    let empty = MultiPolygon::<f64>(vec![]);
+    shape
+        .0
+        .into_iter()
+        .map(|polygon| MultiPolygon(vec![polygon]).union(&empty))
+        .reduce(|merged, polygons| merged.union(&polygons))
+        .unwrap_or(empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::polygon;
+
+    use super::*;
+
+    fn feature(polygon: Polygon) -> Feature {
+        Feature {
+            bbox: None,
+            geometry: Some(Geometry::new(Value::from(&polygon))),
+            id: Some(Id::String("1".to_owned())),
+            properties: None,
+            foreign_members: None,
+        }
+    }
+
+    fn square() -> Polygon {
+        polygon![(x: 0.0, y: 0.0), (x: 4.0, y: 0.0), (x: 4.0, y: 4.0), (x: 0.0, y: 4.0)]
+    }
+
+    #[test]
+    fn valid_features_are_kept_as_they_are() {
+        let (repaired, changed) = repair(feature(square())).unwrap();
+
+        assert!(!changed);
+        assert_eq!(feature_shape(&repaired), Some(MultiPolygon(vec![square()])));
+    }
+
+    #[test]
+    fn bow_ties_are_split_into_their_loops() {
+        let bow_tie =
+            polygon![(x: 0.0, y: 0.0), (x: 2.0, y: 2.0), (x: 2.0, y: 0.0), (x: 0.0, y: 2.0)];
+
+        let (shape, repairs) = repair_shape(MultiPolygon(vec![bow_tie]));
+
+        assert!(repairs.self_intersections);
+        assert!(!has_self_intersections(&shape));
+        assert_eq!(shape.0.len(), 2);
+        assert!((shape.unsigned_area() - 2.0).abs() < 1e-9);
+        assert!(shape.0.iter().all(|polygon| polygon.exterior().is_ccw()));
+    }
+
+    #[test]
+    fn features_of_two_point_rings_are_dropped() {
+        let two_points = polygon![(x: 0.0, y: 0.0), (x: 1.0, y: 1.0)];
+
+        assert!(repair_feature(feature(two_points)).is_none());
+    }
+
+    #[test]
+    fn two_point_holes_are_dropped() {
+        let with_hole = Polygon::new(
+            square().exterior().clone(),
+            vec![LineString::from(vec![(1.0, 1.0), (2.0, 2.0), (1.0, 1.0)])],
+        );
+
+        let (shape, repairs) = repair_shape(MultiPolygon(vec![with_hole]));
+
+        assert!(repairs.degenerate_rings);
+        assert_eq!(shape, MultiPolygon(vec![square()]));
+    }
+
+    #[test]
+    fn clockwise_exteriors_are_reversed() {
+        let mut clockwise = square();
+        clockwise.exterior_mut(|exterior| exterior.make_cw_winding());
+
+        let (repaired, changed) = repair(feature(clockwise)).unwrap();
+
+        assert!(changed);
+        let shape = feature_shape(&repaired).unwrap();
+        assert!(shape.0[0].exterior().is_ccw());
+        assert_eq!(shape.unsigned_area(), 16.0);
+    }
+}
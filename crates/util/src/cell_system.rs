@@ -0,0 +1,123 @@
+mod geohash_cells;
+mod h3_cells;
+mod quadkey_cells;
+mod s2_cells;
+mod sphere;
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use geo::{Coord, MultiPolygon};
+use serde::{Deserialize, Serialize};
+
+pub use geohash_cells::GeohashCells;
+pub use h3_cells::H3Cells;
+pub use quadkey_cells::QuadkeyCells;
+pub use s2_cells::S2Cells;
+
+/// A hierarchy of cells covering the world, identified by strings. The cells of a level are
+/// split into the cells of the next level, down to the level a lookup encodes its location at.
+/// A lookup then checks that cell and all its ancestors.
+pub trait CellSystem: Send + Sync {
+    /// Cells of the coarsest level, together they cover the world.
+    fn roots(&self) -> Vec<String>;
+
+    fn children(&self, cell: &str) -> Result<Vec<String>>;
+
+    /// `None` for a root cell.
+    fn parent(&self, cell: &str) -> Option<String>;
+
+    fn level(&self, cell: &str) -> Result<usize>;
+
+    /// Deepest level there are cells of.
+    fn max_level(&self) -> usize;
+
+    /// Area in lon/lat covering all locations that are looked up through `cell`. Cells crossing
+    /// the antimeridian are split along it.
+    fn shape(&self, cell: &str) -> Result<MultiPolygon>;
+
+    /// Reference point of a cell, the shapes of border cells are stored relative to it.
+    fn origin(&self, cell: &str) -> Result<Coord>;
+
+    /// The cell of `level` holding `coord`.
+    fn encode(&self, coord: Coord, level: usize) -> Result<String>;
+
+    /// The cell and all its ancestors, from the root down.
+    fn ancestors(&self, cell: &str) -> Vec<String> {
+        let mut ancestors: Vec<String> =
+            std::iter::successors(Some(cell.to_owned()), |cell| self.parent(cell)).collect();
+        ancestors.reverse();
+        ancestors
+    }
+}
+
+/// The cell systems an index can be built with.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CellSystemKind {
+    /// Geohashes of one character per level, cells shrink to slivers towards the poles
+    #[default]
+    Geohash,
+    /// Web Mercator tiles addressed by their quadkey, the level is the zoom. Locations beyond
+    /// 85.05° of latitude fall into the tiles at the edge of the map
+    Quadkey,
+    /// S2 cells addressed by the base 4 digits of their id, of about even size everywhere
+    S2,
+    /// H3 hexagons addressed by their hexadecimal index, the level is the resolution
+    H3,
+}
+
+impl CellSystemKind {
+    pub fn cells(&self) -> &'static dyn CellSystem {
+        match self {
+            CellSystemKind::Geohash => &GeohashCells,
+            CellSystemKind::Quadkey => &QuadkeyCells,
+            CellSystemKind::S2 => &S2Cells,
+            CellSystemKind::H3 => &H3Cells,
+        }
+    }
+}
+
+impl FromStr for CellSystemKind {
+    type Err = anyhow::Error;
+
+    fn from_str(system: &str) -> Result<Self> {
+        match system.to_ascii_lowercase().as_str() {
+            "geohash" => Ok(CellSystemKind::Geohash),
+            "quadkey" => Ok(CellSystemKind::Quadkey),
+            "s2" => Ok(CellSystemKind::S2),
+            "h3" => Ok(CellSystemKind::H3),
+            _ => bail!(
+                "Unknown cell system {}, expected geohash, quadkey, s2 or h3",
+                system
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CellSystemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CellSystemKind::Geohash => "geohash",
+            CellSystemKind::Quadkey => "quadkey",
+            CellSystemKind::S2 => "s2",
+            CellSystemKind::H3 => "h3",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How the cells of a layer were built, recorded with the layer so lookups encode locations the
+/// same way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerCells {
+    pub system: CellSystemKind,
+    /// Deepest level of the cells, lookups encode locations at this level
+    pub max_level: usize,
+}
+
+impl LayerCells {
+    pub fn cells(&self) -> &'static dyn CellSystem {
+        self.system.cells()
+    }
+}
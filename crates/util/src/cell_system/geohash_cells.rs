@@ -0,0 +1,50 @@
+use anyhow::{bail, Result};
+use geo::{Coord, MultiPolygon};
+use geohash::{decode_bbox, encode};
+
+use super::CellSystem;
+
+/// Characters appended to a geohash for its children.
+const BASE32: &str = "0123456789bcdefghjkmnpqrstuvwxyz";
+
+const MAX_LEVEL: usize = 12;
+
+/// Geohashes, the level of a cell is the length of its hash.
+pub struct GeohashCells;
+
+impl CellSystem for GeohashCells {
+    fn roots(&self) -> Vec<String> {
+        BASE32.chars().map(|c| c.to_string()).collect()
+    }
+
+    fn children(&self, cell: &str) -> Result<Vec<String>> {
+        if cell.len() >= MAX_LEVEL {
+            bail!("Geohash {} has no children", cell);
+        }
+        Ok(BASE32.chars().map(|c| format!("{}{}", cell, c)).collect())
+    }
+
+    fn parent(&self, cell: &str) -> Option<String> {
+        (cell.len() > 1).then(|| cell[..cell.len() - 1].to_owned())
+    }
+
+    fn level(&self, cell: &str) -> Result<usize> {
+        Ok(cell.len())
+    }
+
+    fn max_level(&self) -> usize {
+        MAX_LEVEL
+    }
+
+    fn shape(&self, cell: &str) -> Result<MultiPolygon> {
+        Ok(MultiPolygon(vec![decode_bbox(cell)?.to_polygon()]))
+    }
+
+    fn origin(&self, cell: &str) -> Result<Coord> {
+        Ok(decode_bbox(cell)?.min())
+    }
+
+    fn encode(&self, coord: Coord, level: usize) -> Result<String> {
+        Ok(encode(coord, level)?)
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use geo::{Coord, MultiPolygon};
+use h3o::{CellIndex, LatLng, Resolution};
+
+use super::{
+    sphere::{normalize, ring_shape, to_point},
+    CellSystem,
+};
+
+/// How far the shape of a cell reaches beyond its hexagon, relative to the distance of its
+/// vertices from the center. The children of an H3 cell don't tile it exactly, they stick out
+/// of their parent by up to 0.16 times, as do their own children out of them. Each level is
+/// √7 times smaller, so descendants at any depth stay within 1 + 0.16 / (1 - 1 / √7), about
+/// 1.26 times the hexagon. The rest is margin for the edges being drawn straight in lon/lat.
+const DESCENDANTS_REACH: f64 = 1.75;
+
+/// Bound of the reach of the descendants of a cell, see `DESCENDANTS_REACH`.
+#[cfg(test)]
+const DESCENDANTS_BOUND: f64 = 1.26;
+
+/// H3 cells, addressed by their index in hexadecimal. The level of a cell is its resolution.
+pub struct H3Cells;
+
+impl CellSystem for H3Cells {
+    fn roots(&self) -> Vec<String> {
+        CellIndex::base_cells()
+            .map(|cell| cell.to_string())
+            .collect()
+    }
+
+    fn children(&self, cell: &str) -> Result<Vec<String>> {
+        let cell = parse(cell)?;
+        let resolution = cell
+            .resolution()
+            .succ()
+            .with_context(|| format!("H3 cell {} has no children", cell))?;
+        Ok(cell
+            .children(resolution)
+            .map(|child| child.to_string())
+            .collect())
+    }
+
+    fn parent(&self, cell: &str) -> Option<String> {
+        let cell = parse(cell).ok()?;
+        Some(cell.parent(cell.resolution().pred()?)?.to_string())
+    }
+
+    fn level(&self, cell: &str) -> Result<usize> {
+        Ok(u8::from(parse(cell)?.resolution()) as usize)
+    }
+
+    fn max_level(&self) -> usize {
+        u8::from(Resolution::Fifteen) as usize
+    }
+
+    /// The hexagon of the cell grown around its center so that it covers the hexagons of all
+    /// descendants, which are the locations looked up through the cell.
+    fn shape(&self, cell: &str) -> Result<MultiPolygon> {
+        Ok(grown_shape(parse(cell)?, DESCENDANTS_REACH))
+    }
+
+    fn origin(&self, cell: &str) -> Result<Coord> {
+        Ok(lat_lng_coord(LatLng::from(parse(cell)?)))
+    }
+
+    fn encode(&self, coord: Coord, level: usize) -> Result<String> {
+        let resolution = Resolution::try_from(level as u8)?;
+        Ok(LatLng::new(coord.y, coord.x)?
+            .to_cell(resolution)
+            .to_string())
+    }
+}
+
+/// The hexagon of `cell` with its vertices moved `reach` times as far from its center.
+fn grown_shape(cell: CellIndex, reach: f64) -> MultiPolygon {
+    let center = to_point(lat_lng_coord(LatLng::from(cell)));
+    let vertices: Vec<_> = cell
+        .boundary()
+        .iter()
+        .map(|vertex| {
+            let vertex = to_point(lat_lng_coord(*vertex));
+            normalize([0, 1, 2].map(|axis| center[axis] + reach * (vertex[axis] - center[axis])))
+        })
+        .collect();
+    ring_shape(&vertices)
+}
+
+fn parse(cell: &str) -> Result<CellIndex> {
+    cell.parse::<CellIndex>()
+        .with_context(|| format!("Invalid H3 cell {}", cell))
+}
+
+fn lat_lng_coord(lat_lng: LatLng) -> Coord {
+    Coord {
+        x: lat_lng.lng(),
+        y: lat_lng.lat(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{Intersects, Point};
+
+    use super::*;
+
+    #[test]
+    fn descendants_stay_inside_the_shape_of_their_ancestor() {
+        // Cells around the antimeridian and the poles have shapes split along it
+        let places = [
+            (13.4, 52.5),
+            (-58.4, -34.6),
+            (151.2, -33.9),
+            (-122.4, 37.8),
+            (77.2, 28.6),
+            (179.99, -16.5),
+            (-179.99, 65.0),
+            (180.0, 0.0),
+            (179.9, -89.9),
+        ];
+        let mut split = 0;
+        for level in [0, 2, 5, 8, 11] {
+            let resolution = Resolution::try_from(level as u8).unwrap();
+            let cells = places
+                .iter()
+                .map(|&(x, y)| parse(&H3Cells.encode(Coord { x, y }, level).unwrap()).unwrap())
+                .chain(resolution.pentagons());
+            for cell in cells {
+                let shape = H3Cells.shape(&cell.to_string()).unwrap();
+                let bound = grown_shape(cell, DESCENDANTS_BOUND);
+                if shape.0.len() > 1 {
+                    split += 1;
+                }
+                let descendants = cell.children(Resolution::try_from(level as u8 + 3).unwrap());
+                for descendant in descendants {
+                    for vertex in descendant.boundary().iter() {
+                        let vertex = Point::from(lat_lng_coord(*vertex));
+                        assert!(shape.intersects(&vertex), "{} outside {}", descendant, cell);
+                        assert!(bound.intersects(&vertex), "{} beyond {}", descendant, cell);
+                    }
+                }
+            }
+        }
+        assert!(split > 0);
+    }
+}
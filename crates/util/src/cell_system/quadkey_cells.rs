@@ -0,0 +1,108 @@
+use std::f64::consts::PI;
+
+use anyhow::{bail, Result};
+use geo::{Coord, MultiPolygon, Rect};
+
+use super::CellSystem;
+
+const MAX_LEVEL: usize = 30;
+
+/// Latitude of the upper edge of the Web Mercator map.
+const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// Web Mercator tiles addressed by their quadkey, one digit per zoom level.
+pub struct QuadkeyCells;
+
+impl CellSystem for QuadkeyCells {
+    fn roots(&self) -> Vec<String> {
+        ["0", "1", "2", "3"].map(str::to_owned).to_vec()
+    }
+
+    fn children(&self, cell: &str) -> Result<Vec<String>> {
+        if tile(cell)?.0 >= MAX_LEVEL {
+            bail!("Quadkey {} has no children", cell);
+        }
+        Ok((0..4).map(|digit| format!("{}{}", cell, digit)).collect())
+    }
+
+    fn parent(&self, cell: &str) -> Option<String> {
+        (cell.len() > 1).then(|| cell[..cell.len() - 1].to_owned())
+    }
+
+    fn level(&self, cell: &str) -> Result<usize> {
+        Ok(tile(cell)?.0)
+    }
+
+    fn max_level(&self) -> usize {
+        MAX_LEVEL
+    }
+
+    fn shape(&self, cell: &str) -> Result<MultiPolygon> {
+        Ok(MultiPolygon(vec![bbox(cell)?.to_polygon()]))
+    }
+
+    fn origin(&self, cell: &str) -> Result<Coord> {
+        Ok(bbox(cell)?.min())
+    }
+
+    fn encode(&self, coord: Coord, level: usize) -> Result<String> {
+        if level == 0 || level > MAX_LEVEL {
+            bail!("Invalid quadkey level {}", level);
+        }
+        let tiles = (1u64 << level) as f64;
+        let lat = coord.y.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+        let x = ((coord.x + 180.0) / 360.0 * tiles)
+            .floor()
+            .clamp(0.0, tiles - 1.0) as u64;
+        let y = ((1.0 - lat.tan().asinh() / PI) / 2.0 * tiles)
+            .floor()
+            .clamp(0.0, tiles - 1.0) as u64;
+
+        Ok((0..level)
+            .rev()
+            .map(|bit| {
+                let digit = ((x >> bit) & 1) | (((y >> bit) & 1) << 1);
+                char::from(b'0' + digit as u8)
+            })
+            .collect())
+    }
+}
+
+/// Zoom and x, y of the tile of a quadkey.
+fn tile(cell: &str) -> Result<(usize, u64, u64)> {
+    if cell.is_empty() || cell.len() > MAX_LEVEL {
+        bail!("Invalid quadkey {}", cell);
+    }
+    let (mut x, mut y) = (0, 0);
+    for c in cell.chars() {
+        let Some(digit) = c.to_digit(4) else {
+            bail!("Invalid quadkey {}", cell);
+        };
+        x = x << 1 | (digit & 1) as u64;
+        y = y << 1 | (digit >> 1) as u64;
+    }
+    Ok((cell.len(), x, y))
+}
+
+fn bbox(cell: &str) -> Result<Rect> {
+    let (zoom, x, y) = tile(cell)?;
+    let tiles = (1u64 << zoom) as f64;
+    let lon = |x: u64| x as f64 / tiles * 360.0 - 180.0;
+    let lat = |y: u64| {
+        (PI * (1.0 - 2.0 * y as f64 / tiles))
+            .sinh()
+            .atan()
+            .to_degrees()
+    };
+
+    Ok(Rect::new(
+        Coord {
+            x: lon(x),
+            y: lat(y + 1),
+        },
+        Coord {
+            x: lon(x + 1),
+            y: lat(y),
+        },
+    ))
+}
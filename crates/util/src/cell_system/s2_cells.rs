@@ -0,0 +1,244 @@
+use anyhow::{bail, Result};
+use geo::{Coord, MultiPolygon};
+
+use super::{
+    sphere::{normalize, ring_shape, to_coord, to_point, Point},
+    CellSystem,
+};
+
+const MAX_LEVEL: usize = 30;
+
+const SWAP_MASK: u8 = 1;
+const INVERT_MASK: u8 = 2;
+
+/// Quadrant `(i << 1) | j` of a child by its position along the Hilbert curve, per orientation.
+const POS_TO_IJ: [[u8; 4]; 4] = [[0, 1, 3, 2], [0, 2, 3, 1], [3, 2, 0, 1], [3, 1, 0, 2]];
+const IJ_TO_POS: [[u8; 4]; 4] = [[0, 1, 3, 2], [0, 3, 1, 2], [2, 3, 1, 0], [2, 1, 3, 0]];
+/// How the orientation of a child differs from its parent, by its position.
+const POS_TO_ORIENTATION: [u8; 4] = [SWAP_MASK, 0, 0, INVERT_MASK | SWAP_MASK];
+
+/// S2 cells, written as the face of the cube followed by the position of the cell along the
+/// Hilbert curve at every level, which are the base 4 digits of its S2 cell id. The level of a
+/// cell is the number of digits after the face.
+pub struct S2Cells;
+
+impl CellSystem for S2Cells {
+    fn roots(&self) -> Vec<String> {
+        (0..6).map(|face| face.to_string()).collect()
+    }
+
+    fn children(&self, cell: &str) -> Result<Vec<String>> {
+        if S2Cell::parse(cell)?.level >= MAX_LEVEL {
+            bail!("S2 cell {} has no children", cell);
+        }
+        Ok((0..4).map(|pos| format!("{}{}", cell, pos)).collect())
+    }
+
+    fn parent(&self, cell: &str) -> Option<String> {
+        (cell.len() > 1).then(|| cell[..cell.len() - 1].to_owned())
+    }
+
+    fn level(&self, cell: &str) -> Result<usize> {
+        Ok(S2Cell::parse(cell)?.level)
+    }
+
+    fn max_level(&self) -> usize {
+        MAX_LEVEL
+    }
+
+    fn shape(&self, cell: &str) -> Result<MultiPolygon> {
+        let cell = S2Cell::parse(cell)?;
+        let corners =
+            [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].map(|(di, dj)| cell.point(di, dj));
+        Ok(ring_shape(&corners))
+    }
+
+    fn origin(&self, cell: &str) -> Result<Coord> {
+        let cell = S2Cell::parse(cell)?;
+        Ok(to_coord(cell.point(0.5, 0.5)))
+    }
+
+    fn encode(&self, coord: Coord, level: usize) -> Result<String> {
+        if level > MAX_LEVEL {
+            bail!("Invalid S2 level {}", level);
+        }
+        let point = to_point(coord);
+        let (face, u, v) = face_uv(point);
+        let cells = (1u64 << level) as f64;
+        let i = (uv_to_st(u) * cells).floor().clamp(0.0, cells - 1.0) as u64;
+        let j = (uv_to_st(v) * cells).floor().clamp(0.0, cells - 1.0) as u64;
+
+        let mut cell = face.to_string();
+        let mut orientation = face as u8 & SWAP_MASK;
+        for bit in (0..level).rev() {
+            let ij = (((i >> bit) & 1) << 1 | ((j >> bit) & 1)) as usize;
+            let pos = IJ_TO_POS[orientation as usize][ij];
+            cell.push(char::from(b'0' + pos));
+            orientation ^= POS_TO_ORIENTATION[pos as usize];
+        }
+        Ok(cell)
+    }
+}
+
+struct S2Cell {
+    face: usize,
+    level: usize,
+    /// Position of the cell on its face, in cells of its level
+    i: u64,
+    j: u64,
+}
+
+impl S2Cell {
+    fn parse(cell: &str) -> Result<Self> {
+        let mut digits = cell.chars().map(|c| c.to_digit(10));
+        let Some(Some(face @ 0..=5)) = digits.next() else {
+            bail!("Invalid S2 cell {}", cell);
+        };
+        let mut parsed = S2Cell {
+            face: face as usize,
+            level: 0,
+            i: 0,
+            j: 0,
+        };
+        let mut orientation = face as u8 & SWAP_MASK;
+        for digit in digits {
+            let Some(pos @ 0..=3) = digit else {
+                bail!("Invalid S2 cell {}", cell);
+            };
+            let ij = POS_TO_IJ[orientation as usize][pos as usize];
+            parsed.i = parsed.i << 1 | (ij >> 1) as u64;
+            parsed.j = parsed.j << 1 | (ij & 1) as u64;
+            orientation ^= POS_TO_ORIENTATION[pos as usize];
+            parsed.level += 1;
+        }
+        if parsed.level > MAX_LEVEL {
+            bail!("Invalid S2 cell {}", cell);
+        }
+        Ok(parsed)
+    }
+
+    /// Point at `di`, `dj` within the cell, from 0 at its first corner to 1 at the opposite one.
+    fn point(&self, di: f64, dj: f64) -> Point {
+        let cells = (1u64 << self.level) as f64;
+        face_uv_to_point(
+            self.face,
+            st_to_uv((self.i as f64 + di) / cells),
+            st_to_uv((self.j as f64 + dj) / cells),
+        )
+    }
+}
+
+/// Face of the cube a point projects onto and its u, v coordinates on that face.
+fn face_uv(point: Point) -> (usize, f64, f64) {
+    let [x, y, z] = point;
+    let axis = if x.abs() >= y.abs() && x.abs() >= z.abs() {
+        0
+    } else if y.abs() >= z.abs() {
+        1
+    } else {
+        2
+    };
+    let face = if point[axis] < 0.0 { axis + 3 } else { axis };
+    let (u, v) = match face {
+        0 => (y / x, z / x),
+        1 => (-x / y, z / y),
+        2 => (-x / z, -y / z),
+        3 => (z / x, y / x),
+        4 => (z / y, -x / y),
+        _ => (-y / z, -x / z),
+    };
+    (face, u, v)
+}
+
+fn face_uv_to_point(face: usize, u: f64, v: f64) -> Point {
+    normalize(match face {
+        0 => [1.0, u, v],
+        1 => [-u, 1.0, v],
+        2 => [-u, -v, 1.0],
+        3 => [-1.0, -v, -u],
+        4 => [v, -1.0, -u],
+        _ => [v, u, -1.0],
+    })
+}
+
+/// The quadratic projection of S2, which keeps the cells of a level at about the same size.
+fn uv_to_st(u: f64) -> f64 {
+    if u >= 0.0 {
+        0.5 * (1.0 + 3.0 * u).sqrt()
+    } else {
+        1.0 - 0.5 * (1.0 - 3.0 * u).sqrt()
+    }
+}
+
+fn st_to_uv(s: f64) -> f64 {
+    if s >= 0.5 {
+        (4.0 * s * s - 1.0) / 3.0
+    } else {
+        (1.0 - 4.0 * (1.0 - s) * (1.0 - s)) / 3.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The S2 cell id of a cell, its face, the positions of its levels and a trailing 1 bit
+    fn cell_id(cell: &str) -> u64 {
+        let mut id = (cell[..1].parse::<u64>().unwrap() << 61) | 1 << (60 - 2 * (cell.len() - 1));
+        for (level, pos) in cell[1..].chars().enumerate() {
+            id |= (pos.to_digit(4).unwrap() as u64) << (59 - 2 * level);
+        }
+        id
+    }
+
+    fn leaf_id(lon: f64, lat: f64) -> u64 {
+        cell_id(&S2Cells.encode(Coord { x: lon, y: lat }, MAX_LEVEL).unwrap())
+    }
+
+    #[test]
+    fn face_centers_encode_to_known_cell_ids() {
+        assert_eq!(leaf_id(0.0, 0.0), 0x1000000000000001);
+        assert_eq!(leaf_id(90.0, 0.0), 0x3000000000000001);
+        assert_eq!(leaf_id(0.0, 90.0), 0x5000000000000001);
+        assert_eq!(leaf_id(0.0, -90.0), 0xb000000000000001);
+    }
+
+    #[test]
+    fn places_encode_into_known_cells() {
+        for (lon, lat, token) in [
+            (-74.0060, 40.7128, "89c25a"),
+            (-122.4194, 37.7749, "8085"),
+            (-0.1278, 51.5074, "487604"),
+            (139.6503, 35.6762, "6018f"),
+        ] {
+            let id = format!("{:016x}", leaf_id(lon, lat));
+            assert!(id.starts_with(token), "{} not in {}", id, token);
+        }
+    }
+
+    #[test]
+    fn consecutive_cells_along_the_curve_are_neighbours() {
+        for face in S2Cells.roots() {
+            let mut cells = vec![face];
+            for _ in 0..3 {
+                cells = cells
+                    .iter()
+                    .flat_map(|cell| S2Cells.children(cell).unwrap())
+                    .collect();
+            }
+            for pair in cells.windows(2) {
+                let [a, b] = [&pair[0], &pair[1]].map(|cell| S2Cell::parse(cell).unwrap());
+                assert_eq!(a.i.abs_diff(b.i) + a.j.abs_diff(b.j), 1, "{:?}", pair);
+            }
+        }
+    }
+
+    #[test]
+    fn cells_encode_from_their_origin() {
+        for cell in ["0", "13", "2012", "30213", "4133", "5021302103"] {
+            let origin = S2Cells.origin(cell).unwrap();
+            let level = S2Cells.level(cell).unwrap();
+            assert_eq!(S2Cells.encode(origin, level).unwrap(), cell);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::f64::consts::PI;
+
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+
+/// Longest arc between two points of a densified ring, in radians. The straight lines between
+/// them in lon/lat then stay within a few meters of the great circle arcs of a cell border.
+const MAX_SEGMENT: f64 = 0.1 * PI / 180.0;
+
+/// Point on the unit sphere.
+pub type Point = [f64; 3];
+
+pub fn to_point(coord: Coord) -> Point {
+    let (lon, lat) = (coord.x.to_radians(), coord.y.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+pub fn to_coord(point: Point) -> Coord {
+    Coord {
+        x: point[1].atan2(point[0]).to_degrees(),
+        y: point[2].clamp(-1.0, 1.0).asin().to_degrees(),
+    }
+}
+
+pub fn normalize(point: Point) -> Point {
+    let length = (point[0] * point[0] + point[1] * point[1] + point[2] * point[2]).sqrt();
+    [point[0] / length, point[1] / length, point[2] / length]
+}
+
+/// Area enclosed by the great circle arcs between `vertices`, in lon/lat. Rings around a pole
+/// are closed along it and rings crossing the antimeridian are split along it.
+pub fn ring_shape(vertices: &[Point]) -> MultiPolygon {
+    let points = densify(vertices);
+    let Some(start) = points.iter().position(|point| !is_pole(*point)) else {
+        return MultiPolygon(vec![]);
+    };
+
+    // Longitudes continue past ±180 so that the ring has no jumps
+    let mut coords = Vec::<Coord>::with_capacity(points.len() + 4);
+    let mut previous: Option<f64> = None;
+    let mut pole = None;
+    for point in points[start..].iter().chain(&points[..start]) {
+        let coord = to_coord(*point);
+        if is_pole(*point) {
+            if let Some(lon) = previous {
+                coords.push(Coord { x: lon, y: coord.y });
+            }
+            pole = Some(coord.y);
+            continue;
+        }
+        let lon = previous.map_or(coord.x, |previous| previous + wrap(coord.x - previous));
+        if let Some(lat) = pole.take() {
+            coords.push(Coord { x: lon, y: lat });
+        }
+        coords.push(Coord { x: lon, y: coord.y });
+        previous = Some(lon);
+    }
+
+    let first = coords[0];
+    let last = previous.unwrap_or(first.x);
+    let end = last + wrap(first.x - last);
+    if let Some(lat) = pole {
+        coords.push(Coord { x: end, y: lat });
+    }
+    if (end - first.x).abs() > 180.0 {
+        let z: f64 = points.iter().map(|point| point[2]).sum();
+        let pole = if z > 0.0 { 90.0 } else { -90.0 };
+        coords.push(Coord { x: end, y: first.y });
+        coords.push(Coord { x: end, y: pole });
+        coords.push(Coord {
+            x: first.x,
+            y: pole,
+        });
+    }
+
+    split_antimeridian(coords)
+}
+
+/// The vertices with points added along the arcs between them, without closing the ring.
+fn densify(vertices: &[Point]) -> Vec<Point> {
+    let mut points = Vec::with_capacity(vertices.len());
+    for (i, a) in vertices.iter().enumerate() {
+        let b = vertices[(i + 1) % vertices.len()];
+        let dot = a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        let angle = dot.clamp(-1.0, 1.0).acos();
+        let segments = (angle / MAX_SEGMENT).ceil().max(1.0) as usize;
+        points.push(*a);
+        for segment in 1..segments {
+            let t = segment as f64 / segments as f64;
+            let (wa, wb) = (((1.0 - t) * angle).sin(), (t * angle).sin());
+            points.push(normalize([
+                wa * a[0] + wb * b[0],
+                wa * a[1] + wb * b[1],
+                wa * a[2] + wb * b[2],
+            ]));
+        }
+    }
+    points
+}
+
+fn is_pole(point: Point) -> bool {
+    point[0].abs() < 1e-12 && point[1].abs() < 1e-12
+}
+
+/// Difference of two longitudes, between -180 and 180.
+fn wrap(delta: f64) -> f64 {
+    delta - 360.0 * (delta / 360.0).round()
+}
+
+/// Cuts an open ring whose longitudes run past ±180 into polygons within ±180. The cells of all
+/// systems are convex or closed along a pole, so each side of the antimeridian holds a single
+/// polygon that is cut off exactly at ±180.
+fn split_antimeridian(ring: Vec<Coord>) -> MultiPolygon {
+    let min = ring
+        .iter()
+        .map(|coord| coord.x)
+        .fold(f64::INFINITY, f64::min);
+    let max = ring
+        .iter()
+        .map(|coord| coord.x)
+        .fold(f64::NEG_INFINITY, f64::max);
+    if min >= -180.0 && max <= 180.0 {
+        return MultiPolygon(vec![Polygon::new(LineString::new(ring), vec![])]);
+    }
+
+    let mut parts = Vec::new();
+    for shift in [-360.0, 0.0, 360.0] {
+        if max <= shift - 180.0 || min >= shift + 180.0 {
+            continue;
+        }
+        let part: Vec<Coord> = clip(&clip(&ring, shift - 180.0, false), shift + 180.0, true)
+            .into_iter()
+            .map(|coord| Coord {
+                x: coord.x - shift,
+                y: coord.y,
+            })
+            .collect();
+        if part.len() >= 3 {
+            parts.push(Polygon::new(LineString::new(part), vec![]));
+        }
+    }
+    MultiPolygon(parts)
+}
+
+/// The part of an open ring west of the longitude `x` if `west`, east of it otherwise.
+fn clip(ring: &[Coord], x: f64, west: bool) -> Vec<Coord> {
+    let inside = |coord: &Coord| if west { coord.x <= x } else { coord.x >= x };
+    let crossing = |a: Coord, b: Coord| Coord {
+        x,
+        y: a.y + (b.y - a.y) * (x - a.x) / (b.x - a.x),
+    };
+
+    let mut clipped = Vec::with_capacity(ring.len() + 2);
+    for (i, current) in ring.iter().enumerate() {
+        let previous = ring[(i + ring.len() - 1) % ring.len()];
+        match (inside(&previous), inside(current)) {
+            (true, true) => clipped.push(*current),
+            (true, false) => clipped.push(crossing(previous, *current)),
+            (false, true) => {
+                clipped.push(crossing(previous, *current));
+                clipped.push(*current);
+            }
+            (false, false) => {}
+        }
+    }
+    clipped
+}
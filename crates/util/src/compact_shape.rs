@@ -0,0 +1,308 @@
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+use serde::{Deserialize, Serialize};
+
+/// Coordinates are stored as multiples of 1e-7 degrees, the precision of OSM coordinates.
+const SCALE: f64 = 1e7;
+
+/// The part of a feature inside a border cell, encoded to be stored and tested cheaply.
+/// Coordinates are quantized to integers relative to the lower left corner of the cell and
+/// stored as zigzag varint deltas, ring by ring, with the closing coordinate left out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CompactShape(Vec<u8>);
+
+impl CompactShape {
+    /// Encodes `shape` relative to `origin`, the lower left corner of its cell.
+    pub fn encode(shape: &MultiPolygon, origin: Coord) -> Self {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, shape.0.len() as u64);
+        for polygon in &shape.0 {
+            write_varint(&mut bytes, 1 + polygon.interiors().len() as u64);
+            for ring in std::iter::once(polygon.exterior()).chain(polygon.interiors()) {
+                let coords = open_ring(ring);
+                write_varint(&mut bytes, coords.len() as u64);
+                let mut previous = (0, 0);
+                for coord in coords {
+                    let quantized = quantize(*coord, origin);
+                    write_varint(&mut bytes, zigzag(quantized.0 - previous.0));
+                    write_varint(&mut bytes, zigzag(quantized.1 - previous.1));
+                    previous = quantized;
+                }
+            }
+        }
+        CompactShape(bytes)
+    }
+
+    /// Decodes the polygons again, `None` if the encoding is corrupt.
+    pub fn decode(&self, origin: Coord) -> Option<MultiPolygon> {
+        let mut polygons = Vec::new();
+        let mut reader = Reader::new(&self.0);
+        for _ in 0..reader.varint()? {
+            let mut rings = Vec::new();
+            for _ in 0..reader.varint()? {
+                let mut coords: Vec<Coord> = reader
+                    .ring()?
+                    .map(|point| {
+                        point.map(|(x, y)| Coord {
+                            x: origin.x + x as f64 / SCALE,
+                            y: origin.y + y as f64 / SCALE,
+                        })
+                    })
+                    .collect::<Option<_>>()?;
+                coords.push(*coords.first()?);
+                rings.push(LineString::new(coords));
+            }
+            let mut rings = rings.into_iter();
+            polygons.push(Polygon::new(rings.next()?, rings.collect()));
+        }
+        Some(MultiPolygon(polygons))
+    }
+
+    /// Whether `coord` lies inside the shape, tested on the encoded rings by counting the ring
+    /// edges crossed on the way out of it. Points on an edge may count as inside or outside.
+    pub fn contains(&self, origin: Coord, coord: Coord) -> bool {
+        let point = quantize(coord, origin);
+        let mut reader = Reader::new(&self.0);
+        let mut inside = false;
+        let Some(polygons_count) = reader.varint() else {
+            return false;
+        };
+        for _ in 0..polygons_count {
+            let Some(rings_count) = reader.varint() else {
+                return false;
+            };
+            for _ in 0..rings_count {
+                let Some(ring) = reader.ring() else {
+                    return false;
+                };
+                let mut first = None;
+                let mut previous = None;
+                for current in ring {
+                    let Some(current) = current else {
+                        return false;
+                    };
+                    if let Some(previous) = previous {
+                        inside ^= crosses(previous, current, point);
+                    }
+                    first = first.or(Some(current));
+                    previous = Some(current);
+                }
+                if let (Some(first), Some(last)) = (first, previous) {
+                    inside ^= crosses(last, first, point);
+                }
+            }
+        }
+        inside
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Coordinates of a ring without the closing coordinate.
+fn open_ring(ring: &LineString) -> &[Coord] {
+    match ring.0.split_last() {
+        Some((last, rest)) if rest.first() == Some(last) => rest,
+        _ => &ring.0,
+    }
+}
+
+fn quantize(coord: Coord, origin: Coord) -> (i64, i64) {
+    (
+        ((coord.x - origin.x) * SCALE).round() as i64,
+        ((coord.y - origin.y) * SCALE).round() as i64,
+    )
+}
+
+/// Whether a ray from `point` towards positive x crosses the edge from `a` to `b`.
+fn crosses(a: (i64, i64), b: (i64, i64), point: (i64, i64)) -> bool {
+    if (a.1 > point.1) == (b.1 > point.1) {
+        return false;
+    }
+    let cross = (b.0 - a.0) as i128 * (point.1 - a.1) as i128
+        - (point.0 - a.0) as i128 * (b.1 - a.1) as i128;
+    (cross > 0) == (b.1 > a.1)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.bytes.get(self.position)?;
+            self.position += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    /// Reads the quantized coordinates of the next ring, one by one. A `None` item marks a
+    /// corrupt encoding.
+    fn ring(&mut self) -> Option<impl Iterator<Item = Option<(i64, i64)>> + use<'_, 'a>> {
+        let count = self.varint()?;
+        let mut previous = (0i64, 0i64);
+        Some((0..count).map(move |_| {
+            let x = previous.0 + unzigzag(self.varint()?);
+            let y = previous.1 + unzigzag(self.varint()?);
+            previous = (x, y);
+            Some(previous)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{polygon, CoordsIter, MultiPolygon};
+
+    use super::*;
+
+    const ORIGIN: Coord = Coord { x: 13.0, y: 52.0 };
+
+    fn shape(polygon: Polygon) -> MultiPolygon {
+        MultiPolygon(vec![polygon])
+    }
+
+    /// Diamond with its left and right corners on the line y = 52.1.
+    fn diamond() -> MultiPolygon {
+        shape(polygon![
+            (x: 13.1, y: 52.0),
+            (x: 13.2, y: 52.1),
+            (x: 13.1, y: 52.2),
+            (x: 13.0, y: 52.1),
+        ])
+    }
+
+    fn contains(shape: &MultiPolygon, x: f64, y: f64) -> bool {
+        CompactShape::encode(shape, ORIGIN).contains(ORIGIN, Coord { x, y })
+    }
+
+    #[test]
+    fn round_trips_quantized_to_osm_precision() {
+        let shape = MultiPolygon(vec![
+            polygon!(
+                exterior: [
+                    (x: 13.123456789, y: 52.0),
+                    (x: 13.5, y: 52.000000049),
+                    (x: 13.5, y: 52.5),
+                    (x: 13.0, y: 52.5),
+                ],
+                interiors: [[
+                    (x: 13.1, y: 52.1),
+                    (x: 13.1, y: 52.2),
+                    (x: 13.2, y: 52.2),
+                ]],
+            ),
+            polygon![(x: 14.0, y: 53.0), (x: 14.1, y: 53.0), (x: 14.1, y: 53.1)],
+        ]);
+
+        let decoded = CompactShape::encode(&shape, ORIGIN).decode(ORIGIN).unwrap();
+        assert_eq!(decoded.0.len(), 2);
+        assert_eq!(decoded.0[0].interiors().len(), 1);
+        for (decoded, original) in decoded.coords_iter().zip(shape.coords_iter()) {
+            assert!((decoded.x - original.x).abs() <= 0.5e-7);
+            assert!((decoded.y - original.y).abs() <= 0.5e-7);
+        }
+        assert_eq!(decoded.coords_count(), shape.coords_count());
+        let exterior = decoded.0[0].exterior();
+        assert_eq!(exterior.0.first(), exterior.0.last());
+        assert!((exterior.0[0].x - 13.1234568).abs() < 1e-9);
+        assert!((exterior.0[1].y - 52.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn encodes_relative_to_the_origin() {
+        let near_origin = CompactShape::encode(&diamond(), ORIGIN);
+        let far_origin = CompactShape::encode(&diamond(), Coord { x: 0.0, y: 0.0 });
+        assert!(near_origin.len() < far_origin.len());
+        assert_eq!(
+            near_origin.decode(ORIGIN),
+            far_origin.decode(Coord { x: 0.0, y: 0.0 })
+        );
+    }
+
+    #[test]
+    fn corrupt_encodings_decode_to_none() {
+        let encoded = CompactShape::encode(&diamond(), ORIGIN);
+        for length in 0..encoded.0.len() {
+            let truncated = CompactShape(encoded.0[..length].to_vec());
+            assert_eq!(truncated.decode(ORIGIN), None);
+            assert!(!truncated.contains(ORIGIN, Coord { x: 13.1, y: 52.1 }));
+        }
+    }
+
+    #[test]
+    fn contains_respects_holes() {
+        let shape = shape(polygon!(
+            exterior: [(x: 13.0, y: 52.0), (x: 13.4, y: 52.0), (x: 13.4, y: 52.4), (x: 13.0, y: 52.4)],
+            interiors: [[(x: 13.1, y: 52.1), (x: 13.1, y: 52.3), (x: 13.3, y: 52.3), (x: 13.3, y: 52.1)]],
+        ));
+        assert!(contains(&shape, 13.05, 52.2));
+        assert!(!contains(&shape, 13.2, 52.2));
+        assert!(contains(&shape, 13.35, 52.2));
+        assert!(!contains(&shape, 13.5, 52.2));
+    }
+
+    #[test]
+    fn rays_through_vertices_count_once() {
+        let diamond = diamond();
+        // The ray passes the right corner, whose edges lie on both sides of it
+        assert!(contains(&diamond, 13.05, 52.1));
+        assert!(contains(&diamond, 13.15, 52.1));
+        // The ray passes both corners from outside
+        assert!(!contains(&diamond, 12.9, 52.1));
+        assert!(!contains(&diamond, 13.3, 52.1));
+        // The ray only touches the top or bottom corner, both edges lie on one side of it
+        assert!(!contains(&diamond, 13.05, 52.2));
+        assert!(!contains(&diamond, 13.05, 52.0));
+    }
+
+    #[test]
+    fn rays_along_edges_count_once() {
+        // A notch in the top, its floor lies on the ray from the tested points
+        let notched = shape(polygon![
+            (x: 13.0, y: 52.0),
+            (x: 13.4, y: 52.0),
+            (x: 13.4, y: 52.2),
+            (x: 13.3, y: 52.2),
+            (x: 13.3, y: 52.1),
+            (x: 13.2, y: 52.1),
+            (x: 13.2, y: 52.2),
+            (x: 13.0, y: 52.2),
+        ]);
+        assert!(contains(&notched, 13.1, 52.1));
+        assert!(contains(&notched, 13.35, 52.1));
+        assert!(!contains(&notched, 13.5, 52.1));
+        assert!(!contains(&notched, 12.9, 52.1));
+    }
+}
@@ -1,14 +1,27 @@
+mod cell_system;
+mod compact_shape;
 mod rocksdb_helper;
+mod tag_filter;
 
+pub use cell_system::{
+    CellSystem, CellSystemKind, GeohashCells, H3Cells, LayerCells, QuadkeyCells, S2Cells,
+};
+pub use compact_shape::CompactShape;
 use geo::MultiPolygon;
 use geojson::JsonObject;
-pub use rocksdb_helper::rocksdb_options;
+pub use rocksdb_helper::{
+    bounded_rocksdb_options, feature_cells_layer, open_layered_db, open_layered_db_read_only,
+    properties_layer, read_layer_build, read_layer_cells, rocksdb_options, write_layer_build,
+};
 use serde::{Deserialize, Serialize};
+pub use tag_filter::{TagFilter, TagFilterError};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RelationMember {
     OuterMember(i64),
     InnerMember(i64),
+    /// Way without a role, its role is decided by the geometry of the relation.
+    UnknownMember(i64),
 }
 
 impl RelationMember {
@@ -16,6 +29,7 @@ impl RelationMember {
         match self {
             RelationMember::OuterMember(id) => *id,
             RelationMember::InnerMember(id) => *id,
+            RelationMember::UnknownMember(id) => *id,
         }
     }
 }
@@ -24,6 +38,10 @@ impl RelationMember {
 pub struct RelationWithMembers {
     pub id: i64,
     pub members: Vec<RelationMember>,
+    /// Relations referenced with an `outer` or `inner` role, their ways belong to this relation.
+    pub relation_members: Vec<RelationMember>,
+    /// Relations referenced with the `subarea` role, e.g. the states of a country.
+    pub subareas: Vec<i64>,
     pub tags: JsonObject,
 }
 
@@ -41,6 +59,35 @@ pub struct Way {
     pub outer: bool,
 }
 
+/// A closed way that matches the filters and is extracted as an area on its own.
+#[derive(Debug, Clone)]
+pub struct AreaWay {
+    pub id: i64,
+    pub tags: JsonObject,
+}
+
+/// What went wrong while assembling the rings of a relation.
+#[derive(Serialize, Debug, Default)]
+pub struct RelationDiagnostic {
+    pub relation_id: i64,
+    /// Member ways that aren't in the extract or have no nodes.
+    pub missing_ways: Vec<i64>,
+    pub missing_nodes: Vec<i64>,
+    /// First and last node of every ring that could not be closed.
+    pub open_rings: Vec<(i64, i64)>,
+    pub bridged_gaps: usize,
+}
+
+impl RelationDiagnostic {
+    pub fn has_issues(&self) -> bool {
+        !self.missing_ways.is_empty()
+            || !self.missing_nodes.is_empty()
+            || !self.open_rings.is_empty()
+            || self.bridged_gaps > 0
+    }
+}
+
+/// A cell filled with a feature, `value` is the id of the feature.
 #[derive(Debug)]
 pub enum GeohashIndex {
     DirectValue {
@@ -54,17 +101,76 @@ pub enum GeohashIndex {
     },
 }
 
-#[derive(Debug, Clone)]
-pub struct ShouldCheck {
-    pub hash: String,
-    pub area: MultiPolygon,
-}
-
 #[derive(Deserialize)]
 pub struct TopodexConfig {
+    /// Tags that must all be present, optionally with the given value.
+    #[serde(default)]
     pub filters: Vec<(String, Option<String>)>,
+    /// Tag filter expression that elements must match in addition to `filters`.
+    #[serde(default)]
+    pub filter: Option<TagFilter>,
+    /// Tags to keep with an optional new name. A key ending in `*` like `name:*` matches all tags
+    /// with that prefix, a `*` in the new name is replaced by the matched suffix.
     pub extract_properties: Vec<(String, Option<String>)>,
+    /// Keep all tags, those not matched by `extract_properties` under their own names.
+    #[serde(default)]
+    pub keep_all_tags: bool,
     pub process_property_name: String,
+    #[serde(default = "default_layer")]
+    pub layer: String,
+    /// Largest gap in meters between two way ends that is bridged while assembling rings.
+    #[serde(default)]
+    pub ring_gap_tolerance: f64,
+    /// Also extract closed ways matching `filters` as areas, next to relations. Ways that are
+    /// members of an extracted relation only make up the relation's feature.
+    #[serde(default)]
+    pub closed_ways: bool,
+    /// Whether relations keep their territorial waters or are cut to the land enclosed by
+    /// `natural=coastline` ways.
+    #[serde(default)]
+    pub coverage: Coverage,
+    /// Which member roles besides `outer` and `inner` are used to build relations.
+    #[serde(default)]
+    pub member_roles: MemberRoles,
+    /// Which of several features covering a location wins its lookup.
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Coverage {
+    #[default]
+    Maritime,
+    Land,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRoles {
+    /// Only `outer` and `inner` members
+    #[default]
+    Strict,
+    /// Also `exclave` as outer and `enclave` as inner members
+    Legacy,
+    /// Like `legacy`, ways without a role are outer or inner by how deeply their ring is
+    /// nested in the other rings of the relation
+    Infer,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Smaller features first, e.g. an enclave before the country around it
+    #[default]
+    SmallestArea,
+    LargestArea,
+    /// Features with a higher numeric value of this property first, features without it last
+    Property(String),
+}
+
+fn default_layer() -> String {
+    rocksdb::DEFAULT_COLUMN_FAMILY_NAME.to_owned()
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -73,8 +179,76 @@ pub struct UndecidedValue {
     pub shape: MultiPolygon,
 }
 
+/// Properties of a feature, stored per layer under the feature's id, which is also what its
+/// cells hold. Serialized as JSON because bitcode can't represent arbitrary JSON values.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeatureProperties {
+    pub id: Option<String>,
+    /// Process value of the feature, several features can share it
+    #[serde(default)]
+    pub value: Option<String>,
+    pub properties: JsonObject,
+    /// Rank among overlapping features, lower ranks come first. Features without a rank come
+    /// last, ties are ordered by id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<f64>,
+}
+
+/// How the cells of a layer were processed, recorded with the layer so that updates fill the
+/// cells of changed features the same way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LayerBuild {
+    #[serde(flatten)]
+    pub cells: LayerCells,
+    // Required even though optional, records that only hold the cells are no builds
+    #[serde(deserialize_with = "Option::deserialize")]
+    pub max_cell_vertices: Option<usize>,
+    #[serde(deserialize_with = "Option::deserialize")]
+    pub simplify_tolerance: Option<f64>,
+}
+
+impl LayerBuild {
+    /// Deepest level of the cells, which lookups encode locations at. Border cells are split
+    /// beyond `cells.max_level` while they are too intricate for `max_cell_vertices`, down to
+    /// the deepest level of the cell system.
+    pub fn lookup_level(&self) -> usize {
+        match self.max_cell_vertices {
+            Some(_) => self.cells.cells().max_level(),
+            None => self.cells.max_level,
+        }
+    }
+}
+
+/// Value of a border cell with the part of its feature inside the cell, relative to the cell.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompactValue {
+    pub value: String,
+    pub shape: CompactShape,
+}
+
+/// What a cell stores, the ids of the features covering it.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GeohashValue {
-    DirectValue { value: String },
-    Undecided { options: Vec<UndecidedValue> },
+    DirectValue {
+        value: String,
+    },
+    /// Border cell as written by earlier versions, still read
+    Undecided {
+        options: Vec<UndecidedValue>,
+    },
+    UndecidedCompact {
+        options: Vec<CompactValue>,
+    },
+    /// Cell covered by several features of which at least one covers all of it
+    Overlapping {
+        options: Vec<CellOption>,
+    },
+}
+
+/// Value of a cell covered by several features, without a shape if its feature covers all of
+/// the cell.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CellOption {
+    pub value: String,
+    pub shape: Option<CompactShape>,
 }
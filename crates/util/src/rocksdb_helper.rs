@@ -1,4 +1,13 @@
-use rocksdb::{BlockBasedOptions, Cache, Options};
+use anyhow::bail;
+use rocksdb::{
+    BlockBasedOptions, Cache, Options, WriteBufferManager, DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
+
+use crate::{LayerBuild, LayerCells};
+
+const PROPERTIES_SUFFIX: &str = ".properties";
+const FEATURE_CELLS_SUFFIX: &str = ".feature_cells";
+const CELLS_SUFFIX: &str = ".cells";
 
 pub fn rocksdb_options() -> Options {
     let cache = Cache::new_lru_cache(3 * 1024 * 1024 * 1024);
@@ -13,3 +22,222 @@ pub fn rocksdb_options() -> Options {
     options.set_stats_persist_period_sec(10);
     options
 }
+
+/// Options for scratch databases that should stay within `memory_budget` bytes. One block cache
+/// of that size is created, and the write buffers of all column families are charged against it
+/// by one write buffer manager, which flushes them once they hold half of the budget. Column
+/// families have to be opened with clones of these options to share the cache and the manager.
+pub fn bounded_rocksdb_options(memory_budget: usize) -> Options {
+    let cache = Cache::new_lru_cache(memory_budget);
+    let write_buffer_manager = WriteBufferManager::new_write_buffer_manager_with_cache(
+        memory_budget / 2,
+        false,
+        cache.clone(),
+    );
+    let mut table_options = BlockBasedOptions::default();
+    table_options.set_block_cache(&cache);
+    table_options.set_bloom_filter(10.0, false);
+
+    let mut options = Options::default();
+    options.set_block_based_table_factory(&table_options);
+    options.set_write_buffer_manager(&write_buffer_manager);
+    options.set_write_buffer_size(memory_budget / 8);
+    options.set_max_write_buffer_number(2);
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    options
+}
+
+/// Name of the column family holding the feature properties of `layer`.
+pub fn properties_layer(layer: &str) -> String {
+    format!("{}{}", layer, PROPERTIES_SUFFIX)
+}
+
+/// Name of the column family holding the cells each feature of `layer` was filled into, by
+/// feature id.
+pub fn feature_cells_layer(layer: &str) -> String {
+    format!("{}{}", layer, FEATURE_CELLS_SUFFIX)
+}
+
+/// Records how the cells of `layer` were built. The record is kept in the default column family
+/// under `{layer}.cells`, which can't clash with the cells of an index built before layers were
+/// introduced as no cell key holds a `.`.
+pub fn write_layer_build(db: &DB, layer: &str, build: &LayerBuild) -> anyhow::Result<()> {
+    db.put(
+        format!("{}{}", layer, CELLS_SUFFIX),
+        serde_json::to_vec(build)?,
+    )?;
+    Ok(())
+}
+
+/// How `layer` was processed, `None` for layers processed before this was recorded, whose
+/// record only holds their cells or is missing. Records are told apart by their
+/// `max_cell_vertices` field, which every build writes even if it is `null`.
+pub fn read_layer_build(db: &DB, layer: &str) -> anyhow::Result<Option<LayerBuild>> {
+    let Some(record) = db.get(format!("{}{}", layer, CELLS_SUFFIX))? else {
+        return Ok(None);
+    };
+    let record: serde_json::Value = serde_json::from_slice(&record)?;
+    if record.get("max_cell_vertices").is_none() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_value(record)?))
+}
+
+/// How the cells of `layer` were built, `None` for indexes built before this was recorded,
+/// which are all geohashes.
+pub fn read_layer_cells(db: &DB, layer: &str) -> anyhow::Result<Option<LayerCells>> {
+    Ok(match db.get(format!("{}{}", layer, CELLS_SUFFIX))? {
+        Some(cells) => Some(serde_json::from_slice(&cells)?),
+        None => None,
+    })
+}
+
+/// Opens the index for writing with all existing layers and creates `layer`, its properties
+/// table and the cells of its features if they are missing.
+pub fn open_layered_db(path: &str, layer: &str) -> anyhow::Result<DB> {
+    if let Some(suffix) = [PROPERTIES_SUFFIX, FEATURE_CELLS_SUFFIX]
+        .into_iter()
+        .find(|suffix| layer.ends_with(suffix))
+    {
+        bail!(
+            "Layer {} ends in {}, which is reserved for the tables of a layer",
+            layer,
+            suffix
+        );
+    }
+
+    let options = rocksdb_options();
+    let existing_layers = DB::list_cf(&options, path).unwrap_or_default();
+    let db = DB::open_cf(&options, path, existing_layers)?;
+
+    for column_family in [
+        layer.to_owned(),
+        properties_layer(layer),
+        feature_cells_layer(layer),
+    ] {
+        if db.cf_handle(&column_family).is_none() {
+            db.create_cf(&column_family, &options)?;
+        }
+    }
+
+    Ok(db)
+}
+
+/// Opens the index read only and returns it together with the names of the layers it holds.
+///
+/// The default column family is reported as a layer if it was processed as one, which gave it
+/// a properties table, or if the index has no named layers, which keeps indexes built before
+/// layers were introduced working.
+pub fn open_layered_db_read_only(path: &str) -> Result<(DB, Vec<String>), rocksdb::Error> {
+    let options = rocksdb_options();
+    let column_families = DB::list_cf(&options, path)?;
+    let db = DB::open_cf_for_read_only(&options, path, &column_families, false)?;
+
+    let named_layers: Vec<String> = column_families
+        .iter()
+        .filter(|name| {
+            name.as_str() != DEFAULT_COLUMN_FAMILY_NAME
+                && !name.ends_with(PROPERTIES_SUFFIX)
+                && !name.ends_with(FEATURE_CELLS_SUFFIX)
+        })
+        .cloned()
+        .collect();
+
+    let default_layer = named_layers.is_empty()
+        || column_families.contains(&properties_layer(DEFAULT_COLUMN_FAMILY_NAME));
+    let layers = default_layer
+        .then(|| DEFAULT_COLUMN_FAMILY_NAME.to_owned())
+        .into_iter()
+        .chain(named_layers)
+        .collect();
+
+    Ok((db, layers))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::CellSystemKind;
+
+    fn index() -> (TempDir, String) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index").to_str().unwrap().to_owned();
+        (dir, path)
+    }
+
+    #[test]
+    fn reads_builds_and_skips_records_of_cells_only() {
+        let (_dir, path) = index();
+        let db = open_layered_db(&path, "country").unwrap();
+        let build = LayerBuild {
+            cells: LayerCells {
+                system: CellSystemKind::S2,
+                max_level: 12,
+            },
+            max_cell_vertices: None,
+            simplify_tolerance: Some(10.0),
+        };
+        write_layer_build(&db, "country", &build).unwrap();
+        db.put("state.cells", br#"{"system":"quadkey","max_level":9}"#)
+            .unwrap();
+
+        assert_eq!(read_layer_build(&db, "country").unwrap(), Some(build));
+        assert_eq!(read_layer_build(&db, "state").unwrap(), None);
+        assert_eq!(read_layer_build(&db, "village").unwrap(), None);
+        assert_eq!(
+            read_layer_cells(&db, "state").unwrap(),
+            Some(LayerCells {
+                system: CellSystemKind::Quadkey,
+                max_level: 9,
+            })
+        );
+    }
+
+    #[test]
+    fn reports_broken_build_records() {
+        let (_dir, path) = index();
+        let db = open_layered_db(&path, "country").unwrap();
+        db.put(
+            "country.cells",
+            br#"{"system":"s2","max_level":12,"max_cell_vertices":"many","simplify_tolerance":null}"#,
+        )
+        .unwrap();
+        db.put("state.cells", b"{").unwrap();
+
+        assert!(read_layer_build(&db, "country").is_err());
+        assert!(read_layer_build(&db, "state").is_err());
+    }
+
+    #[test]
+    fn keeps_the_default_layer_next_to_named_layers() {
+        let (_dir, path) = index();
+        drop(open_layered_db(&path, DEFAULT_COLUMN_FAMILY_NAME).unwrap());
+        drop(open_layered_db(&path, "country").unwrap());
+
+        let (_db, layers) = open_layered_db_read_only(&path).unwrap();
+
+        assert_eq!(layers, vec![DEFAULT_COLUMN_FAMILY_NAME, "country"]);
+    }
+
+    #[test]
+    fn indexes_without_layers_read_the_default_column_family() {
+        let (_dir, path) = index();
+        drop(DB::open_cf(&rocksdb_options(), &path, [DEFAULT_COLUMN_FAMILY_NAME]).unwrap());
+
+        let (_db, layers) = open_layered_db_read_only(&path).unwrap();
+
+        assert_eq!(layers, vec![DEFAULT_COLUMN_FAMILY_NAME]);
+    }
+
+    #[test]
+    fn rejects_layer_names_of_layer_tables() {
+        let (_dir, path) = index();
+
+        assert!(open_layered_db(&path, "country.properties").is_err());
+        assert!(open_layered_db(&path, "country.feature_cells").is_err());
+        assert!(open_layered_db(&path, "country").is_ok());
+    }
+}
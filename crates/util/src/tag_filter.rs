@@ -0,0 +1,425 @@
+use std::{fmt, str::FromStr};
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// Boolean expression over the tags of an element, written as a string in the config, e.g.
+/// `boundary = administrative and admin_level in (4, 5) and (name:en or name)`.
+///
+/// - `key` matches if the tag exists
+/// - `key = value`, `key != value` compare the value, `!=` also matches if the tag is missing
+/// - `key in (a, b)` matches one of several values
+/// - `key ~ "regex"` and `key ^= prefix` match the value by regex or prefix
+/// - `and`, `or`, `not` and parentheses combine expressions, `and` binds tighter than `or`
+///
+/// Values containing spaces, commas or parentheses are written in double quotes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TagFilter {
+    All(Vec<TagFilter>),
+    Any(Vec<TagFilter>),
+    Not(Box<TagFilter>),
+    Exists(String),
+    Equals(String, String),
+    NotEquals(String, String),
+    OneOf(String, Vec<String>),
+    Prefix(String, String),
+    Regex(String, Regex),
+}
+
+impl TagFilter {
+    pub fn matches(&self, tags: &[(&str, &str)]) -> bool {
+        let value = |key: &str| {
+            tags.iter()
+                .find(|(tag_key, _)| *tag_key == key)
+                .map(|(_, value)| *value)
+        };
+
+        match self {
+            TagFilter::All(filters) => filters.iter().all(|filter| filter.matches(tags)),
+            TagFilter::Any(filters) => filters.iter().any(|filter| filter.matches(tags)),
+            TagFilter::Not(filter) => !filter.matches(tags),
+            TagFilter::Exists(key) => value(key).is_some(),
+            TagFilter::Equals(key, expected) => value(key) == Some(expected.as_str()),
+            TagFilter::NotEquals(key, expected) => value(key) != Some(expected.as_str()),
+            TagFilter::OneOf(key, expected) => {
+                value(key).is_some_and(|value| expected.iter().any(|expected| expected == value))
+            }
+            TagFilter::Prefix(key, prefix) => {
+                value(key).is_some_and(|value| value.starts_with(prefix.as_str()))
+            }
+            TagFilter::Regex(key, regex) => value(key).is_some_and(|value| regex.is_match(value)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TagFilterError(String);
+
+impl fmt::Display for TagFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid tag filter: {}", self.0)
+    }
+}
+
+impl std::error::Error for TagFilterError {}
+
+impl FromStr for TagFilter {
+    type Err = TagFilterError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            position: 0,
+        };
+        let filter = parser.parse_or()?;
+        match parser.next() {
+            None => Ok(filter),
+            Some(token) => Err(TagFilterError(format!("unexpected {}", token))),
+        }
+    }
+}
+
+impl TryFrom<String> for TagFilter {
+    type Error = TagFilterError;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        expression.parse()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Equals,
+    NotEquals,
+    Tilde,
+    Prefix,
+    Comma,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Quoted(text) => write!(f, "\"{}\"", text),
+            Token::Equals => write!(f, "'='"),
+            Token::NotEquals => write!(f, "'!='"),
+            Token::Tilde => write!(f, "'~'"),
+            Token::Prefix => write!(f, "'^='"),
+            Token::Comma => write!(f, "','"),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, TagFilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '=' => tokens.push(Token::Equals),
+            '~' => tokens.push(Token::Tilde),
+            ',' => tokens.push(Token::Comma),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '!' | '^' if chars.peek() == Some(&'=') => {
+                chars.next();
+                tokens.push(if c == '!' {
+                    Token::NotEquals
+                } else {
+                    Token::Prefix
+                });
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped @ ('"' | '\\')) => text.push(escaped),
+                            Some(escaped) => {
+                                text.push('\\');
+                                text.push(escaped);
+                            }
+                            None => return Err(TagFilterError("unterminated string".to_owned())),
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(TagFilterError("unterminated string".to_owned())),
+                    }
+                }
+                tokens.push(Token::Quoted(text));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(next) = chars.peek() {
+                    if next.is_whitespace() || "=!~^,()\"".contains(*next) {
+                        break;
+                    }
+                    word.push(*next);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next_is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word == keyword)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), TagFilterError> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(TagFilterError(format!(
+                "expected {} but found {}",
+                expected, token
+            ))),
+            None => Err(TagFilterError(format!(
+                "expected {} at end of expression",
+                expected
+            ))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<TagFilter, TagFilterError> {
+        let mut filters = vec![self.parse_and()?];
+        while self.next_is_keyword("or") {
+            self.next();
+            filters.push(self.parse_and()?);
+        }
+        Ok(combine(filters, TagFilter::Any))
+    }
+
+    fn parse_and(&mut self) -> Result<TagFilter, TagFilterError> {
+        let mut filters = vec![self.parse_unary()?];
+        while self.next_is_keyword("and") {
+            self.next();
+            filters.push(self.parse_unary()?);
+        }
+        Ok(combine(filters, TagFilter::All))
+    }
+
+    fn parse_unary(&mut self) -> Result<TagFilter, TagFilterError> {
+        if self.next_is_keyword("not") {
+            self.next();
+            return Ok(TagFilter::Not(Box::new(self.parse_unary()?)));
+        }
+
+        if self.peek() == Some(&Token::Open) {
+            self.next();
+            let filter = self.parse_or()?;
+            self.expect(Token::Close)?;
+            return Ok(filter);
+        }
+
+        let key = self.parse_text()?;
+        match self.peek() {
+            Some(Token::Equals) => {
+                self.next();
+                Ok(TagFilter::Equals(key, self.parse_text()?))
+            }
+            Some(Token::NotEquals) => {
+                self.next();
+                Ok(TagFilter::NotEquals(key, self.parse_text()?))
+            }
+            Some(Token::Prefix) => {
+                self.next();
+                Ok(TagFilter::Prefix(key, self.parse_text()?))
+            }
+            Some(Token::Tilde) => {
+                self.next();
+                let pattern = self.parse_text()?;
+                let regex = Regex::new(&pattern)
+                    .map_err(|err| TagFilterError(format!("regex {}: {}", pattern, err)))?;
+                Ok(TagFilter::Regex(key, regex))
+            }
+            Some(Token::Word(word)) if word == "in" => {
+                self.next();
+                self.expect(Token::Open)?;
+                let mut values = vec![self.parse_text()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.next();
+                    values.push(self.parse_text()?);
+                }
+                self.expect(Token::Close)?;
+                Ok(TagFilter::OneOf(key, values))
+            }
+            _ => Ok(TagFilter::Exists(key)),
+        }
+    }
+
+    fn parse_text(&mut self) -> Result<String, TagFilterError> {
+        match self.next() {
+            Some(Token::Word(word)) | Some(Token::Quoted(word)) => Ok(word),
+            Some(token) => Err(TagFilterError(format!(
+                "expected a key or value but found {}",
+                token
+            ))),
+            None => Err(TagFilterError(
+                "expected a key or value at end of expression".to_owned(),
+            )),
+        }
+    }
+}
+
+fn combine(mut filters: Vec<TagFilter>, group: fn(Vec<TagFilter>) -> TagFilter) -> TagFilter {
+    if filters.len() == 1 {
+        filters.remove(0)
+    } else {
+        group(filters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(expression: &str) -> TagFilter {
+        expression.parse().unwrap()
+    }
+
+    fn error(expression: &str) -> String {
+        expression.parse::<TagFilter>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let filter = parse("a or b and not c");
+        assert!(filter.matches(&[("a", "1"), ("c", "1")]));
+        assert!(filter.matches(&[("b", "1")]));
+        assert!(!filter.matches(&[("b", "1"), ("c", "1")]));
+        assert!(!filter.matches(&[("c", "1")]));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        let filter = parse("(a or b) and not c");
+        assert!(!filter.matches(&[("a", "1"), ("c", "1")]));
+        assert!(filter.matches(&[("a", "1")]));
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        let filter = parse("not a and b");
+        assert!(filter.matches(&[("b", "1")]));
+        assert!(!filter.matches(&[("a", "1"), ("b", "1")]));
+        assert!(!filter.matches(&[]));
+    }
+
+    #[test]
+    fn compares_values() {
+        let filter = parse("boundary = administrative and admin_level != 2");
+        assert!(filter.matches(&[("boundary", "administrative"), ("admin_level", "4")]));
+        assert!(filter.matches(&[("boundary", "administrative")]));
+        assert!(!filter.matches(&[("boundary", "administrative"), ("admin_level", "2")]));
+        assert!(!filter.matches(&[("boundary", "maritime")]));
+    }
+
+    #[test]
+    fn matches_value_sets() {
+        let filter = parse("admin_level in (4, 5,\"6\")");
+        assert!(filter.matches(&[("admin_level", "4")]));
+        assert!(filter.matches(&[("admin_level", "6")]));
+        assert!(!filter.matches(&[("admin_level", "7")]));
+        assert!(!filter.matches(&[]));
+    }
+
+    #[test]
+    fn matches_prefixes_and_regexes() {
+        let filter = parse("name:en ^= New and ref ~ \"^[A-Z]{2}$\"");
+        assert!(filter.matches(&[("name:en", "New York"), ("ref", "NY")]));
+        assert!(!filter.matches(&[("name:en", "York"), ("ref", "NY")]));
+        assert!(!filter.matches(&[("name:en", "New York"), ("ref", "NYC")]));
+    }
+
+    #[test]
+    fn reads_quoted_values_with_escapes() {
+        let filter = parse(r#"name = "Saint \"Denis\", (Réunion) \\ \n""#);
+        assert!(filter.matches(&[("name", r#"Saint "Denis", (Réunion) \ \n"#)]));
+
+        let filter = parse(r#""addr:street" = "and""#);
+        assert!(filter.matches(&[("addr:street", "and")]));
+    }
+
+    #[test]
+    fn keywords_as_values() {
+        let filter = parse("name = or");
+        assert!(filter.matches(&[("name", "or")]));
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        assert!(error("name ~ \"(\"").starts_with("Invalid tag filter: regex (: "));
+    }
+
+    #[test]
+    fn reports_malformed_expressions() {
+        assert_eq!(
+            error("name = \"Paris"),
+            "Invalid tag filter: unterminated string"
+        );
+        assert_eq!(
+            error("name = \"Paris\\"),
+            "Invalid tag filter: unterminated string"
+        );
+        assert_eq!(
+            error("(a or b"),
+            "Invalid tag filter: expected ')' at end of expression"
+        );
+        assert_eq!(error("a b"), "Invalid tag filter: unexpected 'b'");
+        assert_eq!(error("a )"), "Invalid tag filter: unexpected ')'");
+        assert_eq!(
+            error("a in 4, 5"),
+            "Invalid tag filter: expected '(' but found '4'"
+        );
+        assert_eq!(
+            error("a in (4, 5"),
+            "Invalid tag filter: expected ')' at end of expression"
+        );
+        assert_eq!(
+            error("a ="),
+            "Invalid tag filter: expected a key or value at end of expression"
+        );
+        assert_eq!(
+            error("a and = b"),
+            "Invalid tag filter: expected a key or value but found '='"
+        );
+        assert_eq!(
+            error(""),
+            "Invalid tag filter: expected a key or value at end of expression"
+        );
+    }
+
+    #[test]
+    fn deserializes_from_config_strings() {
+        let filter: TagFilter = serde_json::from_str("\"a and b\"").unwrap();
+        assert!(filter.matches(&[("a", "1"), ("b", "2")]));
+        assert!(serde_json::from_str::<TagFilter>("\"a and\"").is_err());
+    }
+}